@@ -0,0 +1,53 @@
+#!/usr/bin/python
+
+"""
+Benchmarks of the lexer, the parser and the expansion.
+
+Runs every step of the given fixtures, all of them by default, and prints
+the best time of the repetitions:
+
+    python benches/bench.py [-n REPEAT] [small|medium|large ...]
+"""
+
+import optparse
+import os
+import sys
+import timeit
+
+# simple magic for using scripts within a source tree
+basedir = os.path.dirname(os.path.dirname(os.path.abspath(__file__)))
+if os.path.isdir(os.path.join(basedir, 'cartconf')):
+    sys.path.insert(0, basedir)
+
+from cartconf import lex, parse_string
+
+import fixtures
+
+
+def steps(text):
+    """
+    :return: List of (name, callable) of the benchmarked steps.
+    """
+    parsed = parse_string(text)
+    return [("lex", lambda: lex(text)),
+            ("parse", lambda: parse_string(text)),
+            ("expand", lambda: list(parsed.get_dicts()))]
+
+
+def main(argv=None):
+    parser = optparse.OptionParser("usage: %prog [options] [fixture ...]")
+    parser.add_option("-n", "--repeat", dest="repeat", type="int", default=5,
+                      help="repetitions of every step, default 5")
+    options, args = parser.parse_args(argv)
+    names = args or sorted(fixtures.SIZES, key=fixtures.SIZES.get)
+    for name in names:
+        if name not in fixtures.SIZES:
+            parser.error("unknown fixture %s" % name)
+        text = fixtures.config(*fixtures.SIZES[name])
+        for step, func in steps(text):
+            best = min(timeit.repeat(func, number=1, repeat=options.repeat))
+            print("%-8s %-8s %10.3f ms" % (name, step, best * 1000))
+
+
+if __name__ == "__main__":
+    main()
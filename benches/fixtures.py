@@ -0,0 +1,66 @@
+"""
+Generated configurations of the benchmarks.
+
+Every configuration has ``blocks`` variants blocks of ``width`` variants
+with assignments, substitutions, a conditional block and a "no" filter,
+so that lexing, parsing and the expansion all have some work to do.
+"""
+
+# name -> (blocks, width[, keys[, regexps]])
+SIZES = {
+    "small": (3, 3),
+    "medium": (4, 5),
+    "large": (5, 6),
+    # the nesting rather than the width of the blocks, with 10 keys in every
+    # variant
+    "deep": (6, 4, 10),
+    # thousands of combinations behind regexp filters and operators
+    "regexp": (5, 6, 1, 3),
+}
+
+
+def config(blocks, width, keys=1, regexps=0):
+    """
+    :param keys: Keys assigned by every variant.
+    :param regexps: Number of the first blocks whose last variant is dropped
+                    by a "no /regexp/" filter and whose keys are appended to
+                    by a "?+=" operator.
+    :return: Configuration text of the given size.
+    """
+    lines = ["base = 1024",
+             "cmd = run ${base}",
+             "# the configuration of the benchmarks"]
+    for block in range(blocks):
+        lines.append("variants block%d:" % block)
+        for variant in range(width):
+            name = "v%d_%d" % (block, variant)
+            lines += ["    - %s:" % name]
+            lines += ["        %s_key%s = %s" % (name, key or "", name)
+                      for key in range(keys)]
+            lines += ["        cmd += \" %s=${base}\"" % name]
+            if variant == 0:
+                lines += ["        variants:",
+                          "            - inner:",
+                          "                mem = ${base}"]
+    lines += ["v0_1:",
+              "    mem = 2048",
+              "    cmd <= \"smp \"",
+              "no v0_0..v1_0"]
+    for block in range(regexps):
+        lines += ["v%d_.*_key ?+= \" r\"" % block,
+                  "no /v%d_%d/" % (block, width - 1)]
+    return "\n".join(lines) + "\n"
+
+
+def expected_count(blocks, width, keys=1, regexps=0):
+    """
+    :return: Number of dictionaries of config(blocks, width, keys, regexps).
+    """
+    widths = [width - 1 if block < regexps else width
+              for block in range(blocks)]
+    total = excluded = 1
+    for block, count in enumerate(widths):
+        total *= count
+        if block >= 2:
+            excluded *= count
+    return total - excluded
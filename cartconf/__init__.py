@@ -0,0 +1,11 @@
+"""
+Cartesian configuration format file parser.
+"""
+
+from .exceptions import CartconfError, ParserError, LexerError
+from .exceptions import MissingIncludeError
+from .utils import Params
+from .parser import Parser, ConfigBuilder, IndentPolicy, ReaderCache
+from .parser import IncrementalParser, Visitor, walk
+from .parser import parse_string, parse_file, parse_flat, filter_dicts, diff
+from .parser import merge, validate, lex
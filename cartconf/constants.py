@@ -3,3 +3,9 @@ Constants module.
 """
 
 reserved_keys = set(("name", "shortname", "dep", "_short_name_map_file", "_name_map_file"))
+
+#: prefix of the keys kept for internal use, on top of reserved_keys
+reserved_prefix = "_cartconf_"
+
+#: key of the messages of the print statements a dictionary went through
+messages_key = reserved_prefix + "messages"
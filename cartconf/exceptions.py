@@ -2,28 +2,47 @@
 Exceptions module.
 """
 
-class ParserError(Exception):
+class CartconfError(Exception):
+    """
+    Base class of all errors raised while reading a configuration.
+    """
+    pass
+
 
-    def __init__(self, msg, line=None, filename=None, linenum=None):
+class ParserError(CartconfError):
+
+    def __init__(self, msg, line=None, filename=None, linenum=None,
+                 column=None, end_column=None):
         Exception.__init__(self)
         self.msg = msg
         self.line = line
         self.filename = filename
         self.linenum = linenum
+        self.column = column
+        # column past the last char of the error, as in a token Span
+        self.end_column = end_column
 
     def __str__(self):
+        if self.filename is None and self.linenum is None:
+            return self.msg
+        if self.column is not None:
+            location = "%s:%s:%s" % (self.filename, self.linenum, self.column)
+            if (self.end_column is not None and
+                    self.end_column > self.column + 1):
+                location += "-%s" % (self.end_column - 1)
+        else:
+            location = "%s:%s" % (self.filename, self.linenum)
         if self.line:
-            return "%s: %r (%s:%s)" % (self.msg, self.line,
-                                       self.filename, self.linenum)
+            return "%s: %r (%s)" % (self.msg, self.line, location)
         else:
-            return "%s (%s:%s)" % (self.msg, self.filename, self.linenum)
+            return "%s (%s)" % (self.msg, location)
 
 
 class LexerError(ParserError):
     pass
 
 
-class MissingIncludeError(Exception):
+class MissingIncludeError(CartconfError):
 
     def __init__(self, line, filename, linenum):
         Exception.__init__(self)
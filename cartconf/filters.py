@@ -156,17 +156,26 @@ class NegativeCondition(OnlyFilter):
 
 
 # Helpers for all filters
+def _in_labels(label, labels):
+    """
+    Check label presence, regular expression labels can't use hashing.
+    """
+    if label.is_regexp:
+        return any(label == x for x in labels)
+    return label in labels
+
+
 def _match_adjacent(block, ctx, ctx_set):
     """
     It try to match as many blocks as possible from context.
 
     :return: Count of matched blocks.
     """
-    if block[0] not in ctx_set:
+    if not _in_labels(block[0], ctx_set):
         return 0
     if len(block) == 1:
         return 1                          # First match and length is 1.
-    if block[1] not in ctx_set:
+    if not _in_labels(block[1], ctx_set):
         return int(ctx[-1] == block[0])   # Check match with last from ctx.
     k = 0
     i = ctx.index(block[0])
@@ -178,7 +187,7 @@ def _match_adjacent(block, ctx, ctx_set):
             k += 1
             if k >= len(block):           # match all of blocks
                 break
-            if block[k] not in ctx_set:   # block in not in whole ctx.
+            if not _in_labels(block[k], ctx_set):  # not in whole ctx.
                 break
         i += 1
     return k
@@ -187,7 +196,7 @@ def _match_adjacent(block, ctx, ctx_set):
 def _might_match_adjacent(block, ctx, ctx_set, descendant_labels):
     matched = _match_adjacent(block, ctx, ctx_set)
     for elem in block[matched:]:        # Try to find rest of blocks in subtree
-        if elem not in descendant_labels:
+        if not _in_labels(elem, descendant_labels):
             # print "Can't match %s, ctx %s" % (block, ctx)
             return False
     return True
@@ -8,8 +8,11 @@ import os
 import optparse
 import logging
 import re
+import sys
 
+from .exceptions import CartconfError
 from .parser import Parser
+from .utils import split_escaped
 
 
 LOG = logging.getLogger('avocado.' + __name__)
@@ -46,10 +49,12 @@ def print_dicts(options, dicts):
         print_dicts_default(options, dicts)
 
 
-if __name__ == "__main__":
+def main(argv=None):
     parser = optparse.OptionParser('usage: %prog [options] filename '
                                    '[extra code] ...\n\nExample:\n\n    '
-                                   '%prog tests.cfg "only my_set" "no qcow2"')
+                                   '%prog tests.cfg "only my_set" "no qcow2"'
+                                   '\n\nUse "-" as filename to read from the'
+                                   ' standard input.')
     parser.add_option("-v", "--verbose", dest="debug", action="store_true",
                       help="include debug messages in console output")
     parser.add_option("-f", "--fullname", dest="fullname", action="store_true",
@@ -66,24 +71,69 @@ if __name__ == "__main__":
                            " defaults is enabled.  \"name, name, name\"")
     parser.add_option("-s", "--skip-dups", dest="skipdups", default=True, action="store_false",
                       help="Don't drop variables with different suffixes and same val")
-
-    options, args = parser.parse_args()
+    parser.add_option("--only", dest="only", action="append", default=[],
+                      metavar="FILTER",
+                      help="keep only the dicts matching FILTER, can be"
+                           " repeated")
+    parser.add_option("--no", dest="no", action="append", default=[],
+                      metavar="FILTER",
+                      help="drop the dicts matching FILTER, can be repeated")
+    parser.add_option("--format", dest="format", type="choice",
+                      choices=["json", "yaml"],
+                      help="print the dicts as a json or yaml list")
+    parser.add_option("--count-only", dest="count_only", action="store_true",
+                      help="print only the number of dicts")
+    parser.add_option("--names-only", dest="names_only", action="store_true",
+                      help="print only the full name of every dict, one per"
+                           " line")
+
+    options, args = parser.parse_args(argv)
     if not args:
         parser.error("filename required")
+    if sum(bool(mode) for mode in (options.format, options.count_only,
+                                   options.names_only)) > 1:
+        parser.error("--format, --count-only and --names-only are mutually"
+                     " exclusive")
 
     if options.debug:
         LOG.setLevel(logging.DEBUG)
 
     expand = []
     if options.expand:
-        expand = [x.strip() for x in options.expand.split(",")]
-    c = Parser(args[0], defaults=options.defaults, expand_defaults=expand,
-               debug=options.debug)
-    for s in args[1:]:
-        c.parse_string(s)
+        expand = [x.strip() for x in split_escaped(options.expand)]
+    try:
+        if args[0] == "-":
+            c = Parser(defaults=options.defaults, expand_defaults=expand,
+                       debug=options.debug)
+            c.parse_string(sys.stdin.read())
+        else:
+            c = Parser(args[0], defaults=options.defaults,
+                       expand_defaults=expand, debug=options.debug)
+        for s in args[1:]:
+            c.parse_string(s)
+        for s in options.only:
+            c.parse_string("only %s" % s)
+        for s in options.no:
+            c.parse_string("no %s" % s)
+
+        if options.debug:
+            print(c.node.dump(0, True))
+
+        if options.count_only:
+            print(c.count())
+        elif options.names_only:
+            for dic in c.get_dicts(skipdups=options.skipdups):
+                print(dic["name"])
+        elif options.format == "json":
+            print(c.to_json(indent=4, skipdups=options.skipdups))
+        elif options.format == "yaml":
+            print(c.to_yaml(skipdups=options.skipdups), end="")
+        else:
+            dicts = c.get_dicts(skipdups=options.skipdups)
+            print_dicts(options, dicts)
+    except (CartconfError, OSError) as details:
+        sys.exit("%s: error: %s" % (parser.get_prog_name(), details))
 
-    if options.debug:
-        print(c.node.dump(0, True))
 
-    dicts = c.get_dicts(skipdups=options.skipdups)
-    print_dicts(options, dicts)
+if __name__ == "__main__":
+    main()
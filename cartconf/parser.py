@@ -2,37 +2,82 @@
 Module for readers, lexers, and parsers as well as their components.
 """
 
+import bisect
 import collections
+import concurrent.futures
+import copy
+import fnmatch
+import gzip
+import hashlib
+import itertools
+import json
 import logging
+import os
+import re
+import sys
+import threading
+import unicodedata
 
 from .exceptions import *
-from .utils import drop_suffixes
+from .utils import drop_suffixes, dicts_to_json, dicts_to_yaml
+from .utils import write_dicts_jsonl, is_reserved_key, split_escaped
 from .filters import *
+from .filters import _in_labels
 from .tokens import *
+from .tokens import match_substitute, clear_saved_keys, end_suffix_scopes
+from .constants import reserved_keys, reserved_prefix, messages_key
 
 
 LOG = logging.getLogger('avocado.' + __name__)
 
 
-tokens_oper_re = [r"\=", r"\+\=", r"\<\=", r"\~\=", r"\?\=", r"\?\+\=", r"\?\<\="]
+tokens_oper_re = [r"\=", r"\+\=", r"\<\=", r"\~\=", r"\?\=", r"\?\+\=", r"\?\<\=",
+                  r"\-\="]
 _ops_exp = re.compile(r"|".join(tokens_oper_re))
+#: location of a token, 1-based columns of its first and past its last char
+Span = collections.namedtuple("Span", ["linenum", "start", "end"])
+#: assignment a value comes from, variant is the one it's declared in
+Provenance = collections.namedtuple("Provenance",
+                                    ["filename", "linenum", "variant"])
+# key carrying the provenance of a dictionary up to get_dicts_provenance()
+_provenance_key = reserved_prefix + "provenance"
+# items of an assignment expanded as variants
+_list_item_exp = re.compile(r"[A-Za-z0-9_,-]+$")
+# include <path> with <key>=<value> ...
+_include_with_exp = re.compile(r"(.*?)\s+with\s+(.*)$")
+_include_param_exp = re.compile(r"([A-Za-z0-9][A-Za-z0-9_-]*)=(.*)$")
+# <value> if <filter> else <value>
+_inline_cond_exp = re.compile(r"(.*?)\s+if\s+(.+?)\s+else\s+(.*)$")
+# variants [name] [meta]: @template
+_template_ref_exp = re.compile(r"variants(\s[^:]*)?:\s*@([^\s#]+)")
+
+# statements rejected by parse_flat()
+_flat_rejected = {LVariants: "Variants block", LOnly: "Only filter",
+                  LNo: "No filter", LJoin: "Join", LSuffix: "Suffix",
+                  LPrefix: "Prefix",
+                  LNotCond: "Conditional block",
+                  LLRBracket: "Conditional block"}
 
 
 class Label(object):
     __slots__ = ["name", "var_name", "long_name", "hash_val", "hash_var"]
+    is_regexp = False
 
     def __init__(self, name, next_name=None):
+        # the same names are repeated in every node of big configurations
+        # so share their storage among all the labels
         if next_name is None:
-            self.name = name
+            self.name = sys.intern(str(name))
             self.var_name = None
         else:
-            self.name = next_name
-            self.var_name = name
+            self.name = sys.intern(str(next_name))
+            self.var_name = sys.intern(str(name))
 
         if self.var_name is None:
-            self.long_name = "%s" % (self.name)
+            self.long_name = self.name
         else:
-            self.long_name = "(%s=%s)" % (self.var_name, self.name)
+            self.long_name = sys.intern("(%s=%s)" % (self.var_name,
+                                                     self.name))
 
         self.hash_val = self.hash_name()
         self.hash_var = None
@@ -79,9 +124,48 @@ class Label(object):
         return sum([i + 1 * ord(x) for i, x in enumerate(str(self))])
 
 
+class RegExpLabel(Label):
+    """
+    Filter label matching variant names by a regular expression.
+
+    The expression is compiled once when the filter is parsed and matched
+    against the whole variant name.
+    """
+    __slots__ = ["regexp"]
+    is_regexp = True
+
+    def __init__(self, pattern):
+        super(RegExpLabel, self).__init__("/%s/" % pattern)
+        self.regexp = re.compile(pattern)
+
+    def __eq__(self, o):
+        return self.regexp.fullmatch(str(o.name)) is not None
+
+    def __ne__(self, o):
+        return self.regexp.fullmatch(str(o.name)) is None
+
+    def __hash__(self):
+        return self.hash_val
+
+
+class GlobLabel(RegExpLabel):
+    """
+    Filter label matching variant names by a shell-style wildcard pattern.
+
+    "*" matches any run of characters and "?" a single one, the pattern has
+    to match the whole variant name.
+    """
+    __slots__ = []
+
+    def __init__(self, pattern):
+        # pylint: disable=W0233
+        Label.__init__(self, pattern)
+        self.regexp = re.compile(fnmatch.translate(pattern))
+
+
 class Node(object):
     __slots__ = ["var_name", "name", "filename", "dep", "content", "children",
-                 "labels", "append_to_shortname", "failed_cases", "default"]
+                 "labels", "append_to_shortname", "default", "scope", "span"]
 
     def __init__(self):
         self.var_name = []
@@ -92,20 +176,145 @@ class Node(object):
         self.children = []
         self.labels = set()
         self.append_to_shortname = False
-        self.failed_cases = collections.deque()
         self.default = False
+        # name of the variant the statements of the node are declared in
+        self.scope = ""
+        # Span of the name of a variant in its "- name:" line
+        self.span = None
 
     def dump(self, indent: int, recurse: bool = False):
         dump_str = ""
         dump_str += "%sname: %s\n" % (" " * indent, self.name)
         dump_str += "%svariable name: %s\n" % (" " * indent, self.var_name)
         dump_str += "%scontent: %s\n" % (" " * indent, self.content)
-        dump_str += "%sfailed cases: %s\n" % (" " * indent, self.failed_cases)
         if recurse:
             for child in self.children:
                 dump_str += child.dump(indent + 3, recurse)
         return dump_str
 
+    def to_config_string(self):
+        """
+        Serialize the tree back to configuration text.
+
+        The text is not the original source: comments, includes and the
+        variants metadata are gone and statements shared by several variants
+        may be repeated in each of them.  Parsing it generates the same
+        dictionaries as the tree does.
+
+        :return: Configuration text.
+        """
+        lines = _node_lines(self, None)
+        return "".join("%s%s\n" % ("    " * level, line)
+                       for level, line in lines)
+
+
+#: serializes the parsing of the lazy includes
+_lazy_include_lock = threading.RLock()
+
+
+class LazyInclude(object):
+
+    """
+    Included file read when the expansion reaches it for the first time.
+
+    Only files without variants blocks and joins can be included lazily,
+    their statements are unpacked in place of the include like the body of
+    a matching conditional block.
+    """
+
+    def __init__(self, filename, includes, indent_policy, key_prefix=""):
+        self.filename = filename
+        self.includes = includes
+        self.indent_policy = indent_policy
+        # prefix of the keys in scope of the include
+        self.key_prefix = key_prefix
+        self.content = None
+
+    def load(self, parser):
+        """
+        Parse the included file, once.
+
+        :param parser: Parser the file belongs to.
+        :return: Content of the included file.
+        """
+        if self.content is not None:
+            return self.content
+        # the parser state is shared by the threads expanding the tree
+        with _lazy_include_lock:
+            if self.content is None:
+                node = Node()
+                lexer = Lexer(FileReader(self.filename, self.indent_policy,
+                                         parser.reader_cache))
+                lexer.includes = self.includes
+                key_prefix = parser._key_prefix
+                parser._key_prefix = self.key_prefix
+                try:
+                    parsed = parser._parse(lexer, node, -1)
+                finally:
+                    parser._key_prefix = key_prefix
+                if parsed is not node or node.children:
+                    raise ParserError("Lazily included file declares"
+                                      " variants", None, self.filename, None)
+                if any(isinstance(obj, JoinFilter)
+                       for _, _, obj in node.content):
+                    raise ParserError("Lazily included file declares a join",
+                                      None, self.filename, None)
+                self.content = node.content
+        return self.content
+
+    def __repr__(self):
+        return "Lazy_include %s" % self.filename
+
+
+class IndentPolicy(object):
+
+    """
+    Whitespace allowed in the indentation of lines.
+
+    The default "expand" policy is the one of avocado: tabs are expanded to
+    the next multiple of tab_width columns and may be freely mixed with
+    spaces.  The other policies reject the lines violating them:
+
+    * "spaces" -- only spaces may be used for indentation
+    * "tabs" -- only tabs may be used for indentation
+    * "width" -- tabs count as tab_width columns, but a tab following
+      a space is ambiguous and rejected
+    """
+
+    EXPAND = "expand"
+    SPACES = "spaces"
+    TABS = "tabs"
+    WIDTH = "width"
+
+    def __init__(self, mode=EXPAND, tab_width=8):
+        if mode not in (self.EXPAND, self.SPACES, self.TABS, self.WIDTH):
+            raise ValueError("Unknown indentation policy %r" % mode)
+        self.mode = mode
+        self.tab_width = tab_width
+
+    def check(self, line, filename, linenum):
+        """
+        Check the indentation of a line against the policy.
+
+        :param line: The line with its original indentation.
+        :raise LexerError: If the indentation violates the policy.
+        """
+        indent = line[:len(line) - len(line.lstrip())]
+        if self.mode == self.SPACES:
+            bad = indent.find("\t")
+            msg = "Tab in indentation"
+        elif self.mode == self.TABS:
+            bad = indent.find(" ")
+            msg = "Space in indentation"
+        elif self.mode == self.WIDTH:
+            bad = indent.find(" \t")
+            bad = bad + 1 if bad >= 0 else bad
+            msg = "Tab after space in indentation"
+        else:
+            bad = -1
+        if bad >= 0:
+            raise LexerError(msg, line.strip(), filename, linenum, bad + 1)
+
 
 class StrReader(object):
 
@@ -113,25 +322,58 @@ class StrReader(object):
     Preprocess an input string for easy reading.
     """
 
-    def __init__(self, s):
+    def __init__(self, s, indent_policy=None, filename="<string>"):
         """
         Initialize the reader.
 
         :param s: The string to parse.
+        :param indent_policy: IndentPolicy of the lines, the default one
+                              if None.
+        :param filename: Name of the parsed source used in errors.
         """
-        self.filename = "<string>"
-        self._lines = []
+        self.filename = filename
+        self.indent_policy = indent_policy or IndentPolicy()
+        self.linenum = 0
+        self._lines = self._preprocess(s)
         self._line_index = 0
         self._stored_line = None
-        for linenum, line in enumerate(s.splitlines()):
-            line = line.rstrip().expandtabs()
-            stripped_line = line.lstrip()
-            indent = len(line) - len(stripped_line)
-            if (not stripped_line or
-                    stripped_line.startswith("#") or
-                    stripped_line.startswith("//")):
+
+    def _preprocess(self, s):
+        """
+        Split the string into lines, without comments and continuations.
+
+        :return: List of (line, indent, linenum) tuples.
+        """
+        lines = []
+        filename = self.filename
+        continued = None
+        tab_width = self.indent_policy.tab_width
+        for linenum, raw_line in enumerate(s.splitlines()):
+            line = raw_line.rstrip().expandtabs(tab_width)
+            if continued is not None:
+                # The indentation of a continuation line is not significant
+                line = continued[0] + line.lstrip()
+                indent, first_linenum = continued[1:]
+                continued = None
+            else:
+                stripped_line = line.lstrip()
+                indent = len(line) - len(stripped_line)
+                line = stripped_line
+                first_linenum = linenum + 1
+            if (not line or
+                    line.startswith("#") or
+                    line.startswith("//")):
+                continue
+            if first_linenum == linenum + 1:
+                self.indent_policy.check(raw_line, filename, first_linenum)
+            trailing = len(line) - len(line.rstrip("\\"))
+            if trailing % 2:
+                continued = line[:-1], indent, first_linenum
                 continue
-            self._lines.append((stripped_line, indent, linenum + 1))
+            lines.append((line, indent, first_linenum))
+        if continued is not None:
+            lines.append(continued)
+        return lines
 
     def get_next_line(self, prev_indent):
         """
@@ -152,6 +394,7 @@ class StrReader(object):
         if indent <= prev_indent:
             return None, indent, linenum
         self._line_index += 1
+        self.linenum = linenum
         return line, indent, linenum
 
     def set_next_line(self, line, indent, linenum):
@@ -163,6 +406,27 @@ class StrReader(object):
         if line:
             self._stored_line = line, indent, linenum
 
+    def next_indent(self):
+        """
+        :return: Indentation of the real next line, -1 at the end.
+        """
+        if self._line_index >= len(self._lines):
+            return -1
+        return self._lines[self._line_index][1]
+
+    def insert_lines(self, lines):
+        """
+        Read the given lines before the real next line.
+
+        :param lines: List of (line, indent, linenum) tuples.
+        """
+        # the list may be shared with a ReaderCache, don't modify it
+        self._lines = (self._lines[:self._line_index] + lines +
+                       self._lines[self._line_index:])
+
+
+_gzip_magic = b"\x1f\x8b"
+
 
 class FileReader(StrReader):
 
@@ -170,19 +434,224 @@ class FileReader(StrReader):
     Preprocess an input file for easy reading.
     """
 
-    def __init__(self, filename):
+    def __init__(self, filename, indent_policy=None, cache=None):
         """
         Initialize the reader.
 
         :parse filename: The name of the input file.
+        :param indent_policy: IndentPolicy of the lines, the default one
+                              if None.
+        :param cache: ReaderCache taking the lines of an unchanged file
+                      from a previous read.
         """
-        with open(filename) as f:
-            StrReader.__init__(self, f.read())
-        self.filename = filename
+        indent_policy = indent_policy or IndentPolicy()
+        if cache is None:
+            StrReader.__init__(self, self._read(filename), indent_policy,
+                               filename)
+            return
+        key = cache.key(filename, indent_policy)
+        lines = cache.get(key)
+        if lines is None:
+            StrReader.__init__(self, self._read(filename), indent_policy,
+                               filename)
+            cache.put(key, self._lines)
+        else:
+            StrReader.__init__(self, "", indent_policy, filename)
+            self._lines = lines
+
+    def _read(self, filename):
+        # gzip compressed files are read as their uncompressed text
+        with open(filename, "rb") as f:
+            compressed = (f.read(len(_gzip_magic)) == _gzip_magic or
+                          filename.endswith(".gz"))
+        opener = gzip.open if compressed else open
+        with opener(filename, "rt") as f:
+            try:
+                return f.read()
+            except UnicodeDecodeError as details:
+                linenum = details.object[:details.start].count(b"\n") + 1
+                raise LexerError("Undecodable content: %s" % details.reason,
+                                 None, filename, linenum)
+            except (gzip.BadGzipFile, EOFError) as details:
+                raise LexerError("Invalid gzip file %s: %s" %
+                                 (filename, details))
+
+
+class ReaderCache(object):
+
+    """
+    Lines of the files already read, shared by the parsers using it.
+
+    A file is read again once its modification time or its size changes,
+    only the last read of every file is kept.
+    """
+
+    def __init__(self):
+        # path -> (key, lines)
+        self._entries = {}
+
+    def key(self, filename, indent_policy):
+        """
+        :return: Key of the current state of the file.
+        """
+        stat = os.stat(filename)
+        return (os.path.abspath(filename), stat.st_mtime_ns, stat.st_size,
+                indent_policy.mode, indent_policy.tab_width)
+
+    def get(self, key):
+        """
+        :return: Lines stored under key or None.
+        """
+        entry = self._entries.get(key[0])
+        if entry is None or entry[0] != key:
+            return None
+        return entry[1]
+
+    def put(self, key, lines):
+        self._entries[key[0]] = (key, lines)
+
+    def clear(self):
+        self._entries.clear()
+
+
+def strip_comment(value):
+    """
+    Drop a trailing comment from a value.
+
+    A comment starts with a "#" at the beginning of the value or after
+    whitespace, unless it is quoted or escaped as "\\#", so that "a#b" and
+    "a \\# b" survive as literal values.  Escape sequences are kept, see
+    :func:`unescape`.
+
+    :param value: Value with leading whitespace stripped.
+    :return: Value without the comment.
+    """
+    if "#" not in value:
+        return value
+    quote = value[0] if value[0] in "\"'" else None
+    chars = []
+    i = 1 if quote else 0
+    if quote:
+        chars.append(quote)
+    while i < len(value):
+        char = value[i]
+        if char == "\\":
+            chars.append(value[i:i + 2])
+            i += 2
+            continue
+        if quote:
+            if char == quote:
+                quote = None
+        elif char == "#" and (i == 0 or value[i - 1].isspace()):
+            break
+        chars.append(char)
+        i += 1
+    return "".join(chars).rstrip()
+
+
+#: escape sequences recognized in values
+escapes = {"n": "\n", "t": "\t", "\\": "\\", "#": "#"}
+
+
+def unescape(value):
+    """
+    Replace the escape sequences of a value.
+
+    Only "\\n", "\\t", "\\\\" and "\\#" are recognized, any other
+    backslash is kept literally.
+
+    :param value: Value with its comment stripped.
+    :return: Value with the escape sequences replaced.
+    """
+    if "\\" not in value:
+        return value
+    chars = []
+    i = 0
+    while i < len(value):
+        char = value[i]
+        if char == "\\" and value[i + 1:i + 2] in escapes:
+            chars.append(escapes[value[i + 1]])
+            i += 2
+            continue
+        chars.append(char)
+        i += 1
+    return "".join(chars)
 
 
 spec_iden = "_-"
-spec_oper = "+<?~"
+spec_oper = "+<?~-"
+
+
+def _identifier_char(char):
+    """
+    Check whether char belongs to an identifier.
+
+    Identifiers are made of unicode letters, numbers and combining marks
+    (the Mn and Mc categories), "_" and "-".
+    """
+    return (char.isalnum() or char in spec_iden or
+            unicodedata.category(char) in ("Mn", "Mc"))
+
+
+def _wildcard(line, pos, chars):
+    """
+    Check whether the char at pos is a "*" or "?" wildcard of a filter term.
+
+    A "?" only continues the term in chars, at the start of a term or
+    followed by "=", "+" or "<" it starts an operator instead.
+    """
+    char = line[pos]
+    return char == "*" or (char == "?" and chars != "" and
+                           line[pos + 1:pos + 2] not in ("=", "+", "<"))
+
+
+def _assignment_operator(line):
+    """
+    Find the operator of a line if it is an assignment.
+
+    The line is an assignment when an operator outside of parentheses comes
+    before the first colon, the colons past the operator belong to the
+    value.  The operators within parentheses are those of a "(key=value)"
+    filter term of a conditional block.
+
+    :return: Match of the operator or None.
+    """
+    cind = line.find(":")
+    for m in _ops_exp.finditer(line):
+        if 0 <= cind < m.start():
+            return None
+        start = m.start()
+        if line.count("(", 0, start) <= line.count(")", 0, start):
+            return m
+    return None
+
+
+def _assigns_key(line, key):
+    """
+    Check if a line is an assignment to the given key.
+
+    A message such as "print x=1" has an operator too, it only assigns
+    the key when nothing else comes before the operator.
+    """
+    m = _assignment_operator(line)
+    return m is not None and line[:m.start()].strip() == key
+
+
+def _term_char(line, pos, chars):
+    """
+    Check whether the char at pos continues an identifier or a filter term.
+
+    A "-" is part of the names unless it starts the "-=" operator, a comma
+    escaped by a backslash is part of the names as well.
+    """
+    char = line[pos]
+    if char == "-" and line[pos + 1:pos + 2] == "=":
+        return False
+    if char == "\\":
+        return line[pos + 1:pos + 2] == ","
+    if char == "," and pos > 0 and line[pos - 1] == "\\":
+        return True
+    return _identifier_char(char) or _wildcard(line, pos, chars)
 
 
 class Lexer(object):
@@ -192,16 +661,108 @@ class Lexer(object):
         self.filename = reader.filename
         self.line = None
         self.linenum = 0
+        self.indent = 0
         self.ignore_white = False
         self.rest_as_string = False
         self.match_func_index = 0
         self.generator = self.get_lexer()
         self.prev_indent = 0
         self.fast = False
+        # Span of the last token produced by match()
+        self.span = None
+        self.indent_stack = [0]
+        # real paths of the files including this one
+        self.includes = ()
+        # NFC normalize the identifiers
+        self.normalize = False
 
     def set_prev_indent(self, prev_indent):
         self.prev_indent = prev_indent
 
+    def indent_tokens(self, indent):
+        """
+        Compute indentation transition tokens for a line.
+
+        Works like INDENT/DEDENT in the python tokenizer: a deeper line
+        pushes its indentation on the stack, a shallower one pops levels
+        until a matching one is found.
+
+        :param indent: Leading whitespace count of the current line.
+        :return: List of LIndent/LDedent tokens.
+        """
+        tokens = []
+        if indent > self.indent_stack[-1]:
+            self.indent_stack.append(indent)
+            tokens.append(LIndent(indent))
+        while indent < self.indent_stack[-1]:
+            self.indent_stack.pop()
+            tokens.append(LDedent(self.indent_stack[-1]))
+        if indent != self.indent_stack[-1]:
+            raise LexerError("Dedent to %s does not match any outer"
+                             " indentation level" % indent,
+                             self.line, self.filename, self.linenum)
+        return tokens
+
+    def _identifier(self, chars):
+        # "\," is a comma of the name and not a separator of filter terms
+        chars = chars.replace("\\,", ",")
+        if self.normalize:
+            chars = unicodedata.normalize("NFC", chars)
+        return LIdentifier(chars)
+
+    def dedent_all(self):
+        """
+        Close all open indentation levels at the end of input.
+
+        :return: List of LDedent tokens.
+        """
+        return self.indent_tokens(0)
+
+    def tokenize(self, spans=False):
+        """
+        Tokenize the whole input of the reader.
+
+        Unlike the generator used by the parser, the returned stream carries
+        explicit LIndent/LDedent transitions instead of per-line indentation.
+        The reader is consumed by this call.
+
+        :param spans: Return (token, Span) pairs instead of the tokens.  The
+                      indentation tokens span the leading whitespace, the
+                      final LDedent tokens have no span.
+        :return: List of tokens.
+        """
+        tokens = []
+        line, indent, linenum = self.reader.get_next_line(-1)
+        while line is not None:
+            indent_tokens, line_tokens = self.line_tokens(line, indent,
+                                                          linenum)
+            tokens += indent_tokens + line_tokens
+            line, indent, linenum = self.reader.get_next_line(-1)
+        tokens += [(t, None) for t in self.dedent_all()]
+        if spans:
+            return tokens
+        return [t for t, _ in tokens]
+
+    def line_tokens(self, line, indent, linenum):
+        """
+        Tokenize a line of the reader, see tokenize().
+
+        :return: (indentation tokens, tokens of the line) lists of (token,
+                 Span) pairs.
+        """
+        self.line = line
+        self.indent = indent
+        self.linenum = linenum
+        span = Span(linenum, 1, indent + 1)
+        return ([(t, span) for t in self.indent_tokens(indent)],
+                [(t, self.span) for t in self.match(line, 0)])
+
+    def _span(self, start, end):
+        """
+        Set the span of the next token from positions in the stripped line.
+        """
+        self.span = Span(self.linenum, self.column(start), self.column(end))
+
     def set_fast(self):
         self.fast = True
 
@@ -215,106 +776,173 @@ class Lexer(object):
         cind = 0
         if l0 == "v":
             if line.startswith("variants:"):
+                self._span(0, 8)
                 yield LVariants()
+                self._span(8, 9)
                 yield LColon()
                 pos = 9
             elif line.startswith("variants "):
+                self._span(0, 8)
                 yield LVariants()
                 pos = 8
         elif l0 == "-":
+            self._span(0, 1)
             yield LVariant()
             pos = 1
         elif l0 == "o":
             if line.startswith("only "):
+                self._span(0, 4)
                 yield LOnly()
                 pos = 4
-                while line[pos].isspace():
+                while pos < len(line) and line[pos].isspace():
                     pos += 1
         elif l0 == "n":
             if line.startswith("no "):
+                self._span(0, 2)
                 yield LNo()
                 pos = 2
-                while line[pos].isspace():
+                while pos < len(line) and line[pos].isspace():
                     pos += 1
         elif l0 == "i":
             if line.startswith("include "):
+                self._span(0, 7)
                 yield LInclude()
                 pos = 7
         elif l0 == "d":
             if line.startswith("del "):
+                self._span(0, 3)
                 yield LDel()
                 pos = 3
-                while line[pos].isspace():
+                while pos < len(line) and line[pos].isspace():
                     pos += 1
         elif l0 == "s":
             if line.startswith("suffix "):
+                self._span(0, 6)
                 yield LSuffix()
                 pos = 6
-                while line[pos].isspace():
+                while pos < len(line) and line[pos].isspace():
+                    pos += 1
+        elif l0 == "p":
+            if (line.startswith("print ") and
+                    not _assigns_key(line, "print")):
+                # "print = value" still sets the key named print
+                self._span(0, 5)
+                yield LPrint()
+                pos = 5
+            elif (line.startswith("prefix ") and
+                    not _assigns_key(line, "prefix")):
+                # "prefix = value" still sets the key named prefix
+                self._span(0, 6)
+                yield LPrefix()
+                pos = 6
+                while pos < len(line) and line[pos].isspace():
                     pos += 1
         elif l0 == "j":
             if line.startswith("join "):
+                self._span(0, 4)
                 yield LJoin()
                 pos = 4
-                while line[pos].isspace():
+                while pos < len(line) and line[pos].isspace():
                     pos += 1
 
         if self.fast and pos == 0:  # due to refexp
             cind = line[pos:].find(":")
-            m = _ops_exp.search(line[pos:])
+            m = _assignment_operator(line)
 
         oper = ""
         token = None
 
         if self.rest_as_string:
             self.rest_as_string = False
-            yield LString(line[pos:].lstrip())
-        elif self.fast and m and (cind < 0 or cind > m.end()):
+            self._span_value(line, pos)
+            yield self.value_string(line, pos)
+        elif self.fast and m and (cind < 0 or cind >= m.end()):
             chars = ""
-            yield LIdentifier(line[:m.start()].rstrip())
+            self._span(0, len(line[:m.start()].rstrip()))
+            yield self._identifier(line[:m.start()].rstrip())
+            self._span(m.start(), m.end())
             yield tokens_oper[m.group()[:-1]]()
-            yield LString(line[m.end():].lstrip())
+            self._span_value(line, m.end())
+            yield self.value_string(line, m.end())
         else:
             li = enumerate(line[pos:], pos)
             for pos, char in li:
-                if char.isalnum() or char in spec_iden:    # alfanum+_-
+                # letters, numbers, marks, _- and the wildcards
+                if _term_char(line, pos, chars):
+                    if oper:
+                        self._unfinished_operator(oper, pos)
                     chars += char
-                elif char in spec_oper:     # <+?=~
+                elif char in spec_oper:     # <+?~-
                     if chars:
-                        yield LIdentifier(chars)
+                        self._span(pos - len(chars), pos)
+                        yield self._identifier(chars)
                         oper = ""
                     chars = ""
                     oper += char
                 else:
                     if chars:
-                        yield LIdentifier(chars)
+                        self._span(pos - len(chars), pos)
+                        yield self._identifier(chars)
                         chars = ""
                     if char.isspace():   # Whitespace
+                        start = pos
                         for pos, char in li:
                             if not char.isspace():
                                 if not self.ignore_white:
+                                    self._span(start, pos)
                                     yield LWhite()
                                 break
-                    if char.isalnum() or char in spec_iden:
+                    if oper and char != "=" and char not in spec_oper:
+                        self._unfinished_operator(oper, pos)
+                    if _term_char(line, pos, chars):
                         chars += char
                     elif char == "=":
                         if oper in tokens_oper:
+                            self._span(pos - len(oper), pos + 1)
                             yield tokens_oper[oper]()
                         else:
                             raise LexerError("Unexpected character %s on"
                                              " pos %s" % (char, pos),
                                              self.line, self.filename,
-                                             self.linenum)
+                                             self.linenum, self.column(pos),
+                                             self.column(pos + 1))
                         oper = ""
+                    elif char == "-" and line[pos + 1:pos + 2] == "=":
+                        oper += char
                     elif char in tokens_map:
                         token = tokens_map[char]()
+                        self._span(pos, pos + 1)
                     elif char == "\"":
                         chars = ""
-                        pos, char = next(li)
-                        while char != "\"":
+                        start = pos
+                        for pos, char in li:
+                            if char == "\"":
+                                break
                             chars += char
-                            pos, char = next(li)
+                        else:
+                            raise LexerError("Unterminated string \"%s" %
+                                             chars, self.line, self.filename,
+                                             self.linenum, self.column(start),
+                                             self.column(len(line)))
+                        self._span(start, pos + 1)
                         yield LString(chars)
+                        chars = ""
+                    elif char == "/":
+                        chars = ""
+                        start = pos
+                        for pos, char in li:
+                            if char == "/":
+                                break
+                            chars += char
+                        else:
+                            raise LexerError("Unterminated regular expression"
+                                             " %s" % chars, self.line,
+                                             self.filename, self.linenum,
+                                             self.column(start),
+                                             self.column(len(line)))
+                        self._span(start, pos + 1)
+                        yield LRegExp(chars)
+                        chars = ""
                     elif char == "#":
                         break
                     elif char in spec_oper:
@@ -324,25 +952,71 @@ class Lexer(object):
                                          " pos %s. Special chars are allowed"
                                          " only in variable assignation"
                                          " statement" % (char, pos), line,
-                                         self.filename, self.linenum)
+                                         self.filename, self.linenum,
+                                         self.column(pos),
+                                         self.column(pos + 1))
                     if token is not None:
                         yield token
                         token = None
                     if self.rest_as_string:
                         self.rest_as_string = False
+                        self._span_value(line, pos + 1)
                         yield LString(line[pos + 1:].lstrip())
                         break
+        if oper:
+            self._unfinished_operator(oper, pos)
         if chars:
-            yield LIdentifier(chars)
+            self._span(len(line) - len(chars), len(line))
+            yield self._identifier(chars)
             chars = ""
+        self._span(len(line), len(line))
         yield LEndL()
 
+    def _span_value(self, line, pos):
+        """
+        Set the span of the value starting at pos, without its comment.
+        """
+        start = len(line) - len(line[pos:].lstrip())
+        self._span(start, start + len(strip_comment(line[start:]).rstrip()))
+
+    def value_string(self, line, pos):
+        """
+        Lex the rest of the line as an assignment value.
+
+        A value starting with a quote has to contain the closing quote, its
+        contents are kept verbatim including "#", ":" and ",".  Escape
+        sequences are replaced in both quoted and plain values.
+
+        :param pos: Position where the value starts, whitespace may follow.
+        :return: LString of the value without a trailing comment.
+        """
+        value = line[pos:].lstrip()
+        if value[:1] in ("\"", "'") and value[0] not in value[1:]:
+            raise LexerError("Unterminated string %s" % value, self.line,
+                             self.filename, self.linenum,
+                             self.column(len(line) - len(value)),
+                             self.column(len(line.rstrip())))
+        return LString(unescape(strip_comment(value)))
+
+    def _unfinished_operator(self, oper, pos):
+        raise LexerError("Operator %s on pos %s is not followed by \"=\"" %
+                         (oper, pos), self.line, self.filename, self.linenum,
+                         self.column(pos), self.column(pos + 1))
+
+    def column(self, pos):
+        """
+        :param pos: Position in the stripped line.
+        :return: 1-based column of pos in the source line.
+        """
+        return self.indent + pos + 1
+
     def get_lexer(self):
         cr = self.reader
         indent = 0
         while True:
             (self.line, indent,
              self.linenum) = cr.get_next_line(self.prev_indent)
+            self.indent = indent
 
             if not self.line:
                 yield LEndBlock(indent)
@@ -390,7 +1064,8 @@ class Lexer(object):
                 tokens.append(token)
             else:
                 raise ParserError("Expected %s got %s" % (lType, type(token)),
-                                  self.line, self.filename, self.linenum)
+                                  self.line, self.filename, self.linenum,
+                                  *self._token_columns())
         return tokens
 
     def get_until_no_white(self, end_tokens=None):
@@ -422,6 +1097,14 @@ class Lexer(object):
         next(self.generator)
         return lstr
 
+    def _token_columns(self):
+        """
+        :return: Columns of the span of the last token, for the errors.
+        """
+        if self.span is None or self.span.linenum != self.linenum:
+            return None, None
+        return self.span.start, self.span.end
+
     def get_next_check(self, lType):
         token = next(self.generator)
         if type(token) in lType:
@@ -430,7 +1113,8 @@ class Lexer(object):
             raise ParserError("Expected %s got ['%s']=[%s]" %
                               ([x.identifier for x in lType],
                                token.identifier, token),
-                              self.line, self.filename, self.linenum)
+                              self.line, self.filename, self.linenum,
+                              *self._token_columns())
 
     def get_next_check_nw(self, lType):
         token = next(self.generator)
@@ -442,7 +1126,8 @@ class Lexer(object):
             raise ParserError("Expected %s got ['%s']" %
                               ([x.identifier for x in lType],
                                token.identifier),
-                              self.line, self.filename, self.linenum)
+                              self.line, self.filename, self.linenum,
+                              *self._token_columns())
 
     def check_token(self, token, lType):
         if type(token) in lType:
@@ -469,10 +1154,165 @@ def cmd_tokens(tokens1, tokens2):
         return True
 
 
+class _PreDict(dict):
+
+    """
+    Assignments waiting for apply_predict() with their line numbers.
+    """
+
+    __slots__ = ["lines"]
+
+    def __init__(self):
+        dict.__init__(self)
+        self.lines = {}
+
+
 def apply_predict(lexer, node, pre_dict):
-    predict = LApplyPreDict().set_operands(None, pre_dict)
+    predict = LApplyPreDict().set_operands(None, dict(pre_dict))
+    predict.lines = pre_dict.lines
     node.content += [(lexer.filename, lexer.linenum, predict)]
-    return {}
+    return _PreDict()
+
+
+def _set_operands(lexer, op, name, value):
+    """
+    Set the operands of op reporting invalid key expressions at the line.
+    """
+    try:
+        op.set_operands(name, value)
+    except re.error as details:
+        raise ParserError("Invalid regular expression %s: %s" %
+                          (name, details), lexer.line, lexer.filename,
+                          lexer.linenum)
+
+
+def _include_params(lexer, text):
+    """
+    :return: Dict of the "key=value" parameters of an include.
+    """
+    params = {}
+    for param in text.split():
+        match = _include_param_exp.match(param)
+        if not match:
+            raise ParserError("Invalid include parameter %s, expected"
+                              " key=value" % param, lexer.line,
+                              lexer.filename, lexer.linenum)
+        params[sys.intern(match.group(1))] = match.group(2)
+    return params
+
+
+def _bracket_named_labels(tokens):
+    """
+    Rewrite the bare "name=value" filter terms to "(name=value)".
+    """
+    result = []
+    depth = 0
+    i = 0
+    while i < len(tokens):
+        token = tokens[i]
+        if isinstance(token, LLRBracket):
+            depth += 1
+        elif isinstance(token, LRRBracket):
+            depth -= 1
+        elif depth == 0 and type(token) is LIdentifier:
+            rest = [t for t in tokens[i + 1:i + 6]
+                    if not isinstance(t, LWhite)]
+            if (len(rest) >= 2 and isinstance(rest[0], LSet) and
+                    type(rest[1]) is LIdentifier):
+                result += [LLRBracket(), token, rest[0], rest[1],
+                           LRRBracket()]
+                # skip up to the value including the whitespace
+                i += 1
+                while tokens[i] is not rest[1]:
+                    i += 1
+                i += 1
+                continue
+        result.append(token)
+        i += 1
+    return result
+
+
+def _split_alternatives(tokens):
+    """
+    Split the tokens on the "," and " " separators outside of brackets.
+
+    The whitespace around "=" of the "name = value" terms is dropped.
+    """
+    terms = [[]]
+    depth = 0
+    for i, token in enumerate(tokens):
+        if isinstance(token, LLRBracket):
+            depth += 1
+        elif isinstance(token, LRRBracket):
+            depth -= 1
+        elif depth == 0 and isinstance(token, LComa):
+            terms.append([])
+            continue
+        elif depth == 0 and isinstance(token, LWhite):
+            prev = [t for t in tokens[:i] if not isinstance(t, LWhite)]
+            rest = [t for t in tokens[i + 1:] if not isinstance(t, LWhite)]
+            if not ((prev and isinstance(prev[-1], LSet)) or
+                    (rest and isinstance(rest[0], LSet))):
+                terms.append([])
+            continue
+        terms[-1].append(token)
+    return [term for term in terms if term]
+
+
+def _is_label_bracket(tokens):
+    """
+    Check if the bracket content is a "(name)" or "(name=value)" label.
+    """
+    tokens = [t for t in tokens if not isinstance(t, LWhite)]
+    types = [type(t) for t in tokens]
+    return (types == [LIdentifier] or
+            (len(tokens) == 3 and types[0] is LIdentifier and
+             isinstance(tokens[1], LSet) and
+             isinstance(tokens[2], LIdentifier)))
+
+
+def _expand_term(term):
+    """
+    Expand the bracketed groups of one term to all their alternatives.
+    """
+    i = 0
+    while i < len(term):
+        if isinstance(term[i], LLRBracket):
+            depth = 0
+            for j in range(i, len(term)):
+                if isinstance(term[j], LLRBracket):
+                    depth += 1
+                elif isinstance(term[j], LRRBracket):
+                    depth -= 1
+                    if depth == 0:
+                        break
+            else:
+                # unbalanced, left for the filter parser to report
+                return [term]
+            group = term[i + 1:j]
+            if not _is_label_bracket(group):
+                return [expanded
+                        for alternative in _split_alternatives(group)
+                        for expanded in _expand_term(term[:i] + alternative +
+                                                     term[j + 1:])]
+            i = j
+        i += 1
+    return [term]
+
+
+def _expand_groups(tokens):
+    """
+    Rewrite the "(a, b).c" groups to the equivalent "a.c, b.c" filter.
+    """
+    if not any(isinstance(t, LLRBracket) for t in tokens):
+        return tokens
+    result = []
+    for term in _split_alternatives(tokens):
+        for expanded in _expand_term(term):
+            if result:
+                result.append(LComa())
+            result += expanded
+    return result
 
 
 def parse_filter(lexer, tokens):
@@ -480,14 +1320,14 @@ def parse_filter(lexer, tokens):
     :return: Parsed filter
     """
     or_filters = []
-    tokens = iter(tokens + [LEndL()])
+    tokens = iter(_bracket_named_labels(_expand_groups(tokens)) + [LEndL()])
     typet, token = lexer.check_token(next(tokens), [LIdentifier, LLRBracket,
-                                                    LEndL, LWhite])
+                                                    LRegExp, LEndL, LWhite])
     and_filter = []
     con_filter = []
     dots = 1
     while typet not in [LEndL]:
-        if typet in [LIdentifier, LLRBracket]:        # join    identifier
+        if typet in [LIdentifier, LLRBracket, LRegExp]:   # join identifier
             if typet == LLRBracket:    # (xxx=ttt)
                 _, ident = lexer.check_token(next_nw(tokens),
                                              [LIdentifier])  # (iden
@@ -500,6 +1340,15 @@ def parse_filter(lexer, tokens):
                                                  [LIdentifier, LString])
                     lexer.check_token(next_nw(tokens), [LRRBracket])
                     token = Label(str(ident), str(value))
+            elif typet == LRegExp:    # /regexp/
+                try:
+                    token = RegExpLabel(str(token))
+                except re.error as details:
+                    raise ParserError("Invalid regular expression /%s/: %s" %
+                                      (token, details), lexer.line,
+                                      lexer.filename, lexer.linenum)
+            elif "*" in token or "?" in token:
+                token = GlobLabel(str(token))
             else:
                 token = Label(token)
             if dots == 1:
@@ -541,11 +1390,13 @@ def parse_filter(lexer, tokens):
                 token = next(tokens)
             typet, token = lexer.check_token(token, [LIdentifier,
                                                      LComa, LDot,
-                                                     LLRBracket, LEndL])
+                                                     LLRBracket, LRegExp,
+                                                     LEndL])
             continue
         typet, token = lexer.check_token(next(tokens), [LIdentifier, LComa,
                                                         LDot, LLRBracket,
-                                                        LEndL, LWhite])
+                                                        LRegExp, LEndL,
+                                                        LWhite])
     if and_filter:
         if con_filter:
             and_filter.append(con_filter)
@@ -565,9 +1416,54 @@ class Parser(object):
     # pylint: disable=W0102
 
     def __init__(self, filename=None, defaults=False, expand_defaults=[],
-                 debug=False):
+                 debug=False, strict_substitution=False, indent_policy=None,
+                 max_depth=200, expand_lists=False,
+                 strict_duplicates=False, name_separator=".",
+                 strict_keys=False, lazy_includes=False,
+                 reader_cache=None, normalize_identifiers=False,
+                 max_combinations=None, inline_conditions=False,
+                 dedup_dicts=False):
         self.node = Node()
         self.debug = debug
+        self.indent_policy = indent_policy
+        # nested blocks while parsing and variants in a dictionary
+        self.max_depth = max_depth
+        self._depth = 0
+        # dictionaries get_dicts() may generate, None for no limit
+        self.max_combinations = max_combinations
+        # drop the dictionaries equal to a previous one but for the names
+        self.dedup_dicts = dedup_dicts
+        # only accept assignments, see parse_flat()
+        self._flat = False
+        # prepended to the keys assigned by the statements being parsed
+        self._key_prefix = ""
+        # state of the expansions running in every thread, see _origins
+        self._local = threading.local()
+        # skipdups -> unfiltered dicts, see get_dicts_filtered()
+        self._expansion = {}
+        # name -> (line, relative indent) of the "variants @name:" bodies
+        self._templates = {}
+        # (key, value, compiled regexp or None) of exclude_by*()
+        self.exclusions = []
+        # key -> value set before any statement, see with_defaults()
+        self.key_defaults = {}
+        # "key = a, b" defines variants a and b instead of a literal value
+        self.expand_lists = expand_lists
+        # "key = a if filter else b" is resolved against the variant names
+        self.inline_conditions = inline_conditions
+        # error out on a key set twice by "=" in the same block
+        self.strict_duplicates = strict_duplicates
+        # error out on assignments to the keys set by the parser
+        self.strict_keys = strict_keys
+        # read the included files only once the expansion reaches them
+        self.lazy_includes = lazy_includes
+        # ReaderCache of the parsed files or None
+        self.reader_cache = reader_cache
+        # NFC normalize the identifiers so that equal names compare equal
+        self.normalize_identifiers = normalize_identifiers
+        # joins the variant names in the name, shortname and dep keys
+        self.name_separator = name_separator
+        self.strict_substitution = strict_substitution
         self.defaults = defaults
         self.expand_defaults = [LIdentifier(x) for x in expand_defaults]
 
@@ -579,13 +1475,64 @@ class Parser(object):
         self.no_filters = []
         self.assignments = []
 
-        # get_dicts() - is recursive generator, it can invoke itself,
-        # as well as it can be called outside to get dic list
-        # It is necessary somehow mark top-level generator,
-        # to be able process all variables, do suffix stuff, drops dups, etc....
-        # It can be safely done only on top top level get_dicts()
-        # Parent generator will reset this flag
-        self.parent_generator = True
+    def __iter__(self):
+        """
+        Generate the dictionaries, see get_dicts().  Every iteration expands
+        the configuration again.
+        """
+        return self.get_dicts()
+
+    def __getstate__(self):
+        state = self.__dict__.copy()
+        # The parser may be pickled while one of its generators is running,
+        # the copy starts without the state of the running expansions.
+        del state["_local"]
+        return state
+
+    def __setstate__(self, state):
+        self.__dict__.update(state)
+        self._local = threading.local()
+
+    @property
+    def _origins(self):
+        """
+        Id of content -> declaring variant, see get_dicts_provenance().
+
+        The origins and the trace belong to the thread expanding the
+        configuration, the other threads expand it as usual meanwhile.
+        """
+        return getattr(self._local, "origins", None)
+
+    @_origins.setter
+    def _origins(self, origins):
+        self._local.origins = origins
+
+    @property
+    def _trace(self):
+        """
+        _Trace of the combination being explained, see explain().
+        """
+        return getattr(self._local, "trace", None)
+
+    @_trace.setter
+    def _trace(self, trace):
+        self._local.trace = trace
+
+    def _failed_cases(self, node):
+        """
+        Filters recently failing in node, the cases of _get_dicts().
+
+        Every thread caches its own, the node itself isn't changed by the
+        expansions.
+        """
+        cache = getattr(self._local, "failed_cases", None)
+        if cache is None:
+            cache = self._local.failed_cases = {}
+        cached = cache.get(id(node))
+        # a node of a previous parse may have had the same id
+        if cached is None or cached[0] is not node:
+            cached = cache[id(node)] = (node, collections.deque())
+        return cached[1]
 
     def _debug(self, s, *args):
         if self.debug:
@@ -600,8 +1547,12 @@ class Parser(object):
 
         :param filename: Path of the configuration file.
         """
+        self._expansion.clear()
         self.node.filename = filename
-        self.node = self._parse(Lexer(FileReader(filename)), self.node)
+        self.node = self._parse(Lexer(FileReader(filename,
+                                                  self.indent_policy,
+                                                  self.reader_cache)),
+                                self.node)
         self.filename = filename
 
     def parse_string(self, s):
@@ -610,8 +1561,10 @@ class Parser(object):
 
         :param s: String to parse.
         """
+        self._expansion.clear()
         self.node.filename = StrReader("").filename
-        self.node = self._parse(Lexer(StrReader(s)), self.node)
+        self.node = self._parse(Lexer(StrReader(s, self.indent_policy)),
+                                self.node)
 
     def only_filter(self, variant):
         """
@@ -652,14 +1605,21 @@ class Parser(object):
     def _parse(self, lexer, node=None, prev_indent=-1):
         if not node:
             node = self.node
+        # suffixes only affect the keys set from here on
+        scope = node
+        # variant the parsed statements are declared in
+        body_scope = getattr(node, "scope", "")
+        # a prefix of this block replaces the one of the outer blocks
+        key_prefix = self._key_prefix
         block_allowed = [LVariants, LIdentifier, LOnly,
-                         LNo, LInclude, LDel, LNotCond, LSuffix, LJoin]
+                         LNo, LInclude, LDel, LNotCond, LSuffix, LPrefix,
+                         LJoin, LLRBracket, LPrint]
 
         variants_allowed = [LVariant]
 
         identifier_allowed = [LSet, LAppend, LPrepend, LLazySet,
                               LRegExpSet, LRegExpAppend,
-                              LRegExpPrepend, LColon,
+                              LRegExpPrepend, LRegExpDel, LColon,
                               LEndL]
 
         varianst_allowed_in = [LLBracket, LColon, LIdentifier, LEndL]
@@ -668,12 +1628,15 @@ class Parser(object):
         allowed = block_allowed
         var_indent = 0
         var_name = ""
+        var_line = None
+        var_linenum = 0
         # meta contains variants meta-data
         meta = {}
         # pre_dict contains block of operation without collision with
         # others block or operation. Increase speed almost twice.
-        pre_dict = {}
+        pre_dict = _PreDict()
         lexer.set_fast()
+        lexer.normalize = self.normalize_identifiers
 
         # Suffix should be applied as the last operator in the dictionary
         # Reasons:
@@ -681,21 +1644,41 @@ class Parser(object):
         #     2. Affect all elements in current block
         suffix = None
 
+        # line numbers of the keys set by "=" in this block
+        assigned = {}
+
         try:
+            self._depth += 1
+            if self._depth > self.max_depth:
+                raise ParserError("Maximum nesting depth %d exceeded" %
+                                  self.max_depth, lexer.line, lexer.filename,
+                                  lexer.linenum)
             while True:
                 lexer.set_prev_indent(prev_indent)
                 typet, token = lexer.get_next_check(indent_allowed)
                 if typet == LEndBlock:
+                    if allowed == variants_allowed:
+                        raise ParserError("'variants' block without any"
+                                          " variant", var_line,
+                                          lexer.filename, var_linenum)
                     if pre_dict:
                         # flush pre_dict to node content.
                         pre_dict = apply_predict(lexer, node, pre_dict)
                     if suffix:
                         # Node has suffix, apply it to all elements
+                        # set since the start of the block
+                        scope_operator = SuffixScope().set_operands(None,
+                                                                    suffix[2])
+                        scope.content.insert(0, suffix[:2] + (scope_operator,))
                         node.content.append(suffix)
                     return node
 
                 indent = token.length
                 typet, token = lexer.get_next_check(allowed)
+                if self._flat and typet in _flat_rejected:
+                    raise ParserError("%s not allowed in a flat configuration"
+                                      % _flat_rejected[typet], lexer.line,
+                                      lexer.filename, lexer.linenum)
 
                 if typet == LIdentifier:
                     # Parse:
@@ -713,15 +1696,77 @@ class Parser(object):
                         else:
                             identifier = [token] + identifier[:-1]
                             identifier = "".join([str(x) for x in identifier])
+                        if (self._key_prefix and
+                                not isinstance(op, LRegExpOperators) and
+                                not is_reserved_key(str(identifier))):
+                            identifier = self._key_prefix + str(identifier)
                         _, value = lexer.get_next_check([LString])
+                        inline = None
+                        if (self.inline_conditions and
+                                not (value and value[0] == value[-1] and
+                                     value[0] in "\"'")):
+                            inline = _inline_cond_exp.match(value)
+                        if inline:
+                            # Parse:
+                            #    identifier = value1 if filter else value2
+                            if self._flat:
+                                raise ParserError("Conditional value not"
+                                                  " allowed in a flat"
+                                                  " configuration",
+                                                  lexer.line, lexer.filename,
+                                                  lexer.linenum)
+                            if pre_dict:
+                                pre_dict = apply_predict(lexer, node,
+                                                         pre_dict)
+                            node.content += self._inline_condition(
+                                lexer, type(op), str(identifier), inline)
+                            lexer.get_next_check([LEndL])
+                            continue
                         if value and (value[0] == value[-1] == '"' or
                                       value[0] == value[-1] == "'"):
                             value = value[1:-1]
-
-                        op.set_operands(identifier, value)
+                        elif (self.expand_lists and type(op) is LSet and
+                              type(node) not in [Condition,
+                                                 NegativeCondition]):
+                            names = [v.strip() for v in split_escaped(value)]
+                            if len(names) == 1:
+                                # the commas escaped not to define variants
+                                value = names[0]
+                            if (len(names) > 1 and
+                                    all(_list_item_exp.match(v)
+                                        for v in names)):
+                                # Parse:
+                                #    identifier = value1, value2, ...
+                                # as "variants identifier:" of the values
+                                if pre_dict:
+                                    pre_dict = apply_predict(lexer, node,
+                                                             pre_dict)
+                                builder = ConfigBuilder(lexer.filename)
+                                builder.add_variants(str(identifier), names)
+                                node = builder._build(node)
+                                lexer.get_next_check([LEndL])
+                                continue
+
+                        _set_operands(lexer, op, identifier, value)
+                        if (self.strict_keys and
+                                not isinstance(op, LRegExpOperators) and
+                                is_reserved_key(op.name)):
+                            raise ParserError("Assignment to the reserved key"
+                                              " %s" % op.name, lexer.line,
+                                              lexer.filename, lexer.linenum)
+                        if self.strict_duplicates and type(op) is LSet:
+                            if op.name in assigned:
+                                raise ParserError("Duplicate assignment of %s,"
+                                                  " already set on line %s" %
+                                                  (op.name,
+                                                   assigned[op.name]),
+                                                  lexer.line, lexer.filename,
+                                                  lexer.linenum)
+                            assigned[op.name] = lexer.linenum
                         d_nin_val = "$" not in value
                         if isinstance(op, LSet) and d_nin_val:  # Optimization
                             op.apply_to_dict(pre_dict)
+                            pre_dict.lines[op.name] = lexer.linenum
                         else:
                             if pre_dict:
                                 # flush pre_dict to node content.
@@ -729,6 +1774,7 @@ class Parser(object):
                                 # then operation xxx +=, <=, .... are safe.
                                 if op.name in pre_dict and d_nin_val:
                                     op.apply_to_dict(pre_dict)
+                                    pre_dict.lines[op.name] = lexer.linenum
                                     lexer.get_next_check([LEndL])
                                     continue
                                 else:
@@ -743,6 +1789,11 @@ class Parser(object):
                     elif isinstance(identifier[-1], LColon):  # condition:
                         # Parse:
                         #    xxx.yyy.(aaa=bbb):
+                        if self._flat:
+                            raise ParserError("Conditional block not allowed"
+                                              " in a flat configuration",
+                                              lexer.line, lexer.filename,
+                                              lexer.linenum)
                         identifier = [token] + identifier[:-1]
                         cfilter = parse_filter(lexer, identifier + [LEndL()])
                         next_line = lexer.rest_line_as_LString()
@@ -750,10 +1801,11 @@ class Parser(object):
                             lexer.reader.set_next_line(next_line, indent + 1,
                                                        lexer.linenum)
                         cond = Condition(cfilter, lexer.line)
+                        linenum = lexer.linenum
                         self._parse(lexer, cond, prev_indent=indent)
 
                         pre_dict = apply_predict(lexer, node, pre_dict)
-                        node.content += [(lexer.filename, lexer.linenum, cond)]
+                        node.content += [(lexer.filename, linenum, cond)]
                     else:
                         raise ParserError("Syntax ERROR expected \":\" or"
                                           " operand", lexer.line,
@@ -776,11 +1828,14 @@ class Parser(object):
                     if var_name not in self.expand_defaults:
                         meta_in_expand_defautls = True
                     node4 = Node()
+                    node4.scope = body_scope
                     while True:
                         lexer.set_prev_indent(var_indent)
                         # Get token from lexer and check syntax.
                         typet, token = lexer.get_next_check_nw([LIdentifier,
                                                                 LDefault,
+                                                                LColon,
+                                                                LEndL,
                                                                 LIndent,
                                                                 LEndBlock])
                         if typet == LEndBlock:
@@ -790,9 +1845,20 @@ class Parser(object):
                             lexer.get_next_check_nw([LVariant])
                             typet, token = lexer.get_next_check_nw(
                                 [LIdentifier,
-                                 LDefault])
-
-                        if typet == LDefault:  # @
+                                 LDefault,
+                                 LColon,
+                                 LEndL])
+                        name_span = lexer.span
+
+                        if typet in [LColon, LEndL]:
+                            # Parse
+                            #  -: depend1
+                            #  -
+                            # an anonymous variant adds no name
+                            is_default = False
+                            name = []
+                            raw_name = []
+                        elif typet == LDefault:  # @
                             is_default = True
                             name = lexer.get_until_check([LIdentifier, LDot],
                                                          [LColon])
@@ -802,7 +1868,13 @@ class Parser(object):
                                 [LIdentifier, LDot],
                                 [LColon])
 
-                        if len(name) == 2:
+                        if name:
+                            # up to the colon
+                            name_span = name_span._replace(
+                                end=lexer.span.start)
+                        if not name:
+                            pass
+                        elif len(name) == 2:
                             name = [name[0]]
                             raw_name = name
                         else:
@@ -810,9 +1882,16 @@ class Parser(object):
                             name = [x for x in name[:-1]
                                     if isinstance(x, LIdentifier)]
 
-                        token = next(lexer.generator)
-                        while isinstance(token, LWhite):
+                        if any("*" in n or "?" in n for n in name):
+                            raise ParserError("Wildcard in the variant name",
+                                              lexer.line, lexer.filename,
+                                              lexer.linenum, name_span.start,
+                                              name_span.end)
+
+                        if typet != LEndL:
                             token = next(lexer.generator)
+                            while isinstance(token, LWhite):
+                                token = next(lexer.generator)
                         tokens = None
                         if not isinstance(token, LEndL):
                             tokens = [token] + lexer.get_until([LEndL])
@@ -824,8 +1903,14 @@ class Parser(object):
                         node2 = Node()
                         node2.children = [node]
                         node2.labels = node.labels
-
-                        if var_name:
+                        scope_labels = [str(Label(var_name, str(n))
+                                            if var_name else Label(str(n)))
+                                        for n in name]
+                        node2.scope = self.name_separator.join(
+                            ([body_scope] if body_scope else []) +
+                            scope_labels)
+
+                        if var_name and name:
                             op = LSet().set_operands(var_name,
                                                      ".".join([str(n) for n in name]))
                             node2.content += [(lexer.filename,
@@ -844,6 +1929,7 @@ class Parser(object):
                         # Update mapping name to file
 
                         node3.dep = deps
+                        node3.span = name_span
 
                         if meta_with_default:
                             for wd in meta["default"]:
@@ -900,9 +1986,17 @@ class Parser(object):
                                           "conditional block", lexer.line,
                                           lexer.reader.filename, lexer.linenum)
 
+                    var_line = lexer.line
+                    var_linenum = lexer.linenum
                     lexer.set_strict()
                     tokens = lexer.get_until_no_white([LLBracket, LColon,
                                                        LIdentifier, LEndL])
+                    if isinstance(tokens[0], LDefault):
+                        # Parse
+                        #    variants @template:
+                        self._template(lexer, tokens, indent)
+                        lexer.set_fast()
+                        continue
                     vtypet = type(tokens[-1])
                     var_name = ""
                     meta.clear()
@@ -953,7 +2047,23 @@ class Parser(object):
                         raise ParserError("Syntax ERROR expected \":\"",
                                           lexer.line, lexer.filename,
                                           lexer.linenum)
-                    lexer.get_next_check_nw([LEndL])
+                    typet, _ = lexer.get_next_check_nw([LEndL, LDefault])
+                    if typet == LDefault:
+                        # Parse
+                        #    variants _name_ [meta1]: @template
+                        _, template = lexer.get_next_check_nw([LIdentifier])
+                        lexer.get_next_check_nw([LEndL])
+                        if template not in self._templates:
+                            raise ParserError("Unknown variants template %s"
+                                              % template, lexer.line,
+                                              lexer.filename, lexer.linenum)
+                        # align the template with the variants following
+                        # the reference
+                        base = max(indent + 1, lexer.reader.next_indent())
+                        lexer.reader.insert_lines(
+                            [(line, base + rel_indent, lexer.linenum)
+                             for line, rel_indent
+                             in self._templates[template]])
                     allowed = variants_allowed
                     var_indent = indent
 
@@ -974,6 +2084,10 @@ class Parser(object):
                     # Parse:
                     #    join (filter=text)..aaa.bbb, xxxx
                     # syntax is the same as for No/Only filters
+                    if isinstance(node, (Condition, NegativeCondition)):
+                        raise ParserError("join is not supported in a"
+                                          " conditional block", lexer.line,
+                                          lexer.filename, lexer.linenum)
                     lfilter = parse_filter(lexer, lexer.rest_line())
 
                     pre_dict = apply_predict(lexer, node, pre_dict)
@@ -991,10 +2105,22 @@ class Parser(object):
                     # Suffix will be applied as all other elements in current node are processed:
                     suffix = (lexer.filename, lexer.linenum, suffix_operator)
 
+                elif typet == LPrefix:
+                    # Parse:
+                    #    prefix PREFIX
+                    _, token_val = lexer.get_next_check([LIdentifier])
+                    lexer.get_next_check([LEndL])
+                    self._key_prefix = key_prefix + token_val
+
                 elif typet == LInclude:
                     # Parse:
                     #    include relative file patch to working directory.
                     path = lexer.rest_line_as_LString()
+                    params = None
+                    match = _include_with_exp.match(path)
+                    if match:
+                        path = match.group(1)
+                        params = _include_params(lexer, match.group(2))
                     filename = os.path.expanduser(path)
                     if (isinstance(lexer.reader, FileReader) and
                             not os.path.isabs(filename)):
@@ -1004,9 +2130,36 @@ class Parser(object):
                     if not os.path.isfile(filename):
                         raise MissingIncludeError(lexer.line, lexer.filename,
                                                   lexer.linenum)
+                    includes = lexer.includes
+                    if isinstance(lexer.reader, FileReader):
+                        includes += (os.path.realpath(lexer.filename),)
+                    if os.path.realpath(filename) in includes:
+                        raise ParserError("Cyclic include of %s" % filename,
+                                          lexer.line, lexer.filename,
+                                          lexer.linenum)
                     pre_dict = apply_predict(lexer, node, pre_dict)
-                    lch = Lexer(FileReader(filename))
-                    node = self._parse(lch, node, -1)
+                    if params:
+                        params = IncludeParams().set_operands(None, params)
+                        node.content += [(lexer.filename, lexer.linenum,
+                                          params)]
+                    if self.lazy_includes:
+                        # the path outlives the working directory, e.g. in
+                        # to_config_string()
+                        lazy = LazyInclude(os.path.abspath(filename),
+                                           includes,
+                                           lexer.reader.indent_policy,
+                                           self._key_prefix)
+                        node.content += [(lexer.filename, lexer.linenum,
+                                          lazy)]
+                    else:
+                        lch = Lexer(FileReader(filename,
+                                               lexer.reader.indent_policy,
+                                               self.reader_cache))
+                        lch.includes = includes
+                        node = self._parse(lch, node, -1)
+                    if params:
+                        end = IncludeParamsEnd().set_operands(None, params)
+                        node.content += [(lexer.filename, lexer.linenum, end)]
                     lexer.set_prev_indent(prev_indent)
 
                 elif typet == LDel:
@@ -1014,7 +2167,21 @@ class Parser(object):
                     #    del operand
                     _, to_del = lexer.get_next_check_nw([LIdentifier])
                     lexer.get_next_check_nw([LEndL])
-                    token.set_operands(to_del, None)
+                    _set_operands(lexer, token, to_del, None)
+                    assigned.pop(to_del, None)
+
+                    pre_dict = apply_predict(lexer, node, pre_dict)
+                    node.content += [(lexer.filename, lexer.linenum,
+                                      token)]
+
+                elif typet == LPrint:
+                    # Parse:
+                    #    print "message ${key}"
+                    message = lexer.rest_line_as_LString()
+                    if message and (message[0] == message[-1] == '"' or
+                                    message[0] == message[-1] == "'"):
+                        message = message[1:-1]
+                    _set_operands(lexer, token, messages_key, message)
 
                     pre_dict = apply_predict(lexer, node, pre_dict)
                     node.content += [(lexer.filename, lexer.linenum,
@@ -1023,19 +2190,45 @@ class Parser(object):
                 elif typet == LNotCond:
                     # Parse:
                     #    !xxx.yyy.(aaa=bbb): vvv
-                    lfilter = parse_filter(lexer,
-                                           lexer.get_until_no_white(
-                                               [LColon, LEndL])[:-1])
+                    tokens = lexer.get_until_no_white([LColon, LEndL])
+                    if not isinstance(tokens[-1], LColon):
+                        raise ParserError("Expected \":\" after the negated"
+                                          " condition", lexer.line,
+                                          lexer.filename, lexer.linenum)
+                    lfilter = parse_filter(lexer, tokens[:-1])
                     next_line = lexer.rest_line_as_LString()
                     if next_line != "":
                         lexer.reader.set_next_line(next_line, indent + 1,
                                                    lexer.linenum)
                     cond = NegativeCondition(lfilter, lexer.line)
+                    linenum = lexer.linenum
+                    self._parse(lexer, cond, prev_indent=indent)
+                    lexer.set_prev_indent(prev_indent)
+
+                    pre_dict = apply_predict(lexer, node, pre_dict)
+                    node.content += [(lexer.filename, linenum, cond)]
+
+                elif typet == LLRBracket:
+                    # Parse:
+                    #    (aaa=bbb).xxx: vvv
+                    tokens = lexer.get_until_no_white([LColon, LEndL])
+                    if not isinstance(tokens[-1], LColon):
+                        raise ParserError("Expected \":\" after the"
+                                          " condition", lexer.line,
+                                          lexer.filename, lexer.linenum)
+                    cfilter = parse_filter(lexer, [token] + tokens[:-1] +
+                                           [LEndL()])
+                    next_line = lexer.rest_line_as_LString()
+                    if next_line != "":
+                        lexer.reader.set_next_line(next_line, indent + 1,
+                                                   lexer.linenum)
+                    cond = Condition(cfilter, lexer.line)
+                    linenum = lexer.linenum
                     self._parse(lexer, cond, prev_indent=indent)
                     lexer.set_prev_indent(prev_indent)
 
                     pre_dict = apply_predict(lexer, node, pre_dict)
-                    node.content += [(lexer.filename, lexer.linenum, cond)]
+                    node.content += [(lexer.filename, linenum, cond)]
                 else:
                     raise ParserError("Syntax ERROR expected", lexer.line,
                                       lexer.filename, lexer.linenum)
@@ -1043,6 +2236,83 @@ class Parser(object):
             self._debug("%s  %s:  %s" % (lexer.filename, lexer.linenum,
                                          lexer.line))
             raise
+        finally:
+            self._depth -= 1
+            self._key_prefix = key_prefix
+
+    def _inline_condition(self, lexer, op_type, name, match):
+        """
+        Turn "name = a if filter else b" into the content of a "filter:"
+        conditional block setting a and of its negation setting b.
+
+        :param match: _inline_cond_exp match of the value, the else value
+                      can be a conditional value again.
+        :return: Content of the node declaring the value.
+        """
+        text = match.group(2)
+        flexer = Lexer(StrReader(text))
+        try:
+            tokens = [t for t in flexer.tokenize()
+                      if not isinstance(t, (LIndent, LEndL))]
+            lfilter = parse_filter(flexer, tokens)
+        except ParserError as details:
+            raise ParserError("Invalid condition %s: %s" %
+                              (text, details.msg), lexer.line,
+                              lexer.filename, lexer.linenum)
+        location = (lexer.filename, lexer.linenum)
+        cond = Condition(lfilter, text)
+        op = op_type()
+        _set_operands(lexer, op, name, match.group(1))
+        cond.content += [location + (op,)]
+        not_cond = NegativeCondition(lfilter, "!" + text)
+        inline = _inline_cond_exp.match(match.group(3))
+        if inline:
+            not_cond.content += self._inline_condition(lexer, op_type, name,
+                                                       inline)
+        else:
+            op = op_type()
+            _set_operands(lexer, op, name, match.group(3))
+            not_cond.content += [location + (op,)]
+        return [location + (cond,), location + (not_cond,)]
+
+    def _template(self, lexer, tokens, indent):
+        """
+        Store the body of a "variants @template:" block.
+
+        The body is kept as text and parsed at every reference since the
+        variants depend on the statements preceding them.  A template can
+        only refer to the templates defined before it and can't be
+        redefined, so the references can't be recursive.
+        """
+        if (len(tokens) != 2 or not isinstance(tokens[1], LIdentifier) or
+                not isinstance(next_nw(lexer.generator), LColon)):
+            raise ParserError("Syntax ERROR expected \"variants @name:\"",
+                              lexer.line, lexer.filename, lexer.linenum)
+        lexer.get_next_check_nw([LEndL])
+        name = str(tokens[1])
+        if name in self._templates:
+            raise ParserError("Variants template %s already defined" % name,
+                              lexer.line, lexer.filename, lexer.linenum)
+        body = []
+        line, line_indent, linenum = lexer.reader.get_next_line(indent)
+        while line is not None:
+            match = _template_ref_exp.match(line)
+            if match and match.group(2) == name:
+                raise ParserError("Recursive reference of the variants"
+                                  " template %s" % name, line,
+                                  lexer.filename, linenum)
+            if match and match.group(2) not in self._templates:
+                raise ParserError("Unknown variants template %s" %
+                                  match.group(2), line, lexer.filename,
+                                  linenum)
+            body.append((line, line_indent))
+            line, line_indent, linenum = lexer.reader.get_next_line(indent)
+        if not body:
+            raise ParserError("'variants' template without any variant",
+                              lexer.line, lexer.filename, lexer.linenum)
+        base = min(line_indent for _, line_indent in body)
+        self._templates[name] = [(line, line_indent - base)
+                                 for line, line_indent in body]
 
     def get_dicts(self, node=None, ctx=[], content=[], shortname=[], dep=[], skipdups=True):
         """
@@ -1066,15 +2336,18 @@ class Parser(object):
             Transforms into:
                 join a a
         """
-        node = node or self.node
+        return self._get_dicts(node, ctx, content, shortname, dep, skipdups,
+                               parent=True)
 
-        # Keep track to know who is a parent generator
-        parent = False
-        if self.parent_generator:
-            # I am parent of the all
-            parent = True
-            # No one else is
-            self.parent_generator = False
+    def _get_dicts(self, node, ctx, content, shortname, dep, skipdups=True,
+                   parent=False, limited=True):
+        """
+        Worker of :meth:`get_dicts`, parent is set for the top level call
+        only, which flattens the suffixes and applies the exclusions.
+
+        :param limited: Whether the top level call checks max_combinations.
+        """
+        node = node or self.node
 
         # Node is a current block. It has content, its contents: node.content
         # Content without joins
@@ -1093,10 +2366,13 @@ class Parser(object):
             # Accumulate all joins at one node
             joins += [t]
 
+        if parent and limited and self.max_combinations is not None:
+            self._check_combinations()
         if not joins:
             # Return generator
-            for d in self.get_dicts_plain(node, ctx, content, shortname, dep):
-                yield drop_suffixes(d, skipdups=skipdups) if parent else d
+            dicts = self.get_dicts_plain(node, ctx, content, shortname, dep)
+            for d in self._finish_dicts(dicts, parent, skipdups):
+                yield d
         else:
             # Rewrite all separate joins in one node as many `only'
             onlys = []
@@ -1106,57 +2382,616 @@ class Parser(object):
                     f = OnlyFilter([word], str(word))
                     onlys += [(filename, linenum, f)]
 
-            old_content = node.content[:]
-            node.content = new_content
-            for d in self.multiply_join(onlys, node, ctx, content, shortname, dep):
-                yield drop_suffixes(d, skipdups=skipdups) if parent else d
-            node.content = old_content[:]
+            # The tree is shared by the expansions running at the same
+            # time, the content without the joins is passed along instead
+            # of replacing the one of the node.
+            dicts = self.multiply_join(onlys, node, ctx, content, shortname,
+                                       dep, new_content)
+            for d in self._finish_dicts(dicts, parent, skipdups):
+                yield d
+
+    def _finish_dicts(self, dicts, parent, skipdups):
+        """
+        Flatten suffixes and drop excluded dicts at the top level only.
+        """
+        if not parent:
+            return dicts
+        dicts = (_drop_suffixes(d, skipdups) for d in dicts)
+        if self.exclusions:
+            dicts = (d for d in dicts if not self._excluded(d))
+        if self.dedup_dicts:
+            dicts = _unique_dicts(dicts)
+        return self._end_expansion(dicts)
+
+    def _end_expansion(self, dicts):
+        """
+        Drop the caches of the thread once the expansion is over, a long
+        running thread would keep the nodes of every parsed tree otherwise.
+        """
+        try:
+            for d in dicts:
+                yield d
+        finally:
+            self._local.failed_cases = {}
+            clear_saved_keys()
+
+    def _check_combinations(self):
+        """
+        Error out on a configuration exceeding max_combinations before
+        generating any dictionary.
+
+        The count of a configuration depending on the filters (see count())
+        is only known by expanding it, up to the dictionary past the limit.
+        """
+        count = self._tree_count()
+        if count is None:
+            dicts = self._get_dicts(None, [], [], [], [], parent=True,
+                                    limited=False)
+            try:
+                count = sum(1 for _ in itertools.islice(
+                    dicts, self.max_combinations + 1))
+            finally:
+                dicts.close()
+            if count > self.max_combinations:
+                raise ParserError("The configuration expands to more than %d"
+                                  " combinations" % self.max_combinations)
+        elif count > self.max_combinations:
+            raise ParserError("The configuration expands to %d combinations,"
+                              " more than the limit of %d" %
+                              (count, self.max_combinations))
+
+    def _excluded(self, d):
+        for key, value, regexp in self.exclusions:
+            if key not in d:
+                continue
+            if regexp is None:
+                if d[key] == value:
+                    return True
+            elif regexp.fullmatch(str(d[key])):
+                return True
+        return False
+
+    def exclude_by(self, key, value):
+        """
+        Drop the dicts whose key equals value once fully expanded.
+
+        The value is compared after substitutions and suffix handling, dicts
+        without the key are kept.
+
+        :param key: Key of the expanded dicts.
+        :param value: Excluded value.
+        """
+        self._expansion.clear()
+        self.exclusions.append((key, value, None))
+
+    def exclude_by_regexp(self, key, pattern):
+        """
+        Drop the dicts whose key fully matches a regular expression.
+
+        :param key: Key of the expanded dicts.
+        :param pattern: Python regular expression matching excluded values.
+        """
+        self._expansion.clear()
+        self.exclusions.append((key, pattern, re.compile(pattern)))
+
+    def with_defaults(self, values):
+        """
+        Seed keys as if they were set at the top of the configuration.
+
+        Every assignment of the configuration overrides them and they can
+        be substituted like the keys it sets.
+
+        :param values: Mapping of the keys to their string values.
+        :return: The parser itself.
+        :raise ValueError: For a key set by the parser.
+        """
+        for key in values:
+            if is_reserved_key(key):
+                raise ValueError("Default of the reserved key %s" % key)
+        self._expansion.clear()
+        self.key_defaults.update(values)
+        return self
+
+    def get_dicts_filtered(self, only=(), no=(), skipdups=True):
+        """
+        Filter the dicts without expanding the configuration again.
+
+        The configuration is expanded by the first call only, the following
+        ones filter the stored dicts until something more is parsed or
+        excluded.  See :func:`filter_dicts` for the filters.
+
+        :param only: Filters every dict has to match.
+        :param no: Filters no dict may match.
+        :return: List of copies of the matching dicts.
+        """
+        if skipdups not in self._expansion:
+            self._expansion[skipdups] = list(self.get_dicts(
+                skipdups=skipdups))
+        return [dict(d) for d in filter_dicts(self._expansion[skipdups], only,
+                                              no, self.name_separator)]
+
+    def get_dicts_parallel(self, processes=None, skipdups=True):
+        """
+        Expand the parsed configuration using a pool of worker processes.
+
+        The variants of the last top-level block are split into contiguous
+        chunks and every chunk is expanded and filtered by its own process.
+        The chunks are concatenated in order, so the result is the same list
+        get_dicts() would generate.  Configurations whose top level cannot be
+        split (joins, default-only expansion or a single variant) are
+        expanded serially.
+
+        :param processes: Number of worker processes, the number of CPUs by
+                          default.
+        :return: List of dictionaries.
+        """
+        node = self.node
+        joins = [obj for _, _, obj in node.content
+                 if isinstance(obj, JoinFilter)]
+        processes = processes or os.cpu_count() or 1
+        if joins or self.defaults or len(node.children) < 2 or processes < 2:
+            return list(self.get_dicts(skipdups=skipdups))
+
+        size = -(-len(node.children) // processes)
+        chunks = []
+        for start in range(0, len(node.children), size):
+            chunk = copy.copy(node)
+            chunk.children = node.children[start:start + size]
+            chunks.append(chunk)
+        if self.max_combinations is not None:
+            self._check_combinations()
+        dicts = []
+        with concurrent.futures.ProcessPoolExecutor(len(chunks)) as executor:
+            for result in executor.map(_expand_node, [self] * len(chunks),
+                                       chunks, [skipdups] * len(chunks)):
+                dicts += result
+        if self.dedup_dicts:
+            dicts = _unique_dicts(dicts)
+        return list(dicts)
+
+    def count(self):
+        """
+        Count the dictionaries the parsed configuration expands to.
+
+        Without any filter (only, no, join, conditional blocks or
+        exclusions) nor dedup_dicts the count is the product of the variant
+        block sizes and is computed from the tree without generating a
+        single dictionary.  Otherwise whether a combination survives depends
+        on its full context, so the dictionaries are enumerated and discarded
+        one by one.
+
+        :return: Number of dictionaries get_dicts() would generate.
+        """
+        count = self._tree_count()
+        if count is None:
+            count = sum(1 for _ in self.get_dicts())
+        return count
+
+    def _tree_count(self):
+        """
+        :return: Number of dictionaries of a configuration without filters,
+                 None if it has some.
+        """
+        counts = {}
+
+        def count_leaves(node):
+            if id(node) not in counts:
+                for _, _, obj in node.content:
+                    if not isinstance(obj, LOperators):
+                        return None
+                count = 0 if node.children else 1
+                for child in node.children:
+                    child_count = count_leaves(child)
+                    if child_count is None:
+                        return None
+                    count += child_count
+                counts[id(node)] = count
+            return counts[id(node)]
+
+        if self.defaults or self.exclusions or self.dedup_dicts:
+            # which dictionaries are duplicates depends on their values
+            return None
+        return count_leaves(self.node)
+
+    def iter_dicts(self, progress=None, every=1000, skipdups=True):
+        """
+        Generate the dictionaries reporting the progress of the expansion.
+
+        The callback is invoked as progress(done, total) after every
+        ``every`` dictionaries and once more after the last one.  The total
+        comes from count(), so filtered configurations are expanded twice.
+
+        :param progress: Callback or None to just generate the dictionaries.
+        :param every: Number of dictionaries between two callback calls.
+        :return: Generator of the dictionaries, same as get_dicts().
+        """
+        if progress is None:
+            for d in self.get_dicts(skipdups=skipdups):
+                yield d
+            return
+        if every < 1:
+            raise ValueError("The progress interval must be positive, got %s"
+                             % every)
+        total = self.count()
+        done = 0
+        for d in self.get_dicts(skipdups=skipdups):
+            yield d
+            done += 1
+            if done % every == 0:
+                progress(done, total)
+        if done % every:
+            progress(done, total)
+
+    def variant_names(self):
+        """
+        Describe the variants blocks without expanding them.
+
+        Every block is a dict holding its name ("variants name:" or None)
+        and its "variants", every variant is a dict holding its name and the
+        "blocks" nested in it, e.g.::
+
+            [{"name": None,
+              "variants": [{"name": "a", "blocks": []},
+                           {"name": "b", "blocks": []}]}]
+
+        :return: List of the top-level blocks in declaration order.
+        """
+        return _variant_blocks(self.node, None)
+
+    def lint_unreachable(self, skipdups=True):
+        """
+        Find the variants no combination selects.
+
+        A variant is unreachable when the filters drop every combination it
+        takes part in, e.g. a "rhel" variant next to an "only fedora".  The
+        variants are matched by their name path against the names of the
+        expanded dictionaries as an "only" filter placed after all blocks
+        would, a variant named like a reachable variant of another block
+        at the same place in the path is therefore not reported.
+
+        :return: Name paths of the unreachable variants in declaration
+                 order, e.g. ["linux.rhel"].
+        """
+        paths = _variant_paths(self.variant_names())
+        if not paths:
+            return []
+        filters = _parse_filters(paths, OnlyFilter)
+        reached = set()
+        for d in self.get_dicts(skipdups=skipdups):
+            reached.update(_matched_filters(d, filters, self.name_separator))
+            if len(reached) == len(paths):
+                break
+        return [path for i, path in enumerate(paths) if i not in reached]
+
+    def dependency_graph(self):
+        """
+        Describe how the variants blocks relate to each other.
+
+        The blocks are the nodes of the graph, named after "variants name:"
+        or after their variants joined by "|" for the unnamed ones.  The
+        edges link the blocks whose variants are merged by a join ("join"),
+        combined in a filter term ("only", "no" and "condition") or required
+        by a variant ("dep").
+
+        :return: DependencyGraph of the blocks.
+        """
+        return _dependency_graph(self)
+
+    def subset(self, n, seed=0, skipdups=True):
+        """
+        Pick a deterministic sample of the dictionaries.
+
+        The sequence of get_dicts() is split into n equal ranges and one
+        dictionary is taken from every range, at an offset derived from the
+        seed by SHA-256, so the sample covers the whole space and the same
+        seed gives the same sample everywhere.
+
+        :param n: Number of dictionaries, all of them if there are fewer.
+        :param seed: Integer or string selecting the sample.
+        :return: List of the chosen dictionaries in the get_dicts() order.
+        """
+        total = self.count()
+        n = min(max(n, 0), total)
+        chosen = set()
+        for i in range(n):
+            start = i * total // n
+            size = (i + 1) * total // n - start
+            digest = hashlib.sha256(("%s:%d" % (seed, i)).encode()).digest()
+            chosen.add(start + int.from_bytes(digest[:8], "big") % size)
+        return [d for i, d in enumerate(self.get_dicts(skipdups=skipdups))
+                if i in chosen]
+
+    def sample_paths(self, skipdups=True):
+        """
+        Pick dictionaries that together take every variant at least once.
+
+        This is a greedy covering: the dictionary taking the most variants
+        not taken yet is picked until every reachable variant is, which is
+        usually far fewer than all the combinations but not the smallest
+        possible set.  The variants are matched as in lint_unreachable(),
+        the unreachable ones stay uncovered.
+
+        :return: List of the chosen dictionaries in the get_dicts() order.
+        """
+        filters = _parse_filters(_variant_paths(self.variant_names()),
+                                 OnlyFilter)
+        candidates = [(d, _matched_filters(d, filters, self.name_separator))
+                      for d in self.get_dicts(skipdups=skipdups)]
+        if candidates and not filters:
+            # no variants, the single dictionary covers the configuration
+            return [candidates[0][0]]
+        covered = set()
+        chosen = set()
+        while True:
+            best, gain = None, 0
+            for i, (_, matched) in enumerate(candidates):
+                if len(matched - covered) > gain:
+                    best, gain = i, len(matched - covered)
+            if best is None:
+                break
+            chosen.add(best)
+            covered.update(candidates[best][1])
+        return [d for i, (d, _) in enumerate(candidates) if i in chosen]
+
+    def get_dicts_pinned(self, pins, skipdups=True):
+        """
+        Expand the configuration with some named blocks fixed to one variant.
+
+        Equivalent to an "only (block=variant)" filter for every pin, the
+        other blocks are expanded as usual.
+
+        :param pins: Dict mapping the names of "variants name:" blocks to the
+                     name of the variant to keep.
+        :return: Generator of the dictionaries, same as get_dicts().
+        :raise ValueError: If a block or a variant doesn't exist.
+        """
+        known = {}
+
+        def collect(blocks):
+            for block in blocks:
+                variants = known.setdefault(block["name"], set())
+                for variant in block["variants"]:
+                    variants.add(variant["name"])
+                    collect(variant["blocks"])
+        collect(self.variant_names())
+
+        content = []
+        for block, variant in pins.items():
+            if block is None or block not in known:
+                raise ValueError("No variants block named %s" % block)
+            if variant not in known[block]:
+                raise ValueError("No variant %s in the variants block %s" %
+                                 (variant, block))
+            label = Label(block, variant)
+            content.append((self.filename or "<pins>", None,
+                            OnlyFilter([[[label]]], str(label))))
+        return self.get_dicts(content=content, skipdups=skipdups)
+
+    def get_dicts_provenance(self, skipdups=True):
+        """
+        Generate the dictionaries along with the origin of their values.
+
+        :return: Generator of (dict, provenance) tuples, provenance maps the
+                 keys assigned by the configuration to the Provenance of the
+                 assignment their value comes from.
+        """
+        self._origins = {}
+        try:
+            for d in self.get_dicts(skipdups=skipdups):
+                provenance = d.pop(_provenance_key)
+                yield d, provenance
+        finally:
+            self._origins = None
+
+    def explain(self, name):
+        """
+        Describe how a single combination is built.
+
+        The trace lists in order the filter decisions taken on the way to
+        the combination, including the conditional blocks skipped and why,
+        then every operator applied to its dictionary with the variant
+        declaring it and the values it changed, and last the
+        substitutions.  The combinations merged by a join only show the
+        filter decisions.
+
+        :param name: Name of the combination as in its "name" key.
+        :return: List of the lines of the trace.
+        :raise ValueError: If no dictionary has this name.
+        """
+        trace = _Trace(name, name_labels(name, self.name_separator))
+        content = []
+        if trace.ctx:
+            content.append(("<explain>", None, trace.filter))
+        self._origins = {}
+        self._trace = trace
+        try:
+            found = any(d["name"] == name for d in
+                        self.get_dicts(content=content, skipdups=False))
+        finally:
+            self._origins = None
+            self._trace = None
+        if not found:
+            raise ValueError("No combination named %s" % name)
+        return trace.lines
+
+    def keys(self):
+        """
+        Collect the keys any of the dictionaries may hold.
+
+        Every assignment of the tree counts, including those in variants
+        and conditional blocks that no combination may reach, so this is a
+        superset of the keys of get_dicts().  The keys renamed by a suffix
+        are reported by their original name.
+
+        :return: Sorted list of the keys.
+        """
+        keys = set(reserved_keys)
+        _node_keys(self, self.node, keys, set())
+        return sorted(keys)
+
+    def get_default_dict(self, skipdups=True):
+        """
+        Get the combination formed by the default variant of every block.
+
+        Blocks without a variant marked by "@" or [default=xxx] fall back to
+        their first variant.
+
+        :return: The default dictionary or None if filters drop it.
+        """
+        defaults = self.defaults
+        self.defaults = True
+        try:
+            dicts = self.get_dicts(skipdups=skipdups)
+            for d in dicts:
+                dicts.close()
+                return d
+            return None
+        finally:
+            self.defaults = defaults
+
+    def to_json(self, indent=None, skipdups=True):
+        """
+        Expand the parsed configuration and serialize it to JSON.
+
+        :param indent: Indentation passed to the JSON encoder.
+        :return: JSON list of dictionaries with sorted keys.
+        """
+        return dicts_to_json(self.get_dicts(skipdups=skipdups), indent)
+
+    @classmethod
+    def from_json(cls, s, **kwargs):
+        """
+        Rebuild a configuration from the dicts exported by :meth:`to_json`.
+
+        Every dict becomes a variant of a single block, labelled after its
+        name, so the dicts can be filtered again without the original
+        configuration.
+
+        :param s: JSON list of dictionaries.
+        :param kwargs: Options passed to :class:`Parser`.
+        :return: Parser generating the same dictionaries.
+        :raise ValueError: If s is not a non-empty list of exported
+                            dictionaries.
+        """
+        parser = cls(**kwargs)
+        block = Node()
+        dicts = json.loads(s)
+        if not isinstance(dicts, list) or not dicts:
+            # an empty block would still generate one empty dictionary
+            raise ValueError("Not a list of dictionaries: %r" % s[:40])
+        for d in dicts:
+            if not isinstance(d, dict) or not isinstance(d.get("name"), str):
+                raise ValueError("Not an exported dictionary: %r" % (d,))
+            variant = Node()
+            variant.name = name_labels(d["name"], parser.name_separator)
+            variant.append_to_shortname = True
+            # sets the name, shortname and dep keys as well
+            op = LApplyPreDict().set_operands(None, d)
+            variant.content += [("<json>", -1, op)]
+            block.children += [variant]
+            block.labels.update(variant.name)
+        parser.node = block
+        return parser
+
+    def write_jsonl(self, path_or_file, skipdups=True):
+        """
+        Expand the parsed configuration straight to a JSON Lines file.
+
+        :param path_or_file: Path of the output file or an object with
+                             write().
+        :return: Number of written dictionaries.
+        """
+        return write_dicts_jsonl(self.get_dicts(skipdups=skipdups),
+                                 path_or_file)
+
+    def to_yaml(self, skipdups=True):
+        """
+        Expand the parsed configuration and serialize it to YAML.
+
+        :return: YAML list of dictionaries with sorted keys.
+        """
+        return dicts_to_yaml(self.get_dicts(skipdups=skipdups))
+
+    def matrix(self, skipdups=True):
+        """
+        Expand the parsed configuration to a table of its combinations.
+
+        The rows can be passed as they are to csv.writer().writerows().
+
+        :return: List of rows, the sorted keys of keys() and of the
+                 dictionaries first, then a row of values per dictionary
+                 with "" for the keys it doesn't set.  The name map keys
+                 holding mappings are left out and the names of dep are
+                 joined by spaces.
+        """
+        dicts = list(self.get_dicts(skipdups=skipdups))
+        columns = set(self.keys())
+        for d in dicts:
+            columns.update(d)
+            d["dep"] = " ".join(d["dep"])
+        columns = sorted(columns - {"_name_map_file", "_short_name_map_file"})
+        return [columns] + [[d.get(key, "") for key in columns]
+                            for d in dicts]
 
     def mk_name(self, n1, n2):
         """Make name for test. Case: two dics were merged"""
+        sep = self.name_separator
         common_prefix = n1[:[x[0] == x[1] for x in list(zip(n1, n2))].index(0)]
-        cp = ".".join(common_prefix.split('.')[:-1])
-        p1 = re.sub(r"^"+cp, "", n1)
-        p2 = re.sub(r"^"+cp, "", n2)
+        cp = sep.join(common_prefix.split(sep)[:-1])
+        p1 = re.sub(r"^" + re.escape(cp), "", n1)
+        p2 = re.sub(r"^" + re.escape(cp), "", n2)
         if cp:
             name = cp + p1 + p2
         else:
-            name = p1 + "." + p2
+            name = p1 + sep + p2
         return name
 
-    def multiply_join(self, onlys, node=None, ctx=[], content=[], shortname=[], dep=[]):
+    def multiply_join(self, onlys, node=None, ctx=[], content=[], shortname=[], dep=[],
+                      node_content=None):
         """
         Multiply all joins. Return dictionaries one by one
         Each `join' is the same as `only' filter
         This functions is supposed to be a generator, recursive generator
+
+        :param node_content: Statements of node to use instead of its
+                             content, i.e. without the joins.
         """
+        node = node or self.node
+        if node_content is None:
+            node_content = node.content
         # Current join/only
         only = onlys[:1]
         remains = onlys[1:]
 
-        content_orig = node.content[:]
-        node.content += only
-
         if not remains:
-            for d in self.get_dicts_plain(node, ctx, content, shortname, dep):
+            for d in self.get_dicts_plain(node, ctx, content, shortname, dep,
+                                          node_content + only):
                 yield d
         else:
-            for d1 in self.get_dicts_plain(node, ctx, content, shortname, dep):
+            for d1 in self.get_dicts_plain(node, ctx, content, shortname, dep,
+                                           node_content + only):
                 # Current frame multiply by all variants from bottom
-                node.content = content_orig
-                for d2 in self.multiply_join(remains, node, ctx, content, shortname, dep):
+                for d2 in self.multiply_join(remains, node, ctx, content,
+                                             shortname, dep, node_content):
 
                     d = d1.copy()
                     d.update(d2)
+                    if _provenance_key in d:
+                        provenance = d1[_provenance_key].copy()
+                        provenance.update(d2[_provenance_key])
+                        d[_provenance_key] = provenance
                     d["name"] = self.mk_name(d1["name"], d2["name"])
                     d["shortname"] = self.mk_name(d1["shortname"], d2["shortname"])
                     yield d
 
-    def get_dicts_plain(self, node=None, ctx=[], content=[], shortname=[], dep=[]):
+    def get_dicts_plain(self, node=None, ctx=[], content=[], shortname=[], dep=[],
+                        node_content=None):
         """
         Generate dictionaries from the code parsed so far.  This should
         be called after parsing something.
 
+        :param node_content: Statements of node to use instead of its
+                             content, see multiply_join().
         :return: A dict generator.
         """
         def process_content(content, failed_filters):
@@ -1174,7 +3009,8 @@ class Parser(object):
             blocked_filters = []
             for t in content:
                 filename, linenum, obj = t
-                if isinstance(obj, LOperators):
+                if isinstance(obj, (LOperators, LazyInclude)):
+                    # lazy includes are loaded once the filters passed
                     new_content.append(t)
                     continue
                 # obj is an OnlyFilter/NoFilter/Condition/NegativeCondition
@@ -1191,6 +3027,8 @@ class Parser(object):
                     else:
                         self._debug("    conditional block matches:"
                                     " %r (%s:%s)", obj.line, filename, linenum)
+                        if on_path:
+                            trace.resolved(t, True)
                         # Check and unpack the content inside this Condition
                         # object (note: the failed filters should go into
                         # new_internal_filters because we don't expect them to
@@ -1203,6 +3041,8 @@ class Parser(object):
                         continue
                 elif obj.is_irrelevant(ctx, ctx_set, labels):
                     # This filter is no longer relevant and can be removed
+                    if on_path:
+                        trace.resolved(t, False)
                     continue
                 else:
                     # Keep the filter and check it again later
@@ -1213,7 +3053,7 @@ class Parser(object):
                        failed_ctx_set,
                        failed_external_filters,
                        failed_internal_filters):
-            all_content = content + node.content
+            all_content = content + node_content
             for t in failed_external_filters + failed_internal_filters:
                 if t not in all_content:
                     return True
@@ -1225,7 +3065,7 @@ class Parser(object):
                                                   labels):
                     return False
             for t in failed_internal_filters:
-                if t not in node.content:
+                if t not in node_content:
                     return True
 
             for t in failed_internal_filters:
@@ -1237,50 +3077,92 @@ class Parser(object):
                     return False
             return True
 
+        def rejected(child):
+            # An only/no filter failing right in the child, its subtree is
+            # skipped without entering it
+            child_ctx = ctx + child.name
+            child_ctx_set = set(child_ctx)
+            for _, _, obj in new_content:
+                if (type(obj) in (OnlyFilter, NoFilter) and
+                        obj.requires_action(child_ctx, child_ctx_set,
+                                            child.labels)):
+                    return True
+            return False
+
         def add_failed_case():
-            node.failed_cases.appendleft((ctx, ctx_set,
-                                          new_external_filters,
-                                          new_internal_filters))
-            if len(node.failed_cases) > num_failed_cases:
-                node.failed_cases.pop()
+            failed_cases.appendleft((ctx, ctx_set,
+                                     new_external_filters,
+                                     new_internal_filters))
+            if len(failed_cases) > num_failed_cases:
+                failed_cases.pop()
 
         node = node or self.node
+        if node_content is None:
+            node_content = node.content
         # if self.debug:    #Print dict on which is working now.
         #    print(node.dump(0))
         # Update dep
         for d in node.dep:
             for dd in d:
-                dep = dep + [".".join([str(label) for label in ctx + dd])]
+                dep = dep + [self.name_separator.join([str(label)
+                                                       for label in ctx + dd])]
         # Update ctx
         ctx = ctx + node.name
+        if len(ctx) > self.max_depth:
+            raise ParserError("Maximum nesting depth %d exceeded when"
+                              " expanding the variants" % self.max_depth)
         ctx_set = set(ctx)
         labels = node.labels
-        # Get the current name
-        name = ".".join([str(label) for label in ctx])
+        # Get the current name, it's only needed by the leaves and the debug
+        # messages
+        name = None
+        if self.debug or not node.children:
+            name = self.name_separator.join([str(label) for label in ctx])
 
         if node.name:
             self._debug("checking out %r", name)
+        # read once, the origins and the trace are thread-local properties
+        origins = self._origins
+        if origins is not None:
+            _tag_origins(origins, node_content, node.scope)
+        trace = self._trace
+        on_path = trace is not None and trace.on_path(ctx)
 
         # Check previously failed filters
-        for i, failed_case in enumerate(node.failed_cases):
+        failed_cases = self._failed_cases(node)
+        for i, failed_case in enumerate(failed_cases):
             if not might_pass(*failed_case):
                 self._debug("\n*    this subtree has failed before %s\n"
                             "         content: %s\n"
                             "         failcase:%s\n",
-                            name, content + node.content, failed_case)
-                del node.failed_cases[i]
-                node.failed_cases.appendleft(failed_case)
+                            name, content + node_content, failed_case)
+                del failed_cases[i]
+                failed_cases.appendleft(failed_case)
                 return
 
         # Check content and unpack it into new_content
         new_content = []
         new_external_filters = []
         new_internal_filters = []
-        if (not process_content(node.content, new_internal_filters) or
+        if (not process_content(node_content, new_internal_filters) or
                 not process_content(content, new_external_filters)):
             add_failed_case()
-            self._debug("Failed_cases %s", node.failed_cases)
+            self._debug("Failed_cases %s", failed_cases)
             return
+        while any(isinstance(obj, LazyInclude) for _, _, obj in new_content):
+            pending = new_content
+            new_content = []
+            for t in pending:
+                if not isinstance(t[2], LazyInclude):
+                    new_content.append(t)
+                    continue
+                if origins is not None:
+                    _tag_origins(origins, t[2].load(self),
+                                 origins.get(id(t), ""))
+                if not process_content(t[2].load(self),
+                                       new_internal_filters):
+                    add_failed_case()
+                    return
 
         # Update shortname
         if node.append_to_shortname:
@@ -1289,28 +3171,1245 @@ class Parser(object):
         # Recurse into children
         count = 0
         if self.defaults and node.var_name not in self.expand_defaults:
-            for n in node.children:
-                for d in self.get_dicts(n, ctx, new_content, shortname, dep):
+            # Defaults are already in front if they were known at parse time
+            for n in sorted(node.children, key=lambda n: not n.default):
+                if rejected(n):
+                    continue
+                for d in self._get_dicts(n, ctx, new_content, shortname,
+                                         dep):
                     count += 1
                     yield d
                 if n.default and count:
                     break
         else:
             for n in node.children:
-                for d in self.get_dicts(n, ctx, new_content, shortname, dep):
+                if rejected(n):
+                    continue
+                for d in self._get_dicts(n, ctx, new_content, shortname,
+                                         dep):
                     count += 1
                     yield d
         # Reached leaf?
         if not node.children:
             self._debug("    reached leaf, returning it")
-            d = {"name": name, "dep": dep,
-                 "shortname": ".".join([str(sn.name) for sn in shortname])}
-            for _, _, op in new_content:
-                op.apply_to_dict(d)
+            d = dict(self.key_defaults)
+            d.update(name=name, dep=dep,
+                     shortname=self.name_separator.join([str(sn.name)
+                                                         for sn in shortname]))
+            try:
+                if origins is None:
+                    for _, _, op in new_content:
+                        op.apply_to_dict(d)
+                elif on_path and name == trace.name:
+                    provenance = trace.apply(d, new_content, origins)
+                else:
+                    provenance = _apply_tracked(d, new_content, origins)
+            finally:
+                # a scope is left open by an error or by a suffix filtered
+                # out of the content
+                end_suffix_scopes()
+            if on_path and name == trace.name:
+                raw = d.copy()
+                substitute_dict(d, self.strict_substitution)
+                trace.substituted(raw, d)
+            else:
+                substitute_dict(d, self.strict_substitution)
             postfix_parse(d)
+            if origins is not None:
+                d[_provenance_key] = provenance
+            yield d
+
+
+class ConfigBuilder(object):
+
+    """
+    Assemble a configuration tree in code instead of parsing text.
+
+    Statements are recorded in order and turned into the same tree the
+    parser would produce for the equivalent text::
+
+        body = ConfigBuilder().set("y", "2")
+        builder = ConfigBuilder().set("x", "1")
+        builder.add_variants("tests", ["a", ("b", body)])
+        parser = builder.build()
+    """
+
+    def __init__(self, filename="<string>"):
+        """
+        :param filename: Filename reported for the built statements.
+        """
+        self.filename = filename
+        self.steps = []
+
+    def set(self, key, value):
+        """
+        Assign a value, "key = value" in the text format.
+
+        :return: The builder itself.
+        """
+        self.steps.append((self._set, (key, value)))
+        return self
+
+    def add_variants(self, name, children):
+        """
+        Add a variants block.
+
+        :param name: Name of the block as in "variants name:" or None.
+        :param children: Variant names or (variant name, ConfigBuilder)
+                         tuples whose builder assembles the variant body.
+        :return: The builder itself.
+        """
+        self.steps.append((self._add_variants, (name, children)))
+        return self
+
+    def build(self, **kwargs):
+        """
+        :param kwargs: Options passed to :class:`Parser`.
+        :return: Parser holding the assembled tree.
+        """
+        parser = Parser(**kwargs)
+        parser.node = self._build(parser.node)
+        return parser
+
+    def _build(self, node):
+        for step, args in self.steps:
+            node = step(node, *args)
+        return node
+
+    def _set(self, node, key, value):
+        op = LSet().set_operands(key, value)
+        node.content += [(self.filename, -1, op)]
+        return node
+
+    def _add_variants(self, node, name, children):
+        block = Node()
+        for child in children:
+            if isinstance(child, str):
+                variant, body = child, None
+            else:
+                variant, body = child
+            variant_node = Node()
+            variant_node.children = [node]
+            variant_node.labels = node.labels
+            if name:
+                self._set(variant_node, name, variant)
+            if body is not None:
+                variant_node = body._build(variant_node)
+            if name:
+                variant_node.var_name = name
+                variant_node.name = [Label(name, variant)]
+            else:
+                variant_node.name = [Label(variant)]
+            variant_node.append_to_shortname = True
+            for dest, map_name in (("_name_map_file",
+                                    str(variant_node.name[0])),
+                                   ("_short_name_map_file", variant)):
+                op = LUpdateFileMap().set_operands(self.filename, map_name,
+                                                   dest)
+                variant_node.content += [(self.filename, -1, op)]
+            block.children += [variant_node]
+            block.labels.update(variant_node.labels)
+            block.labels.update(variant_node.name)
+        return block
+
+
+#: relation between two variants blocks, see Parser.dependency_graph()
+Edge = collections.namedtuple("Edge", ["source", "target", "kind"])
+
+
+class IncrementalParser(object):
+
+    """
+    Keep a configuration string parsed while it is edited, e.g. in an editor.
+
+    An edit only lexes again the top level statements around the replaced
+    text, the parsing restarts from the last checkpoint before them.  The
+    checkpoints are copies of the tree taken at a top level statement every
+    checkpoint_lines lines and the result is the one of parsing the whole
+    string again::
+
+        incremental = IncrementalParser(text)
+        incremental.edit(start, end, "x = 2")
+        dicts = list(incremental.parser.get_dicts())
+
+    A top level suffix or prefix and strict_duplicates reach past the
+    statements around an edit, the string is parsed from its start after
+    every edit then.
+    """
+
+    def __init__(self, s, filename="<string>", checkpoint_lines=1000,
+                 **kwargs):
+        """
+        :param s: String to parse.
+        :param filename: Name of the string used in the reported errors.
+        :param checkpoint_lines: Reader lines between the checkpoints.
+        :param kwargs: Options passed to :class:`Parser`.
+        """
+        self.parser = Parser(**kwargs)
+        self.filename = filename
+        self.checkpoint_lines = checkpoint_lines
+        self.text = ""
+        # StrReader lines of the text
+        self.lines = []
+        # (indentation tokens, tokens) of the lines and the final dedents
+        self._line_tokens = []
+        self._final_tokens = []
+        # LexerError of lex(text), its tokens are lexed again in full
+        self._token_error = None
+        # lines of the text with their ends
+        self._raw = []
+        # (index of a line, tree, templates) before parsing the line
+        self._checkpoints = [(0, Node(), {})]
+        self.edit(0, 0, s)
+
+    @property
+    def tokens(self):
+        """
+        The (token, Span) pairs of the text as lex(text, spans=True) does.
+
+        :raise LexerError: As lex() on a text the parser still accepts,
+                           e.g. with a dedent between two levels.
+        """
+        if self._token_error is not None:
+            raise self._token_error
+        tokens = []
+        for indent_tokens, line_tokens in self._line_tokens:
+            tokens += indent_tokens + line_tokens
+        return tokens + self._final_tokens
+
+    @staticmethod
+    def _top_levels(lines):
+        """
+        :return: Flags of the lines starting a statement of the top level.
+                 Past a line indented less than the first one, the lines
+                 can be the body of any previous block.
+        """
+        flags = []
+        for line, indent, _ in lines:
+            if indent < lines[0][1]:
+                return flags + [False] * (len(lines) - len(flags))
+            flags.append(indent == lines[0][1] and not line.startswith("-"))
+        return flags
+
+    def edit(self, start, end, replacement):
+        """
+        Replace a part of the text and parse the result.
+
+        :param start: Offset of the first replaced character.
+        :param end: Offset past the last replaced character.
+        :param replacement: New text of the part.
+        :return: Number of the line the parsing restarted from, None if
+                 there is nothing to parse.
+        :raise ParserError: On an invalid result, the next edit parses
+                            again from before the error.
+        """
+        text = self.text[:start] + replacement + self.text[end:]
+        raw = text.splitlines(True)
+        delta = len(raw) - len(self._raw)
+        lines = self.lines
+        linenums = [linenum for _, _, linenum in lines]
+        top_levels = self._top_levels(lines)
+        # statements at the top level before and after the replaced lines
+        begin = bisect.bisect_left(linenums,
+                                   _line_number(self._raw, start)) - 1
+        while begin > 0 and not top_levels[begin]:
+            begin -= 1
+        begin = max(begin, 0)
+        stop = bisect.bisect_right(linenums, _line_number(self._raw, end))
+        while stop < len(lines) and not top_levels[stop]:
+            stop += 1
+        if self._token_error is not None:
+            begin, stop = 0, len(lines)
+        while True:
+            first = lines[begin][2] if begin else 1
+            last = len(raw) + 1
+            if stop < len(lines):
+                last = lines[stop][2] + delta
+            region = raw[first - 1:last - 1]
+            reader = StrReader("".join(region), self.parser.indent_policy,
+                               self.filename)
+            new = [(line, indent, linenum + first - 1)
+                   for line, indent, linenum in reader._lines]
+            if stop == len(lines):
+                break
+            # the statement following the region must stay at the top level
+            trailing = region[-1].rstrip() if region else ""
+            trailing = len(trailing) - len(trailing.rstrip("\\"))
+            if not trailing % 2 and (begin or not new or
+                                     new[0][1] == lines[0][1]):
+                break
+            stop = len(lines)
+
+        following = [(line, indent, linenum + delta)
+                     for line, indent, linenum in lines[stop:]]
+        # the parser doesn't need the tokens, it lexes the lines it parses
+        # and accepts the dedents lex() rejects
+        try:
+            line_tokens, final_tokens = self._lex(reader, new, begin, stop,
+                                                  following, delta)
+            token_error = None
+        except LexerError as details:
+            line_tokens, final_tokens = [], []
+            token_error = details
+
+        self.text = text
+        self._raw = raw
+        self.lines = lines[:begin] + new + following
+        self._line_tokens = line_tokens
+        self._final_tokens = final_tokens
+        self._token_error = token_error
+        # a checkpoint depends on the lines preceding it and on its line
+        # staying at the top level
+        changed = begin
+        while (changed < min(len(lines), len(self.lines)) and
+               lines[changed] == self.lines[changed]):
+            changed += 1
+        top_levels = self._top_levels(self.lines) + [True]
+        self._checkpoints = [checkpoint for checkpoint in self._checkpoints
+                             if checkpoint[0] <= begin or
+                             checkpoint[0] < changed or
+                             checkpoint[0] == changed and top_levels[changed]]
+        return self._parse()
+
+    def _lex(self, reader, new, begin, stop, following, delta):
+        """
+        Lex the new lines of the region between begin and stop.
+
+        :return: Tokens of all the lines and the final dedents.
+        """
+        lexer = Lexer(reader)
+        lexer.normalize = self.parser.normalize_identifiers
+        lexer.set_fast()
+        if begin:
+            lexer.indent_stack = sorted(set([0, self.lines[0][1]]))
+        region_tokens = []
+        for index, line in enumerate(new):
+            indent_tokens, line_tokens = lexer.line_tokens(*line)
+            if begin and not index:
+                # the indentation of the previous lines didn't change
+                indent_tokens = self._line_tokens[begin][0]
+            region_tokens.append((indent_tokens, line_tokens))
+        following_tokens = [(_shift_spans(indent_tokens, delta),
+                             _shift_spans(line_tokens, delta))
+                            for indent_tokens, line_tokens
+                            in self._line_tokens[stop:]]
+        if following:
+            _, indent, linenum = following[0]
+            span = Span(linenum, 1, indent + 1)
+            following_tokens[0] = ([(t, span)
+                                    for t in lexer.indent_tokens(indent)],
+                                   following_tokens[0][1])
+            final_tokens = self._final_tokens
+        else:
+            final_tokens = [(t, None) for t in lexer.dedent_all()]
+        return (self._line_tokens[:begin] + region_tokens + following_tokens,
+                final_tokens)
+
+    def _parse(self):
+        """
+        Parse the lines following the last checkpoint.
+        """
+        parser = self.parser
+        lines = self.lines
+        index, node, templates = self._checkpoints[-1]
+        parser.node = copy.deepcopy(node)
+        parser._templates = copy.deepcopy(templates)
+        parser._expansion.clear()
+        top_levels = self._top_levels(lines)
+        # a suffix of the top level applies to all the previous statements
+        # and a prefix to all the following ones
+        chunked = not parser.strict_duplicates and all(
+            _in_block(lines, index) for index, (line, _, _) in enumerate(lines)
+            if line.startswith(("suffix ", "prefix ")))
+        restart = lines[index][2] if index < len(lines) else None
+        while index < len(lines):
+            stop = len(lines)
+            if chunked:
+                stop = index + self.checkpoint_lines
+                while stop < len(lines) and not top_levels[stop]:
+                    stop += 1
+                stop = min(stop, len(lines))
+            reader = StrReader("", parser.indent_policy, self.filename)
+            reader._lines = lines[index:stop]
+            parser.node.filename = self.filename
+            parser.node = parser._parse(Lexer(reader), parser.node)
+            index = stop
+            if index < len(lines):
+                self._checkpoints.append((index, copy.deepcopy(parser.node),
+                                          copy.deepcopy(parser._templates)))
+        return restart
+
+
+def _in_block(lines, index):
+    """
+    Check whether a StrReader line is in the body of a block for sure, the
+    lines indented more than an assignment are statements of its level.
+    """
+    indent = lines[index][1]
+    for line, line_indent, _ in reversed(lines[:index]):
+        if line_indent < indent:
+            return line.endswith(":") and _assignment_operator(line) is None
+    return False
+
+
+def _line_number(lines, offset):
+    """
+    :param lines: Lines of a text with their ends.
+    :return: Number of the line holding the character at offset, the one
+             past the last line for the end of the text.
+    """
+    pos = 0
+    for linenum, line in enumerate(lines, 1):
+        pos += len(line)
+        if offset < pos:
+            return linenum
+    return len(lines) + 1
+
+
+def _shift_spans(tokens, delta):
+    return [(t, span._replace(linenum=span.linenum + delta))
+            for t, span in tokens]
+
+
+class DependencyGraph(object):
+
+    """
+    Variants blocks and the statements relating them.
+    """
+
+    def __init__(self, nodes, edges):
+        #: names of the blocks in declaration order
+        self.nodes = nodes
+        #: Edge tuples ordered by their blocks
+        self.edges = edges
+
+    def to_edge_list(self):
+        """
+        :return: Text with a "source target kind" line for every edge.
+        """
+        return "".join("%s %s %s\n" % edge for edge in self.edges)
+
+    def to_dot(self):
+        """
+        :return: Graph in the DOT language of graphviz.
+        """
+        lines = ["digraph variants {"]
+        lines += ["    %s;" % _dot_id(node) for node in self.nodes]
+        lines += ["    %s -> %s [label=%s];" % (_dot_id(edge.source),
+                                               _dot_id(edge.target),
+                                               _dot_id(edge.kind))
+                  for edge in self.edges]
+        lines.append("}")
+        return "\n".join(lines) + "\n"
+
+
+def _dot_id(name):
+    return "\"%s\"" % name.replace("\\", "\\\\").replace("\"", "\\\"")
+
+
+class Visitor(object):
+
+    """
+    Walker of a parsed tree for the tools outside of the parser.
+
+    The methods visit the statements below the one they get, a subclass
+    overrides those of the statements it cares about and calls the base
+    method where it still wants to descend.  Start it with :func:`walk`.
+    """
+
+    def __init__(self):
+        # parser loading the included files, set by walk()
+        self.parser = None
+        # ids of the visited nodes
+        self.visited = set()
+
+    def visit_node(self, node):
+        """
+        Visit the statements of a node, then its children.
+
+        The nodes shared by several variants are visited once.
+        """
+        if id(node) in self.visited:
+            return
+        self.visited.add(id(node))
+        self.visit_content(node.content)
+        if node.children and node.children[0].name:
+            self.visit_variants(node)
+        else:
+            for child in node.children:
+                self.visit_node(child)
+
+    def visit_variants(self, node):
+        """
+        Visit a variants block, node.children are its variants.
+        """
+        for child in node.children:
+            self.visit_node(child)
+
+    def visit_content(self, content):
+        for _, _, obj in content:
+            if isinstance(obj, LApplyPreDict):
+                for key, value in obj.value.items():
+                    self.visit_assignment(key, "=", value)
+            elif isinstance(obj, IncludeParams):
+                for key, value in obj.value.items():
+                    self.visit_assignment(key, "=", value)
+            elif isinstance(obj, (LSet, LAppend, LPrepend, LLazySet)):
+                self.visit_assignment(obj.name, obj.identifier, obj.value)
+            elif isinstance(obj, (Condition, NegativeCondition)):
+                self.visit_condition(obj)
+            elif isinstance(obj, (OnlyFilter, NoFilter, JoinFilter)):
+                self.visit_filter(obj)
+            elif isinstance(obj, LazyInclude):
+                self.visit_include(obj)
+            else:
+                self.visit_statement(obj)
+
+    def visit_assignment(self, key, operator, value):
+        """
+        :param operator: "=", "+=", "<=" or "~=".
+        """
+
+    def visit_filter(self, lfilter):
+        """
+        Visit an only, no or join filter.
+        """
+
+    def visit_condition(self, condition):
+        """
+        Visit a conditional block, then the statements in it.
+        """
+        self.visit_content(condition.content)
+
+    def visit_include(self, include):
+        """
+        Visit the statements of an included file, when walk() got a parser.
+        """
+        if self.parser is not None:
+            self.visit_content(include.load(self.parser))
+
+    def visit_statement(self, statement):
+        """
+        Visit any other statement, e.g. a del or a suffix.
+        """
+
+
+def walk(node, visitor):
+    """
+    Walk a parsed tree with a visitor.
+
+    :param node: Node to start from or a Parser to walk all of, only the
+                 latter loads the included files.
+    :param visitor: Visitor instance.
+    :return: The visitor.
+    """
+    visitor.parser = None
+    if isinstance(node, Parser):
+        visitor.parser, node = node, node.node
+    visitor.visited = set()
+    visitor.visit_node(node)
+    return visitor
+
+
+#: labels of a dictionary name, plain or (variable=value), following the
+#: start of the name or a separator of any length
+_name_label_pattern = (r"(?:^|%(sep)s)(?:\(([^=()]+)=([^()]*)\)"
+                       r"|((?:(?!%(sep)s)[^()])+))")
+
+
+def name_labels(name, separator="."):
+    """
+    Recover the variant labels of a dictionary from its name.
+
+    :param name: Value of the "name" key, e.g. "(tests=wait).long".
+    :param separator: The name_separator of the Parser.
+    :return: List of labels in the order of the name.
+    """
+    exp = re.compile(_name_label_pattern % {"sep": re.escape(separator)})
+    return [Label(m.group(1), m.group(2)) if m.group(1)
+            else Label(m.group(3)) for m in exp.finditer(name)]
+
+
+def filter_dicts(dicts, only=(), no=(), separator="."):
+    """
+    Filter already expanded dictionaries.
+
+    The filters have the syntax and the semantics of the "only" and "no"
+    statements placed after all variants blocks, they are matched against
+    the variant names stored in the "name" key.
+
+    :param dicts: Iterable of dictionaries.
+    :param only: Filters every kept dictionary has to match.
+    :param no: Filters no kept dictionary may match.
+    :param separator: The name_separator of the Parser.
+    :return: Generator of the kept dictionaries.
+    """
+    only_filters = _parse_filters(only, OnlyFilter)
+    no_filters = _parse_filters(no, NoFilter)
+    for d in dicts:
+        ctx = name_labels(d["name"], separator)
+        ctx_set = set(ctx)
+        if (all(f.match(ctx, ctx_set) for f in only_filters) and
+                not any(f.match(ctx, ctx_set) for f in no_filters)):
             yield d
 
 
+def _parse_filters(filters, filter_class):
+    """
+    :param filters: Filter strings, e.g. ["a..b", "c"].
+    :return: List of filter_class instances.
+    """
+    parsed = []
+    for text in filters:
+        lexer = Lexer(StrReader(text))
+        tokens = [t for t in lexer.tokenize()
+                  if not isinstance(t, (LIndent, LEndL))]
+        parsed.append(filter_class(parse_filter(lexer, tokens), text))
+    return parsed
+
+
+def _matched_filters(d, filters, separator):
+    """
+    :return: Set of the indexes of the filters the name of d matches.
+    """
+    ctx = name_labels(d["name"], separator)
+    ctx_set = set(ctx)
+    return set(i for i, f in enumerate(filters) if f.match(ctx, ctx_set))
+
+
+def _variant_paths(blocks, prefix=""):
+    """
+    :param blocks: Blocks as described by Parser.variant_names().
+    :return: Name paths of all the variants, e.g. ["linux", "linux.rhel"].
+    """
+    paths = []
+    for block in blocks:
+        for variant in block["variants"]:
+            path = prefix + variant["name"]
+            paths.append(path)
+            paths += _variant_paths(variant["blocks"], path + ".")
+    return paths
+
+
+def diff(config_a, config_b):
+    """
+    Compare the dictionaries generated by two configurations.
+
+    :param config_a: Old Parser or iterable of dictionaries.
+    :param config_b: New Parser or iterable of dictionaries.
+    :return: (added, removed) dicts mapping the names of the dictionaries
+             only generated by config_b, resp. config_a, to them.
+    """
+    def by_name(config):
+        dicts = config.get_dicts() if isinstance(config, Parser) else config
+        return dict((d["name"], d) for d in dicts)
+
+    dicts_a = by_name(config_a)
+    dicts_b = by_name(config_b)
+    added = dict((name, d) for name, d in dicts_b.items()
+                 if name not in dicts_a)
+    removed = dict((name, d) for name, d in dicts_a.items()
+                   if name not in dicts_b)
+    return added, removed
+
+
+#: options of the Parser changing how the statements are parsed
+_parse_options = ("defaults", "expand_defaults", "expand_lists",
+                  "inline_conditions", "lazy_includes", "name_separator",
+                  "normalize_identifiers", "strict_duplicates", "strict_keys",
+                  "strict_substitution")
+
+
+def merge(base, overlay):
+    """
+    Combine two configurations, the overlay statements following the base.
+
+    The statements of overlay are applied on top of the base ones as if its
+    text was appended to the base file: an overlay "=" replaces the base
+    value, the other operators accumulate on it and the overlay variants
+    blocks are multiplied with the base ones.  Neither parser is modified.
+
+    :param base: Parser of the base configuration, also providing the options.
+    :param overlay: Parser of the overlay configuration.
+    :return: New Parser holding the merged tree.
+    :raise ParserError: If the parsers were created with different options
+                        of the parsing.
+    """
+    for option in _parse_options:
+        if getattr(base, option) != getattr(overlay, option):
+            raise ParserError("Cannot merge parsers with different %s"
+                              % option)
+    if (vars(base.indent_policy or IndentPolicy()) !=
+            vars(overlay.indent_policy or IndentPolicy())):
+        raise ParserError("Cannot merge parsers with different indent_policy")
+    merged = copy.deepcopy(base)
+    merged.parse_string(overlay.node.to_config_string())
+    return merged
+
+
+def _unique_dicts(dicts):
+    """
+    Drop the dictionaries whose keys and values equal those of a previous
+    one, the keys set by the parser such as the names aside.
+    """
+    seen = set()
+    for d in dicts:
+        contents = tuple(sorted((key, value) for key, value in d.items()
+                                if not is_reserved_key(key)))
+        if contents not in seen:
+            seen.add(contents)
+            yield d
+
+
+def _drop_suffixes(d, skipdups):
+    """
+    Flatten the suffixes of d and of the keys of its provenance.
+    """
+    provenance = d.pop(_provenance_key, None)
+    d = drop_suffixes(d, skipdups=skipdups, provenance=provenance)
+    if provenance is not None:
+        d[_provenance_key] = provenance
+    return d
+
+
+def _tag_origins(origins, content, variant):
+    """
+    Record variant as the origin of content and of its conditional blocks.
+    """
+    for t in content:
+        origins[id(t)] = variant
+        if isinstance(t[2], (Condition, NegativeCondition)):
+            _tag_origins(origins, t[2].content, variant)
+
+
+def _apply_tracked(d, content, origins):
+    """
+    Apply the operators of content to d, following where the values are set.
+
+    :return: Dict mapping the keys of d to their Provenance.
+    """
+    provenance = {}
+    # provenance of the keys shadowed by include parameters
+    shadowed = {}
+    for t in content:
+        filename, linenum, op = t
+        if isinstance(op, Suffix):
+            # renames the keys, keeping their order
+            keys = list(d)
+            op.apply_to_dict(d)
+            for old, new in zip(keys, d):
+                if old != new and old in provenance:
+                    provenance[new] = provenance.pop(old)
+            continue
+        if isinstance(op, IncludeParamsEnd):
+            op.apply_to_dict(d)
+            for key, source in shadowed.pop(id(op.value), {}).items():
+                if source is None:
+                    provenance.pop(key, None)
+                else:
+                    provenance[key] = source
+            continue
+        if isinstance(op, IncludeParams):
+            shadowed[id(op)] = dict((key, provenance.get(key))
+                                    for key in op.value)
+        variant = origins.get(id(t), "")
+        if isinstance(op, LApplyPreDict):
+            op.apply_to_dict(d)
+            for key in op.value:
+                provenance[key] = Provenance(filename,
+                                             op.lines.get(key, linenum),
+                                             variant)
+            continue
+        before = d.copy()
+        op.apply_to_dict(d)
+        source = Provenance(filename, linenum, variant)
+        for key, value in d.items():
+            if (key not in before or value is not before[key] or
+                    (type(op) in (LSet, LAppend, LPrepend) and
+                     key == op.name)):
+                provenance[key] = source
+        for key in [key for key in provenance if key not in d]:
+            del provenance[key]
+    for key in reserved_keys:
+        provenance.pop(key, None)
+    return provenance
+
+
+class _Trace(object):
+
+    """
+    Steps building the combination explained by Parser.explain().
+    """
+
+    def __init__(self, name, ctx):
+        self.name = name
+        self.ctx = ctx
+        # only expand the combinations with all the labels of the name
+        self.filter = OnlyFilter([[[label] for label in ctx]], name)
+        self.lines = []
+
+    def on_path(self, ctx):
+        """
+        :return: Whether ctx leads to the explained combination.
+        """
+        return ctx == self.ctx[:len(ctx)]
+
+    def resolved(self, t, applies):
+        """
+        Record the decision of a filter or a conditional block.
+        """
+        filename, linenum, obj = t
+        if obj is self.filter:
+            return
+        location = "%s:%s" % (filename, linenum)
+        if isinstance(obj, (Condition, NegativeCondition)):
+            if applies:
+                self.lines.append("%s: conditional block %r applies" %
+                                  (location, obj.line))
+            else:
+                self.lines.append("%s: conditional block %r skipped, it"
+                                  " doesn't match" % (location, obj.line))
+        elif isinstance(obj, (OnlyFilter, NoFilter)):
+            self.lines.append("%s: filter %r passes" % (location, obj.line))
+
+    def apply(self, d, content, origins):
+        """
+        Apply content to d one operator at a time, recording the changes.
+
+        :return: Empty provenance, the dictionary isn't returned.
+        """
+        for t in content:
+            filename, linenum, op = t
+            if isinstance(op, (LUpdateFileMap, SuffixScope)):
+                op.apply_to_dict(d)
+                continue
+            before = d.copy()
+            op.apply_to_dict(d)
+            variant = origins.get(id(t), "")
+            variant = " (%s)" % variant if variant else ""
+            if isinstance(op, LApplyPreDict):
+                for key, value in op.value.items():
+                    self.lines.append("%s:%s%s: %s = %r" %
+                                      (filename, op.lines.get(key, linenum),
+                                       variant, key, value))
+                continue
+            location = "%s:%s%s" % (filename, linenum, variant)
+            if isinstance(op, Suffix):
+                statement = "suffix %s" % op.value
+            elif isinstance(op, (IncludeParams, IncludeParamsEnd)):
+                statement = "include parameters"
+            else:
+                statement = "%s %s %s" % (op.name, op.identifier, op.value)
+            changes = ["%s = %r" % (key, value) for key, value in d.items()
+                       if key not in reserved_keys and
+                       (key not in before or before[key] != value)]
+            changes += ["del %s" % key for key in before if key not in d]
+            self.lines.append("%s: %s -> %s" %
+                              (location, statement,
+                               ", ".join(changes) or "no change"))
+        return {}
+
+    def substituted(self, raw, d):
+        """
+        Record the values changed by the substitutions.
+        """
+        for key, value in d.items():
+            if key in raw and raw[key] != value:
+                self.lines.append("substitution: %s = %r -> %r" %
+                                  (key, raw[key], value))
+
+
+def _block_name(var_name, variants):
+    return var_name or "|".join(variants)
+
+
+def _content_filters(parser, content, filters):
+    """
+    Add the (kind, filter) of the filters in content to filters.
+    """
+    for _, _, obj in content:
+        if isinstance(obj, JoinFilter):
+            filters.append(("join", obj.filter))
+        elif isinstance(obj, (Condition, NegativeCondition)):
+            filters.append(("condition", obj.filter))
+            _content_filters(parser, obj.content, filters)
+        elif isinstance(obj, OnlyFilter):
+            filters.append(("only", obj.filter))
+        elif isinstance(obj, NoFilter):
+            filters.append(("no", obj.filter))
+        elif isinstance(obj, LazyInclude):
+            _content_filters(parser, obj.load(parser), filters)
+
+
+def _dependency_graph(parser):
+    """
+    Worker of :meth:`Parser.dependency_graph`.
+    """
+    names = []
+
+    def collect(blocks):
+        for block in blocks:
+            name = _block_name(block["name"], [variant["name"] for variant
+                                               in block["variants"]])
+            if name not in names:
+                names.append(name)
+            for variant in block["variants"]:
+                collect(variant["blocks"])
+    collect(parser.variant_names())
+
+    # block name -> labels of its variants
+    labels = {}
+    # (block name, dependencies of one of its variants)
+    deps = []
+    filters = []
+    visited = set()
+    stack = [parser.node]
+    while stack:
+        node = stack.pop()
+        if id(node) in visited:
+            continue
+        visited.add(id(node))
+        if node.children and node.children[0].name:
+            name = _block_name(node.children[0].var_name,
+                               [".".join(str(label.name)
+                                         for label in child.name)
+                                for child in node.children])
+            for child in node.children:
+                labels.setdefault(name, set()).update(child.name)
+                if child.dep:
+                    deps.append((name, child.dep))
+        _content_filters(parser, node.content, filters)
+        stack.extend(node.children)
+
+    def word_blocks(word):
+        found = []
+        for block in word:
+            for label in block:
+                for name in names:
+                    if (name in labels and name not in found and
+                            _in_labels(label, labels[name])):
+                        found.append(name)
+        return found
+
+    edges = set()
+    for kind, lfilter in filters:
+        if kind == "join" and len(lfilter) > 1:
+            for i, word in enumerate(lfilter):
+                for other in lfilter[i + 1:]:
+                    edges.update(Edge(source, target, kind)
+                                 for source in word_blocks(word)
+                                 for target in word_blocks(other))
+            continue
+        for word in lfilter:
+            found = word_blocks(word)
+            edges.update(Edge(source, target, kind)
+                         for i, source in enumerate(found)
+                         for target in found[i + 1:])
+    for name, dep in deps:
+        for word in dep:
+            edges.update(Edge(name, target, "dep")
+                         for target in word_blocks(word) if target != name)
+    edges = sorted(edges, key=lambda edge: (names.index(edge.source),
+                                            names.index(edge.target),
+                                            edge.kind))
+    return DependencyGraph(names, edges)
+
+
+def _expand_node(parser, node, skipdups):
+    """
+    Worker of :meth:`Parser.get_dicts_parallel`, expand a single subtree.
+
+    The parser is a copy, the duplicates and the limit of combinations are
+    handled over all the subtrees by the caller.
+    """
+    parser.dedup_dicts = False
+    parser.max_combinations = None
+    return list(parser.get_dicts(node, skipdups=skipdups))
+
+
+def _dominates(stop, node, cache):
+    """
+    Check whether every path from node to the leaves passes through stop.
+    """
+    if node is stop:
+        return True
+    if id(node) not in cache:
+        cache[id(node)] = bool(node.children) and all(
+            _dominates(stop, child, cache) for child in node.children)
+    return cache[id(node)]
+
+
+def _block_prefix(node):
+    """
+    Find the node holding the statements preceding a variants block.
+
+    :return: Nearest descendant every variant of the block passes through
+             or None if there's no such node.
+    """
+    cache = {}
+    queue = collections.deque(node.children[0].children)
+    seen = set()
+    while queue:
+        candidate = queue.popleft()
+        if id(candidate) in seen:
+            continue
+        seen.add(id(candidate))
+        cache.clear()
+        if all(_dominates(candidate, child, cache)
+               for child in node.children):
+            return candidate
+        queue.extend(candidate.children)
+    return None
+
+
+def _filter_string(lfilter, separator=", "):
+    return separator.join("..".join(".".join(str(label) for label in block)
+                                    for block in word)
+                          for word in lfilter)
+
+
+def _value_string(value):
+    quoted = value != value.strip() or "#" in value or not value
+    value = (value.replace("\\", "\\\\").replace("\n", "\\n")
+             .replace("\t", "\\t"))
+    if value[:1] in ("\"", "'"):
+        quoted = True
+    if quoted:
+        quote = "'" if "\"" in value else "\""
+        if quote in value:
+            # no quoting available, keep the value but escape comments
+            return value.replace("#", "\\#")
+        value = quote + value + quote
+    return value
+
+
+def _content_lines(content):
+    lines = []
+    for _, _, obj in content:
+        if isinstance(obj, LApplyPreDict):
+            lines += [(0, "%s = %s" % (key, _value_string(value)))
+                      for key, value in obj.value.items()]
+        elif isinstance(obj, LDel):
+            lines.append((0, "del %s" % obj.name))
+        elif isinstance(obj, Suffix):
+            lines.append((0, "suffix %s" % obj.value))
+        elif isinstance(obj, LPrint):
+            lines.append((0, "print %s" % _value_string(obj.value)))
+        elif isinstance(obj, IncludeParams):
+            lines += [(0, "%s = %s" % (key, _value_string(value)))
+                      for key, value in obj.value.items()]
+        elif isinstance(obj, LazyInclude):
+            lines.append((0, "include %s" % obj.filename))
+        elif isinstance(obj, (SuffixScope, LUpdateFileMap, IncludeParamsEnd)):
+            # recreated by the parser
+            continue
+        elif isinstance(obj, LOperators):
+            lines.append((0, "%s %s %s" % (obj.name, obj.identifier,
+                                           _value_string(obj.value))))
+        elif isinstance(obj, (Condition, NegativeCondition)):
+            negation = "!" if isinstance(obj, NegativeCondition) else ""
+            lines.append((0, "%s%s:" % (negation, _filter_string(obj.filter))))
+            lines += [(level + 1, line)
+                      for level, line in _content_lines(obj.content)]
+        elif isinstance(obj, JoinFilter):
+            lines.append((0, "join %s" % _filter_string(obj.filter, " ")))
+        elif isinstance(obj, OnlyFilter):
+            lines.append((0, "only %s" % _filter_string(obj.filter)))
+        elif isinstance(obj, NoFilter):
+            lines.append((0, "no %s" % _filter_string(obj.filter)))
+    return lines
+
+
+def _node_lines(node, stop):
+    """
+    Serialize the statements of node down to (but excluding) stop.
+
+    :return: List of (indentation level, line) tuples.
+    """
+    if node is stop:
+        return []
+    lines = []
+    if len(node.children) == 1 and not node.children[0].name:
+        lines += _node_lines(node.children[0], stop)
+    elif node.children:
+        prefix = _block_prefix(node)
+        if prefix is not None and prefix is not stop:
+            lines += _node_lines(prefix, stop)
+        var_name = node.children[0].var_name
+        lines.append((0, "variants %s:" % var_name if var_name
+                      else "variants:"))
+        for child in node.children:
+            name = ".".join(str(label.name) for label in child.name)
+            header = "- %s%s:" % ("" if child.append_to_shortname else "@",
+                                  name)
+            if child.dep:
+                header += " %s" % _filter_string(child.dep)
+            lines.append((1, header))
+            body = [(level + 2, line) for level, line in
+                    _node_lines(child, prefix or stop)]
+            if var_name and body[:1] == [(2, "%s = %s" % (var_name, name))]:
+                # recreated by the parser from the variant name
+                body = body[1:]
+            lines += body
+    lines += _content_lines(node.content)
+    return lines
+
+
+def _node_keys(parser, node, keys, visited):
+    """
+    Add the keys assigned in node and in its subtree to keys.
+    """
+    if id(node) in visited:
+        return
+    visited.add(id(node))
+    _content_keys(parser, node.content, keys)
+    for child in node.children:
+        _node_keys(parser, child, keys, visited)
+
+
+def _content_keys(parser, content, keys):
+    """
+    Add the keys assigned in content and in its conditional blocks to keys.
+    """
+    for _, _, obj in content:
+        if isinstance(obj, LApplyPreDict):
+            keys.update(obj.value)
+        elif isinstance(obj, IncludeParams):
+            keys.update(obj.value)
+        elif isinstance(obj, (LSet, LAppend, LPrepend, LLazySet)):
+            keys.add(obj.name)
+        elif isinstance(obj, (Condition, NegativeCondition)):
+            _content_keys(parser, obj.content, keys)
+        elif isinstance(obj, LazyInclude):
+            _content_keys(parser, obj.load(parser), keys)
+
+
+def _variant_blocks(node, stop):
+    """
+    Describe the variants blocks of node down to (but excluding) stop.
+    """
+    if node is stop:
+        return []
+    if len(node.children) == 1 and not node.children[0].name:
+        return _variant_blocks(node.children[0], stop)
+    if not node.children:
+        return []
+    prefix = _block_prefix(node)
+    blocks = []
+    if prefix is not None and prefix is not stop:
+        blocks += _variant_blocks(prefix, stop)
+    variants = [{"name": ".".join(str(label.name) for label in child.name),
+                 "blocks": _variant_blocks(child, prefix or stop)}
+                for child in node.children]
+    blocks.append({"name": node.children[0].var_name or None,
+                   "variants": variants})
+    return blocks
+
+
+def parse_string(s, **kwargs):
+    """
+    Parse a configuration string.
+
+    :param s: String to parse.
+    :param kwargs: Options passed to :class:`Parser`.
+    :return: Parser holding the parsed configuration.
+    """
+    p = Parser(**kwargs)
+    p.parse_string(s)
+    return p
+
+
+def lex(s, filename="<string>", spans=False, indent_policy=None,
+        normalize_identifiers=False):
+    """
+    Tokenize a configuration string.
+
+    The assignment values are lexed as strings like the parser does it,
+    so values with substitutions or special characters can be tokenized.
+
+    :param s: String to tokenize.
+    :param filename: Name of the string used in the reported errors.
+    :param spans: Return (token, Span) pairs instead of the tokens.
+    :param indent_policy: :class:`IndentPolicy` of the string.
+    :param normalize_identifiers: NFC normalize the identifiers.
+    :return: List of tokens.
+    :raise CartconfError: On a string the lexer can't tokenize.
+    """
+    lexer = Lexer(StrReader(s, indent_policy, filename))
+    lexer.normalize = normalize_identifiers
+    lexer.set_fast()
+    return lexer.tokenize(spans)
+
+
+def parse_flat(s, **kwargs):
+    """
+    Parse a configuration string made of assignments only.
+
+    Comments, continuations, includes, substitutions and all the assignment
+    operators work as usual, variants blocks, filters and conditional blocks
+    are rejected.
+
+    :param s: String to parse.
+    :param kwargs: Options passed to :class:`Parser`.
+    :return: Dict of the assigned keys in assignment order.
+    :raise ParserError: On any statement other than an assignment.
+    """
+    p = Parser(**kwargs)
+    p._flat = True
+    p.parse_string(s)
+    d = next(p.get_dicts())
+    return dict((key, value) for key, value in d.items()
+                if key not in reserved_keys)
+
+
+def parse_file(filename, **kwargs):
+    """
+    Parse a configuration file.
+
+    :param filename: Path of the configuration file.
+    :param kwargs: Options passed to :class:`Parser`.
+    :return: Parser holding the parsed configuration.
+    """
+    return Parser(filename, **kwargs)
+
+
+def validate(s, filename="<string>", **kwargs):
+    """
+    Check a configuration string without expanding it.
+
+    Parsing stops at the first error, so the string is parsed again with
+    every offending line blanked until it passes and all the errors get
+    reported at once.  An error inside an included file blanks the include
+    line itself.
+
+    :param s: String to check.
+    :param filename: Name of the string used in the reported errors.
+    :param kwargs: Options passed to :class:`Parser`.
+    :return: List of the errors found in order, empty if the string is valid.
+    """
+    lines = s.splitlines()
+    errors = []
+    while True:
+        p = Parser(**kwargs)
+        p.node.filename = filename
+        reader = None
+        try:
+            reader = StrReader("\n".join(lines), p.indent_policy, filename)
+            p._parse(Lexer(reader), p.node)
+        except CartconfError as details:
+            errors.append(details)
+            linenum = getattr(details, "linenum", None)
+            if details.filename != filename and reader is not None:
+                linenum = reader.linenum
+            if (not linenum or linenum > len(lines) or
+                    not lines[linenum - 1]):
+                break
+            lines[linenum - 1] = ""
+        else:
+            break
+    return errors
+
+
 def convert_data_size(size, default_sufix='B'):
     """
     Convert data size from human readable units to an int of arbitrary size.
@@ -1362,11 +4461,71 @@ def compare_string(str1, str2):
         return 1
 
 
+def substitute_dict(dic, strict=False):
+    """
+    Resolve ${key} references left over after all assignments were applied.
+
+    References are resolved against the same dictionary, recursively.  The
+    ${env:NAME} and ${env:NAME:-default} references are resolved from the
+    process environment instead.  A reference to an undefined key or
+    environment variable without default is kept literally unless strict is
+    set.
+
+    :param dic: Dictionary to substitute in place.
+    :param strict: Raise ParserError on references to undefined keys.
+    :raise ParserError: On cyclic references.
+    """
+    resolved = {}
+
+    def resolve(key, stack):
+        if key in resolved:
+            return resolved[key]
+        value = dic[key]
+        if "${" not in value:
+            return value
+        if key in stack:
+            raise ParserError("Cyclic substitution %s" %
+                              " -> ".join(stack + [key]))
+        stack = stack + [key]
+
+        def replace(match):
+            name = match.group(1)
+            if name.startswith("env:"):
+                var, sep, default = name[4:].partition(":-")
+                if var in os.environ:
+                    return os.environ[var]
+                if sep:
+                    return default
+                if strict:
+                    raise ParserError("Undefined environment variable %s in %s"
+                                      % (var, key))
+                return match.group(0)
+            if isinstance(dic.get(name), str):
+                return resolve(name, stack)
+            if strict:
+                raise ParserError("Undefined substitution ${%s} in %s" %
+                                  (name, key))
+            return match.group(0)
+
+        resolved[key] = match_substitute.sub(replace, value)
+        return resolved[key]
+
+    # only the values holding a reference can change
+    pending = [key for key, value in dic.items()
+               if isinstance(value, str) and "${" in value and
+               isinstance(key, str) and key not in reserved_keys]
+    for key in pending:
+        dic[key] = resolve(key, [])
+
+
+_postfixes = ("_max", "_min", "_fixed")
+
+
 def postfix_parse(dic):
     tmp_dict = {}
     for key in dic:
         # Bypass the case that use tuple as key value
-        if isinstance(key, tuple):
+        if isinstance(key, tuple) or not key.endswith(_postfixes):
             continue
         if key.endswith("_max"):
             tmp_key = key.split("_max")[0]
@@ -0,0 +1,126 @@
+#!/usr/bin/python
+
+"""
+Type stub generator.
+
+Writes a stub of the names exported by the cartconf package for the type
+checkers and the IDEs, run it after changing a signature:
+
+    python -m cartconf.stubgen cartconf/__init__.pyi
+"""
+
+import inspect
+import sys
+import types
+
+
+HEADER = ("# Generated by cartconf.stubgen, do not edit.\n\n"
+          "from typing import Any\n")
+
+
+def exported_names(module):
+    """
+    :param module: Module to inspect.
+    :return: Sorted public names of the module other than submodules.
+    """
+    return sorted(name for name, obj in vars(module).items()
+                  if not name.startswith("_") and
+                  not isinstance(obj, types.ModuleType))
+
+
+def _signature(func, skip_first=False):
+    """
+    :param skip_first: Keep the first parameter, i.e. self or cls, as it is.
+    :return: Parameter list of func, the default values become "...".
+    """
+    try:
+        sig = inspect.signature(func)
+    except (TypeError, ValueError):
+        return "*args: Any, **kwargs: Any"
+    params = []
+    star = False
+    for param in sig.parameters.values():
+        if skip_first and not params:
+            params.append(param.name)
+            continue
+        text = param.name
+        if param.kind is param.VAR_POSITIONAL:
+            text = "*" + text
+            star = True
+        elif param.kind is param.VAR_KEYWORD:
+            text = "**" + text
+        elif param.kind is param.KEYWORD_ONLY and not star:
+            params.append("*")
+            star = True
+        text += ": Any"
+        if param.default is not param.empty:
+            text += " = ..."
+        params.append(text)
+    return ", ".join(params)
+
+
+def _function_stub(name, func, indent=""):
+    return "%sdef %s(%s) -> Any: ...\n" % (indent, name, _signature(func))
+
+
+def _class_stub(name, cls):
+    bases = [base.__name__ for base in cls.__bases__ if base is not object]
+    lines = ["class %s%s:\n" % (name, "(%s)" % ", ".join(bases)
+                                  if bases else "")]
+    for attr, obj in vars(cls).items():
+        if attr.startswith("_") and attr != "__init__":
+            continue
+        if isinstance(obj, property):
+            lines.append("    @property\n"
+                         "    def %s(self) -> Any: ...\n" % attr)
+        elif isinstance(obj, classmethod):
+            lines.append("    @classmethod\n"
+                         "    def %s(%s) -> Any: ...\n" %
+                         (attr, _signature(obj.__func__, True)))
+        elif isinstance(obj, staticmethod):
+            lines.append("    @staticmethod\n" +
+                         _function_stub(attr, obj.__func__, "    "))
+        elif inspect.isfunction(obj):
+            lines.append("    def %s(%s) -> %s: ...\n" %
+                         (attr, _signature(obj, True),
+                          "None" if attr == "__init__" else "Any"))
+    if len(lines) == 1:
+        lines.append("    ...\n")
+    return "".join(lines)
+
+
+def generate(module=None):
+    """
+    Generate the stub of a module.
+
+    :param module: Module to describe, the cartconf package by default.
+    :return: Stub source text.
+    """
+    if module is None:
+        import cartconf as module
+    stubs = [HEADER]
+    for name in exported_names(module):
+        obj = getattr(module, name)
+        if inspect.isclass(obj):
+            stubs.append(_class_stub(name, obj))
+        elif callable(obj):
+            stubs.append(_function_stub(name, obj))
+        else:
+            stubs.append("%s: Any\n" % name)
+    return "\n\n".join(stubs)
+
+
+def main(argv=None):
+    argv = sys.argv[1:] if argv is None else argv
+    if len(argv) > 1:
+        sys.exit("usage: python -m cartconf.stubgen [output.pyi]")
+    stub = generate()
+    if argv:
+        with open(argv[0], "w") as f:
+            f.write(stub)
+    else:
+        sys.stdout.write(stub)
+
+
+if __name__ == "__main__":
+    main()
@@ -3,25 +3,101 @@ Tokens module.
 """
 
 import collections
+import enum
 import os
 import re
+import sys
+import threading
 
 from .exceptions import ParserError
-from .constants import reserved_keys
-from .utils import drop_suffixes
+from .constants import reserved_keys, messages_key
+from .utils import drop_suffixes, is_reserved_key
 
 
-#: list of all available manual steps or simply semi-automation tools
-#__all__ = ["Filter", "NoOnlyFilter", "OnlyFilter", "NoFilter", "JoinFilter",
-#           "BlockFilter", "Condition", "NegativeCondition"]
+#: list of all available tokens and token maps
+__all__ = ["TokenKind", "Token", "LIndent", "LDedent", "LEndL", "LEndBlock",
+           "LIdentifier", "LWhite", "LString", "LRegExp", "LColon",
+           "LVariants", "LDot", "LVariant", "LDefault", "LOnly", "LSuffix",
+           "LPrefix", "LJoin",
+           "LNo", "LCond", "LNotCond", "LOr", "LAnd", "LCoc", "LComa",
+           "LLBracket", "LRBracket", "LLRBracket", "LRRBracket",
+           "LRegExpStart", "LRegExpStop", "LInclude", "LOperators",
+           "LRegExpOperators", "LSet", "LAppend", "LPrepend", "LLazySet",
+           "LRegExpSet", "LRegExpAppend", "LRegExpPrepend", "LRegExpDel",
+           "LDel", "LPrint", "LApplyPreDict",
+           "LUpdateFileMap", "Suffix", "SuffixScope", "IncludeParams",
+           "IncludeParamsEnd", "tokens_map", "tokens_oper"]
 
 
 match_substitute = re.compile("\$\{(.+?)\}")
 
 
+class TokenKind(enum.Enum):
+    """
+    Kind of a token, shared by the token classes of the same role.
+
+    Matching on the kind doesn't depend on the class hierarchy, e.g. a
+    LDedent is a LIndent subclass but has its own kind.
+    """
+    TOKEN = "token"
+    INDENT = "indent"
+    DEDENT = "dedent"
+    END_LINE = "endl"
+    END_BLOCK = "endblock"
+    IDENTIFIER = "identifier"
+    WHITE = "white"
+    STRING = "string"
+    REGEXP = "regexp"
+    COLON = ":"
+    VARIANTS = "variants"
+    DOT = "."
+    VARIANT = "-"
+    DEFAULT = "@"
+    ONLY = "only"
+    SUFFIX = "suffix"
+    PREFIX = "prefix"
+    JOIN = "join"
+    NO = "no"
+    COND = "cond"
+    NOT_COND = "!"
+    OR = "or"
+    AND = ".."
+    COC = "coc"
+    COMMA = ","
+    LBRACKET = "["
+    RBRACKET = "]"
+    LRBRACKET = "("
+    RRBRACKET = ")"
+    SUBSTITUTION_START = "${"
+    SUBSTITUTION_STOP = "}"
+    INCLUDE = "include"
+    SET = "="
+    APPEND = "+="
+    PREPEND = "<="
+    LAZY_SET = "~="
+    REGEXP_SET = "?="
+    REGEXP_APPEND = "?+="
+    REGEXP_PREPEND = "?<="
+    REGEXP_DEL = "-="
+    DEL = "del"
+    PRINT = "print"
+    #: operators created by the parser, not by the lexer
+    OPERATOR = "operator"
+
+
 class Token(object):
-    __slots__ = []
-    identifier = ""
+    __slots__ = ["_identifier"]
+    kind = TokenKind.TOKEN
+
+    def __init__(self, identifier=""):
+        self._identifier = identifier
+
+    @property
+    def identifier(self):
+        """
+        Subclasses shadow this with a constant class attribute.
+        """
+        return self._identifier
 
     def __str__(self):
         return self.identifier
@@ -29,22 +105,54 @@ class Token(object):
     def __repr__(self):
         return "'%s'" % self.identifier
 
+    def __eq__(self, o):
+        if not isinstance(o, Token):
+            return NotImplemented
+        return o.identifier == self.identifier
+
     def __ne__(self, o):
         """
         The comparison is asymmetric due to optimization.
         """
+        if not isinstance(o, Token):
+            return NotImplemented
         if o.identifier != self.identifier:
             return True
         return False
 
+    def __hash__(self):
+        return hash(self.identifier)
+
 
 class LIndent(Token):
+    """
+    Indentation of a line, the count of its leading whitespace.
+    """
     __slots__ = ["length"]
+    kind = TokenKind.INDENT
     identifier = "indent"
+    #: smallest valid length
+    min_length = 0
 
     def __init__(self, length):
+        if (not isinstance(length, int) or isinstance(length, bool) or
+                length < self.min_length):
+            raise ValueError("Invalid indentation length %r" % (length,))
         self.length = length
 
+    def __eq__(self, o):
+        if not isinstance(o, LIndent):
+            return NotImplemented
+        return o.identifier == self.identifier and o.length == self.length
+
+    def __ne__(self, o):
+        if not isinstance(o, LIndent):
+            return NotImplemented
+        return o.identifier != self.identifier or o.length != self.length
+
+    def __hash__(self):
+        return hash(self.identifier)
+
     def __str__(self):
         return "%s %s" % (self.identifier, self.length)
 
@@ -52,19 +160,30 @@ class LIndent(Token):
         return "%s %s" % (self.identifier, self.length)
 
 
+class LDedent(LIndent):
+    __slots__ = []
+    kind = TokenKind.DEDENT
+    identifier = "dedent"
+
+
 class LEndL(Token):
     __slots__ = []
+    kind = TokenKind.END_LINE
     identifier = "endl"
 
 
 class LEndBlock(LIndent):
     __slots__ = []
-    pass
+    kind = TokenKind.END_BLOCK
+    # -1 marks the end of the input
+    min_length = -1
 
 
 class LIdentifier(str):
     __slots__ = []
-    identifier = "Identifier re([A-Za-z0-9][A-Za-z0-9_-]*)"
+    kind = TokenKind.IDENTIFIER
+    # unicode letters, numbers and marks
+    identifier = r"Identifier re([\w-]+)"
 
     def __str__(self):
         return super(LIdentifier, self).__str__()
@@ -127,139 +246,193 @@ class LIdentifier(str):
 
 class LWhite(LIdentifier):
     __slots__ = []
+    kind = TokenKind.WHITE
     identifier = "WhiteSpace re(\\s)"
 
 
 class LString(LIdentifier):
     __slots__ = []
+    kind = TokenKind.STRING
     identifier = "String re(.+)"
 
 
+class LRegExp(LIdentifier):
+    __slots__ = []
+    kind = TokenKind.REGEXP
+    identifier = "RegExp re(/.+/)"
+
+
 class LColon(Token):
     __slots__ = []
+    kind = TokenKind.COLON
     identifier = ":"
 
 
 class LVariants(Token):
     __slots__ = []
+    kind = TokenKind.VARIANTS
     identifier = "variants"
 
 
 class LDot(Token):
     __slots__ = []
+    kind = TokenKind.DOT
     identifier = "."
 
 
 class LVariant(Token):
     __slots__ = []
+    kind = TokenKind.VARIANT
     identifier = "-"
 
 
 class LDefault(Token):
     __slots__ = []
+    kind = TokenKind.DEFAULT
     identifier = "@"
 
 
 class LOnly(Token):
     __slots__ = []
+    kind = TokenKind.ONLY
     identifier = "only"
 
 
 class LSuffix(Token):
     __slots__ = []
+    kind = TokenKind.SUFFIX
     identifier = "suffix"
 
 
+class LPrefix(Token):
+    __slots__ = []
+    kind = TokenKind.PREFIX
+    identifier = "prefix"
+
+
 class LJoin(Token):
     __slots__ = []
+    kind = TokenKind.JOIN
     identifier = "join"
 
 
 class LNo(Token):
     __slots__ = []
+    kind = TokenKind.NO
     identifier = "no"
 
 
 class LCond(Token):
     __slots__ = []
+    kind = TokenKind.COND
     identifier = ""
 
 
 class LNotCond(Token):
     __slots__ = []
+    kind = TokenKind.NOT_COND
     identifier = "!"
 
 
 class LOr(Token):
     __slots__ = []
+    kind = TokenKind.OR
     identifier = ","
 
 
 class LAnd(Token):
     __slots__ = []
+    kind = TokenKind.AND
     identifier = ".."
 
 
 class LCoc(Token):
     __slots__ = []
+    kind = TokenKind.COC
     identifier = "."
 
 
 class LComa(Token):
     __slots__ = []
+    kind = TokenKind.COMMA
     identifier = ","
 
 
 class LLBracket(Token):
     __slots__ = []
+    kind = TokenKind.LBRACKET
     identifier = "["
 
 
 class LRBracket(Token):
     __slots__ = []
+    kind = TokenKind.RBRACKET
     identifier = "]"
 
 
 class LLRBracket(Token):
     __slots__ = []
+    kind = TokenKind.LRBRACKET
     identifier = "("
 
 
 class LRRBracket(Token):
     __slots__ = []
+    kind = TokenKind.RRBRACKET
     identifier = ")"
 
 
 class LRegExpStart(Token):
     __slots__ = []
+    kind = TokenKind.SUBSTITUTION_START
     identifier = "${"
 
 
 class LRegExpStop(Token):
     __slots__ = []
+    kind = TokenKind.SUBSTITUTION_STOP
     identifier = "}"
 
 
 class LInclude(Token):
     __slots__ = []
+    kind = TokenKind.INCLUDE
     identifier = "include"
 
 
 class LOperators(Token):
     __slots__ = ["name", "value"]
+    kind = TokenKind.OPERATOR
     identifier = ""
     function = None
 
     def set_operands(self, name, value):
         # pylint: disable=W0201
-        self.name = str(name)
+        self.name = sys.intern(str(name))
         # pylint: disable=W0201
         self.value = str(value)
         return self
 
 
+class LRegExpOperators(LOperators):
+    """
+    Operator whose name is a regular expression matched against the keys.
+
+    The expression is compiled once, when the operands are set, and reused
+    for every dictionary the operator is applied to.
+    """
+    __slots__ = ["exp"]
+
+    def set_operands(self, name, value):
+        super(LRegExpOperators, self).set_operands(name, value)
+        # pylint: disable=W0201
+        self.exp = re.compile("%s$" % self.name)
+        return self
+
+
 class LSet(LOperators):
     __slots__ = []
+    kind = TokenKind.SET
     identifier = "="
 
     def apply_to_dict(self, d):
@@ -268,100 +441,173 @@ class LSet(LOperators):
         """
         if self.name not in reserved_keys:
             d[self.name] = _substitution(self.value, d)
+            if _saved_keys.scopes:
+                _assigned(self.name)
 
 
 class LAppend(LOperators):
     __slots__ = []
+    kind = TokenKind.APPEND
     identifier = "+="
 
     def apply_to_dict(self, d):
         if self.name not in reserved_keys:
             d[self.name] = d.get(self.name, "") + _substitution(self.value, d)
+            if _saved_keys.scopes:
+                _assigned(self.name)
 
 
 class LPrepend(LOperators):
     __slots__ = []
+    kind = TokenKind.PREPEND
     identifier = "<="
 
     def apply_to_dict(self, d):
         if self.name not in reserved_keys:
             d[self.name] = _substitution(self.value, d) + d.get(self.name, "")
+            if _saved_keys.scopes:
+                _assigned(self.name)
 
 
 class LLazySet(LOperators):
     __slots__ = []
+    kind = TokenKind.LAZY_SET
     identifier = "~="
 
     def apply_to_dict(self, d):
         if self.name not in reserved_keys and self.name not in d:
             d[self.name] = _substitution(self.value, d)
+            if _saved_keys.scopes:
+                _assigned(self.name)
 
 
-class LRegExpSet(LOperators):
+class LRegExpSet(LRegExpOperators):
     __slots__ = []
+    kind = TokenKind.REGEXP_SET
     identifier = "?="
 
     def apply_to_dict(self, d):
-        exp = re.compile("%s$" % self.name)
         value = _substitution(self.value, d)
         for key in d:
             keystr = "".join(key) if isinstance(key, tuple) else key
-            if key not in reserved_keys and exp.match(keystr):
+            if key not in reserved_keys and self.exp.match(keystr):
                 d[key] = value
+                if _saved_keys.scopes:
+                    _assigned(key)
 
 
-class LRegExpAppend(LOperators):
+class LRegExpAppend(LRegExpOperators):
     __slots__ = []
+    kind = TokenKind.REGEXP_APPEND
     identifier = "?+="
 
     def apply_to_dict(self, d):
-        exp = re.compile("%s$" % self.name)
         value = _substitution(self.value, d)
         for key in d:
             keystr = "".join(key) if isinstance(key, tuple) else key
-            if key not in reserved_keys and exp.match(keystr):
+            if key not in reserved_keys and self.exp.match(keystr):
                 d[key] += value
+                if _saved_keys.scopes:
+                    _assigned(key)
 
 
-class LRegExpPrepend(LOperators):
+class LRegExpPrepend(LRegExpOperators):
     __slots__ = []
+    kind = TokenKind.REGEXP_PREPEND
     identifier = "?<="
 
     def apply_to_dict(self, d):
-        exp = re.compile("%s$" % self.name)
         value = _substitution(self.value, d)
         for key in d:
             keystr = "".join(key) if isinstance(key, tuple) else key
-            if key not in reserved_keys and exp.match(keystr):
+            if key not in reserved_keys and self.exp.match(keystr):
                 d[key] = value + d[key]
+                if _saved_keys.scopes:
+                    _assigned(key)
 
 
-class LDel(LOperators):
+class LRegExpDel(LOperators):
+    """
+    Operator removing the parts of the value of a key matching a regular
+    expression, a missing key is left missing.
+
+    Unlike the other LRegExp* operators the name is a plain key and the
+    value is the expression.
+    """
+    __slots__ = ["exp"]
+    kind = TokenKind.REGEXP_DEL
+    identifier = "-="
+
+    def set_operands(self, name, value):
+        super(LRegExpDel, self).set_operands(name, value)
+        # pylint: disable=W0201
+        self.exp = None
+        if "$" not in self.value:
+            self.exp = re.compile(self.value)
+        return self
+
+    def apply_to_dict(self, d):
+        if self.name in reserved_keys or self.name not in d:
+            return
+        exp = self.exp
+        if exp is None:
+            exp = re.compile(_substitution(self.value, d))
+        d[self.name] = exp.sub("", d[self.name])
+        if _saved_keys.scopes:
+            _assigned(self.name)
+
+
+class LDel(LRegExpOperators):
     __slots__ = []
+    kind = TokenKind.DEL
     identifier = "del"
 
     def apply_to_dict(self, d):
-        exp = re.compile("%s$" % self.name)
         keys_to_del = collections.deque()
         for key in d:
             keystr = "".join(key) if isinstance(key, tuple) else key
-            if key not in reserved_keys and exp.match(keystr):
+            if key not in reserved_keys and self.exp.match(keystr):
                 keys_to_del.append(key)
         for key in keys_to_del:
             del d[key]
 
 
-class LApplyPreDict(LOperators):
+class LPrint(LOperators):
+    """
+    Message of a print statement, added to the messages of the dictionary.
+    """
     __slots__ = []
+    kind = TokenKind.PRINT
+    identifier = "print"
+
+    def apply_to_dict(self, d):
+        # a new list, the dictionaries don't share the messages
+        d[self.name] = (d.get(self.name, []) +
+                        [_substitution(self.value, d)])
+
+
+class LApplyPreDict(LOperators):
+    __slots__ = ["lines", "lists"]
     identifier = "apply_pre_dict"
 
     def set_operands(self, name, value):
         self.name = name    # pylint: disable=W0201,E0237
         self.value = value  # pylint: disable=W0201,E0237
+        # line numbers of the assignments merged into value
+        self.lines = {}     # pylint: disable=W0201
+        # keys of list values, e.g. the "dep" of Parser.from_json(), copied
+        # into every dictionary
+        self.lists = [key for key, val in value.items()
+                      if isinstance(val, list)]  # pylint: disable=W0201
         return self
 
     def apply_to_dict(self, d):
         d.update(self.value)
+        for key in self.lists:
+            d[key] = list(d[key])
+        if _saved_keys.scopes:
+            for key in self.value:
+                _assigned(key)
 
     def __str__(self):
         return "Apply_pre_dict: %s" % self.value
@@ -398,10 +644,67 @@ class LUpdateFileMap(LOperators):
             d[dest][self.shortname] = self.name
 
 
+class _SavedKeys(threading.local):
+
+    def __init__(self):
+        # keys an operator saves from the dictionary for a later operator,
+        # by id of the saving one
+        self.keys = {}
+        # keys assigned since the start of the suffix scopes being applied,
+        # by id of their suffix
+        self.scopes = {}
+
+
+#: every thread expanding a tree saves its own keys
+_saved_keys = _SavedKeys()
+
+
+def _saved(op):
+    return _saved_keys.keys.get(id(op))
+
+
+def _save(op, keys):
+    _saved_keys.keys[id(op)] = keys
+
+
+def clear_saved_keys():
+    """
+    Forget the keys saved in the thread, at the end of an expansion.
+    """
+    _saved_keys.keys.clear()
+    end_suffix_scopes()
+
+
+def end_suffix_scopes():
+    """
+    Stop following the assignments of the suffix scopes still open in the
+    thread, once the operators of a dictionary are applied.
+    """
+    _saved_keys.scopes.clear()
+
+
+def _assigned(key):
+    for keys in _saved_keys.scopes.values():
+        keys.add(key)
+
+
 class Suffix(LOperators):
     __slots__ = []
     identifier = "apply_suffix"
 
+    @property
+    def inherited(self):
+        """
+        Keys of the dictionary at the start of the scope, see SuffixScope.
+        """
+        return _saved(self)
+
+    def _end_scope(self):
+        """
+        :return: Keys assigned within the scope, see SuffixScope.
+        """
+        return _saved_keys.scopes.pop(id(self), set())
+
     def __str__(self):
         return "Suffix: %s" % (self.value)
 
@@ -414,13 +717,112 @@ class Suffix(LOperators):
                 return True
 
     def apply_to_dict(self, d):
-        for key in d.copy():
-            if key not in reserved_keys:
+        inherited = self.inherited
+        assigned = self._end_scope()
+        items = list(d.items())
+        d.clear()
+        for key, value in items:
+            if not isinstance(key, tuple) and is_reserved_key(key):
+                pass
+            elif (inherited is not None and key in inherited and
+                    key not in assigned):
+                # Keys set outside of the suffix scope are left alone
+                pass
+            else:
                 # Store key as a tuple: (key, suffix1, suffix2, suffix3,....)
                 # This allows us to manipulate later on suffixes
-                # Add suffix to the key, remove the old key
-                new_key = (key if isinstance(key, tuple) else (key,)) + (self.value,)
-                d[new_key] = d.pop(key)
+                # Rebuilding the dictionary keeps the order of the keys
+                key = (key if isinstance(key, tuple) else (key,)) + (self.value,)
+                if _saved_keys.scopes:
+                    _assigned(key)
+            d[key] = value
+
+
+class SuffixScope(LOperators):
+    """
+    Marks the start of the block a suffix is declared in.
+
+    The keys present in the dictionary at this point are remembered by the
+    suffix operator, which renames only the keys set within its block: the
+    ones assigned since the scope started or missing at its start.
+    """
+    __slots__ = []
+    identifier = "suffix_scope"
+
+    def set_operands(self, name, value):
+        self.name = name    # pylint: disable=W0201,E0237
+        self.value = value  # pylint: disable=W0201,E0237
+        return self
+
+    def apply_to_dict(self, d):
+        _save(self.value, set(d))
+        _saved_keys.scopes[id(self.value)] = set()
+
+    def __str__(self):
+        return "Suffix_scope: %s" % self.value.value
+
+    def __repr__(self):
+        return "Suffix_scope %s" % self.value.value
+
+
+class IncludeParams(LOperators):
+    """
+    Sets the parameters passed by "include <file> with <key>=<value>".
+
+    The previous values of the keys are remembered for the matching
+    IncludeParamsEnd, so the parameters are only visible in the included
+    file.
+    """
+    __slots__ = []
+    identifier = "include_params"
+
+    def set_operands(self, name, value):
+        self.name = name    # pylint: disable=W0201,E0237
+        self.value = value  # pylint: disable=W0201,E0237
+        return self
+
+    @property
+    def saved(self):
+        """
+        Values of the keys before the parameters were set.
+        """
+        return _saved(self)
+
+    def apply_to_dict(self, d):
+        _save(self, dict((key, d[key]) for key in self.value if key in d))
+        d.update(self.value)
+
+    def __str__(self):
+        return "Include_params: %s" % self.value
+
+    def __repr__(self):
+        return "Include_params %s" % self.value
+
+
+class IncludeParamsEnd(LOperators):
+    """
+    Restores the keys overridden by the parameters of an include.
+    """
+    __slots__ = []
+    identifier = "include_params_end"
+
+    def set_operands(self, name, value):
+        self.name = name    # pylint: disable=W0201,E0237
+        self.value = value  # pylint: disable=W0201,E0237
+        return self
+
+    def apply_to_dict(self, d):
+        for key in self.value.value:
+            if key in self.value.saved:
+                d[key] = self.value.saved[key]
+            else:
+                d.pop(key, None)
+
+    def __str__(self):
+        return "Include_params_end: %s" % self.value.value
+
+    def __repr__(self):
+        return "Include_params_end %s" % self.value.value
 
 
 tokens_map = {"-": LVariant,
@@ -442,6 +844,7 @@ tokens_oper = {"": LSet,
                "?": LRegExpSet,
                "?+": LRegExpAppend,
                "?<": LRegExpPrepend,
+               "-": LRegExpDel,
                }
 
 
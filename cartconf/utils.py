@@ -2,21 +2,134 @@
 Utils module.
 """
 
-from .constants import reserved_keys
+import json
 
+from .constants import reserved_keys, reserved_prefix
 
-def drop_suffixes(d, skipdups=True):
+
+def is_reserved_key(key):
+    """
+    Check if a key is set by the parser and may not be assigned.
+    """
+    return key in reserved_keys or key.startswith(reserved_prefix)
+
+
+def split_escaped(value, sep=","):
+    """
+    Split value on the separators not escaped by a backslash.
+
+    :return: List of the items, an escaped separator is kept as a literal
+             separator of its item.
+    """
+    items = []
+    item = ""
+    pos = 0
+    while True:
+        end = value.find(sep, pos)
+        if end < 0:
+            items.append(item + value[pos:])
+            return items
+        if end > pos and value[end - 1] == "\\":
+            item += value[pos:end - 1] + sep
+        else:
+            items.append(item + value[pos:end])
+            item = ""
+        pos = end + len(sep)
+
+
+#: strings accepted by Params.get_bool()
+_bool_values = {"yes": True, "true": True, "1": True,
+                "no": False, "false": False, "0": False}
+
+_missing = object()
+
+
+class Params(dict):
+
+    """
+    Expanded dictionary with typed reads of its string values.
+
+    The parser yields plain dictionaries, wrap one to read it typed::
+
+        params = Params(d)
+        timeout = params.get_int("timeout", 60)
+
+    The getters raise KeyError for a missing key unless a default is given,
+    the default is returned as it is.
+    """
+
+    def _get(self, key, default):
+        if key not in self:
+            if default is _missing:
+                raise KeyError(key)
+            return default, True
+        return self[key], False
+
+    def get_int(self, key, default=_missing):
+        """
+        :return: Value of key as an integer, decimal or with a 0x, 0o or
+                 0b prefix.
+        :raise ValueError: If the value is not an integer.
+        """
+        value, missing = self._get(key, default)
+        if missing:
+            return value
+        text = str(value).strip()
+        try:
+            try:
+                return int(text)
+            except ValueError:
+                # 0x, 0o and 0b prefixed
+                return int(text, 0)
+        except ValueError:
+            raise ValueError("Value of %s is not an integer: %r" %
+                             (key, value))
+
+    def get_bool(self, key, default=_missing):
+        """
+        :return: Value of key as a boolean, from yes/no, true/false or 1/0
+                 in any case.
+        :raise ValueError: If the value is none of them.
+        """
+        value, missing = self._get(key, default)
+        if missing:
+            return value
+        try:
+            return _bool_values[str(value).strip().lower()]
+        except KeyError:
+            raise ValueError("Value of %s is not a boolean: %r" %
+                             (key, value))
+
+    def get_list(self, key, sep=None, default=_missing):
+        """
+        :param sep: Separator of the items, any whitespace if None.  A
+                    separator escaped by a backslash is part of its item.
+        :return: Value of key split into a list of stripped items, empty
+                 items are dropped.
+        """
+        value, missing = self._get(key, default)
+        if missing:
+            return value
+        if isinstance(value, list):
+            return value
+        if sep is None:
+            items = str(value).split()
+        else:
+            items = split_escaped(str(value), sep)
+        return [item.strip() for item in items if item.strip()]
+
+
+def drop_suffixes(d, skipdups=True, provenance=None):
     """
     Merge suffixes for same var, or drop off unnecessary suffixes
 
-    This step returns a copy of a suffix flattened dictionary.
+    This step returns a copy of a suffix flattened dictionary, the keys
+    keep their order.  The keys of provenance, a dict keyed like d, are
+    flattened in place the same way.
     """
-    # dictionary `d_flat' is going to be the mutated copy of `d`
-    d_flat = d.copy()
+    # new names of the suffixed keys, None for the dropped ones
+    new_keys = {}
     for key in d:
-        if key in reserved_keys:
-            continue
-
         if not isinstance(key, tuple):
             continue
 
@@ -26,7 +139,7 @@ def drop_suffixes(d, skipdups=True):
             gen_var_name = key[0]
             if gen_var_name in d and d[gen_var_name] == d[key]:
                 # Drop gen_var_name, use general key with same value
-                d_flat.pop(key)
+                new_keys[key] = None
                 continue
 
             can_drop_all_suffixes_for_this_key = True
@@ -43,6 +156,81 @@ def drop_suffixes(d, skipdups=True):
             # merge suffixes, preserve reverse order of suffixes
             new_key = key[:1] + key[1:][::-1]
             new_key = ''.join((map(str, new_key)))
-        d_flat[new_key] = d_flat.pop(key)
+        new_keys[key] = new_key
 
+    if not new_keys:
+        return d.copy()
+    d_flat = {}
+    flat_provenance = {}
+    for key, value in d.items():
+        if key in new_keys:
+            new_key = new_keys[key]
+            if new_key is None:
+                continue
+        elif key not in d_flat or is_reserved_key(key):
+            # a flattened key wins over a plain one of the same name
+            new_key = key
+        else:
+            continue
+        d_flat[new_key] = value
+        if provenance is not None and key in provenance:
+            flat_provenance[new_key] = provenance[key]
+    if provenance is not None:
+        provenance.clear()
+        provenance.update(flat_provenance)
     return d_flat
+
+
+def dicts_to_json(dicts, indent=None):
+    """
+    Serialize expanded dictionaries to a JSON list.
+
+    Keys are sorted so the output is reproducible.
+
+    :param dicts: Iterable of dictionaries.
+    :param indent: Indentation passed to the JSON encoder.
+    :return: JSON string.
+    """
+    return json.dumps(list(dicts), sort_keys=True, indent=indent)
+
+
+def write_dicts_jsonl(dicts, path_or_file):
+    """
+    Stream expanded dictionaries as JSON Lines, one object per line.
+
+    The dictionaries are written as they are generated, without collecting
+    them first.
+
+    :param dicts: Iterable of dictionaries.
+    :param path_or_file: Path of the output file or an object with write().
+    :return: Number of written dictionaries.
+    """
+    if not hasattr(path_or_file, "write"):
+        with open(path_or_file, "w") as f:
+            return write_dicts_jsonl(dicts, f)
+    count = 0
+    for d in dicts:
+        path_or_file.write(json.dumps(d, sort_keys=True) + "\n")
+        count += 1
+    return count
+
+
+def dicts_to_yaml(dicts):
+    """
+    Serialize expanded dictionaries to a YAML list.
+
+    Requires PyYAML, keys are sorted so the output is reproducible.
+
+    :param dicts: Iterable of dictionaries.
+    :return: YAML string.
+    """
+    import yaml
+
+    class Dumper(yaml.SafeDumper):
+        # dictionaries may share values, e.g. an empty "dep" list
+        def ignore_aliases(self, data):
+            return True
+    Dumper.add_representer(Params, Dumper.represent_dict)
+
+    return yaml.dump(list(dicts), Dumper=Dumper, default_flow_style=False,
+                     sort_keys=True)
@@ -1,16 +1,34 @@
 #!/usr/bin/python
 
 import unittest
+import unittest.mock
+import csv
+import io
+import itertools
+import json
 import os
 import gzip
+import pickle
+import random
+import subprocess
 import sys
+import tempfile
+import threading
 
 # simple magic for using scripts within a source tree
 basedir = os.path.dirname(os.path.dirname(os.path.abspath(__file__)))
 if os.path.isdir(os.path.join(basedir, 'cartconf')):
     sys.path.append(basedir)
 
+try:
+    import yaml
+except ImportError:
+    yaml = None
+
 from cartconf import parser
+from cartconf import tokens
+from cartconf import utils
+from cartconf import constants
 
 
 testdir = os.path.dirname(__file__)
@@ -22,7 +40,284 @@ class NodeTest(unittest.TestCase):
     def test_dump(self):
         node = parser.Node()
         empty_dumped_str = node.dump(0)
-        self.assertRegex(empty_dumped_str, "name:.*\nvariable name:.*\ncontent:.*\nfailed cases:.*\n")
+        self.assertRegex(empty_dumped_str, "name:.*\nvariable name:.*\ncontent:.*\n")
+
+    def test_tree_shape(self):
+        p = parser.Parser()
+        p.parse_string("""
+            variants:
+                - a:
+                    variants:
+                        - x:
+                        - y:
+                - b:
+            """)
+        root = p.node
+        self.assertEqual([[str(n) for n in child.name]
+                          for child in root.children], [["a"], ["b"]])
+        variant_a = root.children[0]
+        self.assertEqual([[str(n) for n in child.name]
+                          for child in variant_a.children], [["x"], ["y"]])
+        self.assertEqual([type(obj) for _, _, obj in variant_a.content],
+                         [parser.LUpdateFileMap, parser.LUpdateFileMap])
+
+    def test_span(self):
+        p = parser.parse_string("""
+            variants:
+                - a.b:
+                    variants:
+                        - @x: a
+            """)
+        variant = p.node.children[0]
+        self.assertEqual(variant.span, parser.Span(3, 19, 22))
+        self.assertEqual(variant.children[0].span, parser.Span(5, 27, 29))
+        self.assertIsNone(p.node.span)
+
+
+class TokensTest(unittest.TestCase):
+
+    def test_exports(self):
+        from cartconf.tokens import LIndent, LDedent  # noqa: F401
+        for name in tokens.__all__:
+            self.assertTrue(hasattr(tokens, name), name)
+            self.assertIs(getattr(parser, name), getattr(tokens, name))
+
+    def test_token_identifier(self):
+        token = tokens.Token("name")
+        self.assertEqual(str(token), "name")
+        self.assertEqual(repr(token), "'name'")
+        self.assertEqual(str(tokens.Token()), "")
+        self.assertEqual(str(tokens.LColon()), ":")
+
+    def test_token_eq_hash(self):
+        self.assertEqual(tokens.Token("name"), tokens.Token("name"))
+        self.assertNotEqual(tokens.Token("name"), tokens.Token("other"))
+        self.assertEqual(len(set([tokens.Token("name"),
+                                  tokens.Token("name")])), 1)
+        self.assertEqual({tokens.Token("name"): 1}[tokens.Token("name")], 1)
+        self.assertEqual(tokens.LIndent(4), tokens.LIndent(4))
+        self.assertNotEqual(tokens.LIndent(4), tokens.LIndent(8))
+
+    def test_indent_length(self):
+        self.assertEqual(tokens.LIndent(10 ** 20).length, 10 ** 20)
+        self.assertEqual(tokens.LEndBlock(-1).length, -1)
+        for length in (-1, 1.5, "4", None, True):
+            self.assertRaises(ValueError, tokens.LIndent, length)
+            self.assertRaises(ValueError, tokens.LDedent, length)
+        self.assertRaises(ValueError, tokens.LEndBlock, -2)
+
+
+class LexerTest(unittest.TestCase):
+
+    def test_indent_dedent(self):
+        reader = parser.StrReader("variants:\n    a:\n        x = 1\n    b:\n")
+        lexer = parser.Lexer(reader)
+        tokens = []
+        line, indent, _ = reader.get_next_line(-1)
+        while line is not None:
+            tokens += lexer.indent_tokens(indent)
+            tokens.append(line)
+            line, indent, _ = reader.get_next_line(-1)
+        tokens += lexer.dedent_all()
+        self.assertEqual([type(t) for t in tokens],
+                         [str, parser.LIndent, str, parser.LIndent, str,
+                          parser.LDedent, str, parser.LDedent])
+        self.assertEqual([t.length for t in tokens
+                          if isinstance(t, parser.LIndent)],
+                         [4, 8, 4, 0])
+
+    def test_tokenize(self):
+        tokens = parser.Lexer(parser.StrReader("foo.bar: baz")).tokenize()
+        self.assertEqual([type(t) for t in tokens],
+                         [parser.LIdentifier, parser.LDot, parser.LIdentifier,
+                          parser.LColon, parser.LWhite, parser.LIdentifier,
+                          parser.LEndL])
+        self.assertEqual([str(t) for t in tokens
+                          if isinstance(t, parser.LIdentifier) and
+                          not isinstance(t, parser.LWhite)],
+                         ["foo", "bar", "baz"])
+
+    def test_lex(self):
+        from cartconf import lex, CartconfError
+        stream = lex("variants:\n    - é:\n        only a..b\n")
+        self.assertEqual([type(t) for t in stream],
+                         [parser.LVariants, parser.LColon, parser.LEndL,
+                          parser.LIndent, parser.LVariant, parser.LWhite,
+                          parser.LIdentifier, parser.LColon, parser.LEndL,
+                          parser.LIndent, parser.LOnly, parser.LIdentifier,
+                          parser.LDot, parser.LDot, parser.LIdentifier,
+                          parser.LEndL, parser.LDedent, parser.LDedent])
+        self.assertEqual([str(t) for t in stream
+                          if type(t) is parser.LIdentifier],
+                         ["é", "a", "b"])
+        self.assertEqual([type(t) for t in lex("x += $y \"z\" # c")],
+                         [parser.LIdentifier, parser.LAppend, parser.LString,
+                          parser.LEndL])
+        self.assertEqual(str(lex("x = ${y}, 'z'")[2]), "${y}, 'z'")
+        combining = lex("- e\u0301:", normalize_identifiers=True)
+        self.assertEqual(str(combining[2]), "é")
+        self.assertEqual(lex("a = 1", spans=True)[0][1],
+                         parser.Span(1, 1, 2))
+        with self.assertRaises(CartconfError) as cm:
+            lex("x = 1\nfoo$bar: baz", filename="my.cfg")
+        self.assertEqual((cm.exception.filename, cm.exception.linenum),
+                         ("my.cfg", 2))
+
+    def test_token_kinds(self):
+        kind = tokens.TokenKind
+        stream = parser.Lexer(parser.StrReader(
+            "variants:\n"
+            "    - @a:\n"
+            "        x += \"1\"\n"
+            "        only b..c, (d=e)\n"
+            "no /c.*/\n")).tokenize()
+        self.assertEqual(
+            [t.kind for t in stream],
+            [kind.VARIANTS, kind.COLON, kind.END_LINE,
+             kind.INDENT, kind.VARIANT, kind.WHITE, kind.DEFAULT,
+             kind.IDENTIFIER, kind.COLON, kind.END_LINE,
+             kind.INDENT, kind.IDENTIFIER, kind.WHITE, kind.APPEND,
+             kind.WHITE, kind.STRING, kind.END_LINE,
+             kind.ONLY, kind.IDENTIFIER, kind.DOT, kind.DOT, kind.IDENTIFIER,
+             kind.COMMA, kind.WHITE, kind.LRBRACKET, kind.IDENTIFIER,
+             kind.SET, kind.IDENTIFIER, kind.RRBRACKET, kind.END_LINE,
+             kind.DEDENT, kind.DEDENT, kind.NO, kind.REGEXP, kind.END_LINE])
+        self.assertIs(tokens.LApplyPreDict.kind, kind.OPERATOR)
+        self.assertIs(tokens.LEndBlock(-1).kind, kind.END_BLOCK)
+
+    def test_token_spans(self):
+        source = ("variants:\n"
+                  "    - a:\n"
+                  "        image_name = \"disk\"  # comment\n"
+                  "        only (x=y).z, /r.*/\n")
+        lines = source.splitlines()
+
+        def text(span):
+            return lines[span.linenum - 1][span.start - 1:span.end - 1]
+        for fast in (False, True):
+            lexer = parser.Lexer(parser.StrReader(source))
+            if fast:
+                lexer.set_fast()
+            stream = lexer.tokenize(spans=True)
+            identifier = [span for t, span in stream if t == "image_name"]
+            self.assertEqual(identifier, [parser.Span(3, 9, 19)])
+            value = [span for t, span in stream
+                     if isinstance(t, parser.LString)]
+            self.assertEqual([text(span) for span in value], ["\"disk\""])
+        spans = [(str(t), text(span))
+                 for t, span in parser.Lexer(parser.StrReader(
+                     source)).tokenize(spans=True)
+                 if span is not None and span.linenum == 4 and
+                 not isinstance(t, (parser.LEndL, parser.LIndent))]
+        self.assertEqual(spans, [("only", "only"), ("(", "("), ("x", "x"),
+                                 ("=", "="), ("y", "y"), (")", ")"),
+                                 (".", "."), ("z", "z"), (",", ","),
+                                 ("", " "), ("r.*", "/r.*/")])
+
+    def test_tokenize_invalid_char(self):
+        lexer = parser.Lexer(parser.StrReader("foo$bar: baz"))
+        self.assertRaises(parser.LexerError, lexer.tokenize)
+
+    def _operator_types(self, line):
+        return [type(t) for t in parser.Lexer(parser.StrReader(line)).tokenize()
+                if isinstance(t, parser.LOperators)]
+
+    def test_assignment_operators(self):
+        self.assertEqual(self._operator_types("x += 1"), [parser.LAppend])
+        self.assertEqual(self._operator_types("x <= 2"), [parser.LPrepend])
+        self.assertEqual(self._operator_types("x = 3"), [parser.LSet])
+
+    def test_regexp_operators(self):
+        self.assertEqual(self._operator_types("x ?= 1"), [parser.LRegExpSet])
+        self.assertEqual(self._operator_types("x ?+= 1"),
+                         [parser.LRegExpAppend])
+        self.assertEqual(self._operator_types("x ?<= 1"),
+                         [parser.LRegExpPrepend])
+        for line in ["x ? 1", "x ?1", "x ?"]:
+            lexer = parser.Lexer(parser.StrReader(line))
+            self.assertRaises(parser.LexerError, lexer.tokenize)
+
+    def test_error_position(self):
+        lexer = parser.Lexer(parser.StrReader("x = 1\nvariants:\n"
+                                              "    - a$b:\n"))
+        with self.assertRaises(parser.LexerError) as cm:
+            lexer.tokenize()
+        self.assertEqual((cm.exception.linenum, cm.exception.column), (3, 8))
+        self.assertIn("<string>:3:8", str(cm.exception))
+        # the errors about a token report the columns of its span
+        with self.assertRaises(parser.LexerError) as cm:
+            parser.lex("x = 1\ny = \"abc\n")
+        self.assertEqual((cm.exception.column, cm.exception.end_column),
+                         (5, 9))
+        self.assertIn("<string>:2:5-8", str(cm.exception))
+        with self.assertRaises(parser.ParserError) as cm:
+            parser.parse_string("variants:\n    - a*b:\n")
+        self.assertIn("<string>:2:7-9", str(cm.exception))
+        self.assertIsInstance(cm.exception, parser.CartconfError)
+
+    def test_dedent_mismatch(self):
+        lexer = parser.Lexer(parser.StrReader(""))
+        lexer.indent_tokens(4)
+        self.assertRaises(parser.LexerError, lexer.indent_tokens, 2)
+
+    def test_indent_policy(self):
+        spaces = "variants:\n    - a:\n        x = 1\n    - b:\n"
+        tabs = spaces.replace("    ", "\t")
+        mixed = "variants:\n    - a:\n    \tx = 1\n"
+
+        def lines(string, policy=None):
+            reader = parser.StrReader(string, policy)
+            return [reader.get_next_line(-1)[:2] for _ in range(4)]
+
+        expanded = lines(tabs)
+        self.assertEqual([indent for _, indent in expanded], [0, 8, 16, 8])
+        self.assertEqual(lines(mixed)[2], ("x = 1", 8))
+        self.assertEqual(lines(spaces, parser.IndentPolicy("spaces")),
+                         lines(spaces))
+        self.assertEqual(lines(tabs, parser.IndentPolicy("tabs")), expanded)
+        self.assertEqual(lines(tabs, parser.IndentPolicy("width", 4)),
+                         lines(spaces))
+        for policy, string, linenum in (("spaces", tabs, 2),
+                                        ("tabs", spaces, 2),
+                                        ("width", mixed, 3)):
+            with self.assertRaises(parser.LexerError) as cm:
+                parser.StrReader(string, parser.IndentPolicy(policy))
+            self.assertEqual(cm.exception.linenum, linenum)
+        self.assertRaises(ValueError, parser.IndentPolicy, "unknown")
+        # comments are not checked
+        parser.StrReader("x = 1\n\t# comment\n",
+                         parser.IndentPolicy("spaces"))
+        p = parser.Parser(indent_policy=parser.IndentPolicy("tabs"))
+        p.parse_string(tabs)
+        self.assertEqual([d["name"] for d in p.get_dicts()], ["a", "b"])
+
+    def test_deep_indent(self):
+        depth = 10 ** 6
+        config = ("variants:\n" + " " * depth + "- a:\n" +
+                  " " * (2 * depth) + "x = 1\n" + " " * depth + "- b:\n")
+        lexer = parser.Lexer(parser.StrReader(config))
+        self.assertEqual(lexer.indent_tokens(depth), [tokens.LIndent(depth)])
+        self.assertEqual(lexer.indent_tokens(0), [tokens.LDedent(0)])
+        self.assertEqual([(d["name"], d.get("x"))
+                          for d in parser.parse_string(config).get_dicts()],
+                         [("a", "1"), ("b", None)])
+
+    def test_line_endings(self):
+        lf = "x = 1\nvariants:\n    - a:\n        y = 2\n    - b:\n        z = 3"
+        crlf = lf.replace("\n", "\r\n") + "\r\n"
+        tokens = parser.Lexer(parser.StrReader(lf)).tokenize()
+        self.assertEqual(parser.Lexer(parser.StrReader(crlf)).tokenize(),
+                         tokens)
+        self.assertEqual([type(t) for t in tokens[-3:]],
+                         [parser.LEndL, parser.LDedent, parser.LDedent])
+        with tempfile.TemporaryDirectory() as tmpdir:
+            filename = os.path.join(tmpdir, "crlf.cfg")
+            with open(filename, "wb") as f:
+                f.write(crlf.rstrip().encode())
+            dicts = list(parser.Parser(filename).get_dicts())
+        self.assertEqual([(d["name"], d["x"], d.get("y"), d.get("z"))
+                          for d in dicts],
+                         [("a", "1", "2", None), ("b", "1", None, "3")])
 
 
 class ParserTest(unittest.TestCase):
@@ -63,6 +358,16 @@ class ParserTest(unittest.TestCase):
 
         self._checkDictionaries(p, dump)
 
+    def _getNames(self, string, **kwargs):
+        p = parser.Parser(**kwargs)
+        p.parse_string(string)
+        return [d["name"] for d in p.get_dicts()]
+
+    def _getValues(self, string, key, **kwargs):
+        p = parser.Parser(**kwargs)
+        p.parse_string(string)
+        return [(d["name"], d.get(key)) for d in p.get_dicts()]
+
     def testSimpleVariant(self):
         self._checkStringConfig("""
             c = abc
@@ -215,152 +520,2455 @@ class ParserTest(unittest.TestCase):
                               ]
                               )
 
-    def testDefaults(self):
-        self._checkStringDump("""
-            variants tests:
-              - wait:
-                   run = "wait"
-                   variants:
-                     - long:
-                        time = short_time
-                     - short: long
-                        time = long_time
-              - test2:
-                   run = "test1"
+    def testDefaults(self):
+        self._checkStringDump("""
+            variants tests:
+              - wait:
+                   run = "wait"
+                   variants:
+                     - long:
+                        time = short_time
+                     - short: long
+                        time = long_time
+              - test2:
+                   run = "test1"
+
+            variants virt_system [ default=  linux ]:
+              - linux:
+              - @windows:
+
+            variants host_os:
+              - linux:
+                   image = linux
+              - @windows:
+                   image = windows
+            """,
+                              [
+                                  {'_name_map_file': {'<string>': '(host_os=windows).(virt_system=linux).(tests=wait).long'},
+                                   '_short_name_map_file': {'<string>': 'windows.linux.wait.long'},
+                                   'dep': [],
+                                   'host_os': 'windows',
+                                   'image': 'windows',
+                                   'name': '(host_os=windows).(virt_system=linux).(tests=wait).long',
+                                   'run': 'wait',
+                                   'shortname': 'wait.long',
+                                   'tests': 'wait',
+                                   'time': 'short_time',
+                                   'virt_system': 'linux'},
+                                  {'_name_map_file': {'<string>': '(host_os=windows).(virt_system=linux).(tests=wait).short'},
+                                   '_short_name_map_file': {'<string>': 'windows.linux.wait.short'},
+                                   'dep': ['(host_os=windows).(virt_system=linux).(tests=wait).long'],
+                                   'host_os': 'windows',
+                                   'image': 'windows',
+                                   'name': '(host_os=windows).(virt_system=linux).(tests=wait).short',
+                                   'run': 'wait',
+                                   'shortname': 'wait.short',
+                                   'tests': 'wait',
+                                   'time': 'long_time',
+                                   'virt_system': 'linux'},
+                                  {'_name_map_file': {'<string>': '(host_os=windows).(virt_system=linux).(tests=test2)'},
+                                   '_short_name_map_file': {'<string>': 'windows.linux.test2'},
+                                   'dep': [],
+                                   'host_os': 'windows',
+                                   'image': 'windows',
+                                   'name': '(host_os=windows).(virt_system=linux).(tests=test2)',
+                                   'run': 'test1',
+                                   'shortname': 'test2',
+                                   'tests': 'test2',
+                                   'virt_system': 'linux'},
+                              ],
+                              True)
+
+        self.assertRaises(parser.ParserError,
+                          self._checkStringDump, """
+                variants tests [default=system2]:
+                  - system1:
+                """,
+                          [],
+                          True)
+
+    def testDefaultDict(self):
+        p = parser.Parser()
+        p.parse_string("""
+            variants:
+                - a:
+                - @b:
+            variants:
+                - c:
+                - d:
+            """)
+        self.assertEqual(p.get_default_dict()["name"], "c.b")
+        self.assertEqual(len(list(p.get_dicts())), 4)
+
+    def testDel(self):
+        self._checkStringDump("""
+            variants tests:
+              - wait:
+                   run = "wait"
+                   variants:
+                     - long:
+                        time = short_time
+                     - short: long
+                        time = long_time
+              - test2:
+                   run = "test1"
+            """,
+                              [
+                                  {'_name_map_file': {'<string>': '(tests=wait).long'},
+                                   '_short_name_map_file': {'<string>': 'wait.long'},
+                                   'dep': [],
+                                   'name': '(tests=wait).long',
+                                   'run': 'wait',
+                                   'shortname': 'wait.long',
+                                   'tests': 'wait',
+                                   'time': 'short_time'},
+                                  {'_name_map_file': {'<string>': '(tests=wait).short'},
+                                   '_short_name_map_file': {'<string>': 'wait.short'},
+                                   'dep': ['(tests=wait).long'],
+                                   'name': '(tests=wait).short',
+                                   'run': 'wait',
+                                   'shortname': 'wait.short',
+                                   'tests': 'wait',
+                                   'time': 'long_time'},
+                                  {'_name_map_file': {'<string>': '(tests=test2)'},
+                                   '_short_name_map_file': {'<string>': 'test2'},
+                                   'dep': [],
+                                   'name': '(tests=test2)',
+                                   'run': 'test1',
+                                   'shortname': 'test2',
+                                   'tests': 'test2'},
+                              ],
+                              True)
+
+        self._checkStringDump("""
+            variants tests:
+              - wait:
+                   run = "wait"
+                   variants:
+                     - long:
+                        time = short_time
+                     - short: long
+                        time = long_time
+              - test2:
+                   run = "test1"
+
+            del time
+            """,
+                              [
+                                  {'_name_map_file': {'<string>': '(tests=wait).long'},
+                                   '_short_name_map_file': {'<string>': 'wait.long'},
+                                   'dep': [],
+                                   'name': '(tests=wait).long',
+                                   'run': 'wait',
+                                   'shortname': 'wait.long',
+                                   'tests': 'wait'},
+                                  {'_name_map_file': {'<string>': '(tests=wait).short'},
+                                   '_short_name_map_file': {'<string>': 'wait.short'},
+                                   'dep': ['(tests=wait).long'],
+                                   'name': '(tests=wait).short',
+                                   'run': 'wait',
+                                   'shortname': 'wait.short',
+                                   'tests': 'wait'},
+                                  {'_name_map_file': {'<string>': '(tests=test2)'},
+                                   '_short_name_map_file': {'<string>': 'test2'},
+                                   'dep': [],
+                                   'name': '(tests=test2)',
+                                   'run': 'test1',
+                                   'shortname': 'test2',
+                                   'tests': 'test2'},
+                              ],
+                              True)
+
+    def testVariantBodies(self):
+        p = parser.Parser()
+        p.parse_string("""
+            variants:
+                - a:
+                    x = 1
+                - b:
+                    x = 2
+                - c:
+                    x = 3
+            """)
+        self.assertEqual([(d["name"], d["x"]) for d in p.get_dicts()],
+                         [("a", "1"), ("b", "2"), ("c", "3")])
+
+    def testCartesianProduct(self):
+        p = parser.Parser()
+        p.parse_string("""
+            variants:
+                - a:
+                    x = a
+                - b:
+                    x = b
+            variants:
+                - c:
+                    y = c
+                - d:
+                    y = d
+                - e:
+                    y = e
+            """)
+        dicts = list(p.get_dicts())
+        self.assertEqual(len(dicts), 6)
+        self.assertEqual([d["name"] for d in dicts],
+                         ["c.a", "c.b", "d.a", "d.b", "e.a", "e.b"])
+        self.assertEqual((dicts[0]["x"], dicts[0]["y"]), ("a", "c"))
+        self.assertEqual((dicts[5]["x"], dicts[5]["y"]), ("b", "e"))
+
+    def testLazyDicts(self):
+        p = parser.Parser()
+        p.parse_string("\n".join("variants:\n" +
+                                 "".join("    - v%d_%d:\n" % (i, j)
+                                         for j in range(10))
+                                 for i in range(7)))
+        dicts = p.get_dicts()
+        first = list(itertools.islice(dicts, 5))
+        dicts.close()
+        self.assertEqual(len(first), 5)
+        self.assertEqual(first[0]["name"],
+                         ".".join("v%d_0" % i for i in reversed(range(7))))
+        # a closed generator must not affect the next expansion
+        self.assertEqual(list(itertools.islice(p.get_dicts(), 5)), first)
+
+    def testValueSpecialChars(self):
+        p = parser.Parser()
+        p.parse_string("""
+            x = a:b, c
+            variants:
+                - a:
+                    y = only: no, x.y
+                    z = 1
+            """)
+        d = next(p.get_dicts())
+        self.assertEqual((d["x"], d["y"], d["z"]), ("a:b, c", "only: no, x.y", "1"))
+        tokens = parser.Lexer(parser.StrReader("x = a:b, c\ny = 2")).tokenize()
+        self.assertEqual([type(t) for t in tokens].count(parser.LEndL), 2)
+
+    def testQuotedValues(self):
+        p = parser.Parser()
+        p.parse_string("""
+            cmd = "echo hello; ls # not a comment" # comment
+            x = 'a: b, c'
+            y = "  padded  "
+            """)
+        d = next(p.get_dicts())
+        self.assertEqual((d["cmd"], d["x"], d["y"]),
+                         ("echo hello; ls # not a comment", "a: b, c",
+                          "  padded  "))
+        for string, column in (("x = \"abc", 5),
+                               ("variants:\n    - a:\n        x = 'a#b", 13)):
+            with self.assertRaises(parser.LexerError) as cm:
+                parser.Parser().parse_string(string)
+            self.assertIn("Unterminated string", str(cm.exception))
+            self.assertEqual(cm.exception.column, column)
+
+    def testNativeDicts(self):
+        p = parser.Parser()
+        p.parse_string("""
+            x = 1
+            variants:
+                - a:
+                    foo = "quoted"
+                - b:
+                    foo = ${x}
+                    foo += 2
+            """)
+        values = []
+        for params in p.get_dicts():
+            self.assertIs(type(params), dict)
+            for key in ("name", "shortname", "x", "foo"):
+                self.assertIs(type(params[key]), str)
+            values.append(params["foo"])
+        self.assertEqual(values, ["quoted", "12"])
+
+    def testTypedParams(self):
+        d = next(parser.parse_string("""
+            timeout = 060
+            mask = 0x1f
+            negative = " -3 "
+            enabled = Yes
+            disabled = off
+            nics = nic1 nic2
+            disks = a.img, , b.img
+            empty = ""
+            """).get_dicts())
+        params = utils.Params(d)
+        self.assertEqual(params, d)
+        self.assertEqual(params.get_int("timeout"), 60)
+        self.assertEqual(params.get_int("mask"), 31)
+        self.assertEqual(params.get_int("negative"), -3)
+        self.assertRaisesRegex(ValueError, "timeout.*integer",
+                               utils.Params(timeout="1m").get_int, "timeout")
+        for value, expected in (("yes", True), ("TRUE", True), ("1", True),
+                                ("no", False), ("False", False),
+                                ("0", False)):
+            self.assertIs(utils.Params(x=value).get_bool("x"), expected)
+        self.assertIs(params.get_bool("enabled"), True)
+        self.assertRaisesRegex(ValueError, "disabled.*boolean",
+                               params.get_bool, "disabled")
+        self.assertEqual(params.get_list("nics"), ["nic1", "nic2"])
+        self.assertEqual(params.get_list("disks", ","), ["a.img", "b.img"])
+        self.assertEqual(params.get_list("empty"), [])
+        self.assertEqual(params.get_list("dep"), [])
+        self.assertRaises(KeyError, params.get_int, "missing")
+        self.assertEqual(params.get_int("missing", 5), 5)
+        self.assertIs(params.get_bool("missing", None), None)
+        self.assertEqual(params.get_list("missing", default=[]), [])
+
+    def testParallelDicts(self):
+        p = parser.Parser()
+        p.parse_string("""
+            variants:
+                - a:
+                    x = a
+                - b:
+                    x = b
+            variants:
+                - c:
+                - d:
+                    y = d
+                - e:
+                    x += e
+            no b..e
+            """)
+        serial = list(p.get_dicts())
+        self.assertEqual(len(serial), 5)
+        self.assertEqual(p.get_dicts_parallel(processes=2), serial)
+        self.assertEqual(p.get_dicts_parallel(processes=1), serial)
+        # joins are expanded serially
+        p.parse_string("join c d\n")
+        self.assertEqual(p.get_dicts_parallel(processes=2),
+                         list(p.get_dicts()))
+
+    def testThreadedDicts(self):
+        tmpdir = tempfile.TemporaryDirectory()
+        self.addCleanup(tmpdir.cleanup)
+        included = os.path.join(tmpdir.name, "included.cfg")
+        with open(included, "w") as f:
+            f.write("y += ${arch}\n")
+        p = parser.Parser(lazy_includes=True)
+        p.parse_string("""
+            x = 1
+            y = 0
+            variants:
+                - a:
+                    foo = a
+                    x += a
+                    suffix _a
+                - b:
+                    include %s with arch=b
+            variants:
+                - c:
+                    bar = c
+                    suffix _c
+                - d:
+                - e:
+            join a..c b
+            """ % included)
+        expected = list(parser.parse_string(p.node.to_config_string())
+                        .get_dicts())
+        results = []
+        errors = []
+        barrier = threading.Barrier(8)
+
+        def expand():
+            try:
+                barrier.wait()
+                if len(results) % 2:
+                    results.append([d for d, _ in
+                                    p.get_dicts_provenance()])
+                else:
+                    results.append(list(p.get_dicts()))
+            except Exception as details:
+                errors.append(details)
+
+        interval = sys.getswitchinterval()
+        sys.setswitchinterval(1e-6)
+        try:
+            threads = [threading.Thread(target=expand) for _ in range(8)]
+            for thread in threads:
+                thread.start()
+            for thread in threads:
+                thread.join()
+        finally:
+            sys.setswitchinterval(interval)
+        self.assertEqual(errors, [])
+        self.assertEqual(len(results), 8)
+        for dicts in results:
+            self.assertEqual(dicts, list(p.get_dicts()))
+        self.assertEqual([d["name"] for d in results[0]],
+                         [d["name"] for d in expected])
+
+    def testThreadedFailedCases(self):
+        p = parser.parse_string("""
+            variants:
+                - a1:
+                    only b1
+                - a2:
+                    no b2, c2
+                - a3:
+            variants:
+                - b1:
+                - b2:
+                - b3:
+            variants:
+                - c1:
+                - c2:
+            """)
+        results = []
+        barrier = threading.Barrier(8)
+
+        def expand():
+            barrier.wait()
+            # the filters of a1 and a2 fail again in several combinations,
+            # the cached failures skip those
+            results.append(list(p.get_dicts()))
+
+        interval = sys.getswitchinterval()
+        sys.setswitchinterval(1e-6)
+        try:
+            threads = [threading.Thread(target=expand) for _ in range(8)]
+            for thread in threads:
+                thread.start()
+            for thread in threads:
+                thread.join()
+        finally:
+            sys.setswitchinterval(interval)
+        self.assertEqual(len(results), 8)
+        expected = list(p.get_dicts())
+        self.assertEqual([d["name"] for d in expected],
+                         ["c1.b1.a1", "c1.b1.a2", "c1.b1.a3", "c1.b2.a3",
+                          "c1.b3.a2", "c1.b3.a3", "c2.b1.a1", "c2.b1.a3",
+                          "c2.b2.a3", "c2.b3.a3"])
+        for dicts in results:
+            self.assertEqual(dicts, expected)
+
+    def testCount(self):
+        space = """
+            variants:
+                - a:
+                - b:
+                - c:
+            variants:
+                - d:
+                    x = 1
+                - e:
+            """
+        for suffix in ("", "only a, b\n", "no a..d\n",
+                       "b:\n    only e\n", "join d e\n"):
+            p = parser.Parser()
+            p.parse_string(space + suffix)
+            self.assertEqual(p.count(), len(list(p.get_dicts())), suffix)
+        p = parser.Parser()
+        p.parse_string(space)
+        with unittest.mock.patch.object(p, "get_dicts") as get_dicts_mock:
+            self.assertEqual(p.count(), 6)
+        get_dicts_mock.assert_not_called()
+
+    def testMaxCombinations(self):
+        def blocks(count):
+            return "".join("""
+            variants:
+                - a%d:
+                - b%d:
+                - c%d:
+            """ % (i, i, i) for i in range(count))
+        p = parser.Parser(max_combinations=1000)
+        p.parse_string(blocks(20))
+        self.assertEqual(p.count(), 3 ** 20)
+        with unittest.mock.patch.object(parser.Parser, "get_dicts_plain") \
+                as plain_mock:
+            with self.assertRaises(parser.ParserError) as cm:
+                next(p.get_dicts())
+        plain_mock.assert_not_called()
+        self.assertIn("expands to %d combinations" % 3 ** 20,
+                      str(cm.exception))
+        # the generator can be used again after the error
+        p.max_combinations = None
+        self.assertEqual(next(p.get_dicts())["name"],
+                         ".".join("a%d" % i for i in reversed(range(20))))
+
+        p = parser.Parser(max_combinations=9)
+        p.parse_string(blocks(2))
+        self.assertEqual(len(list(p.get_dicts())), 9)
+
+        # a filtered configuration is counted before the first dictionary
+        p = parser.Parser(max_combinations=9)
+        p.parse_string(blocks(3) + "no a0\n")
+        dicts = p.get_dicts()
+        self.assertRaisesRegex(parser.ParserError,
+                               "more than 9 combinations", next, dicts)
+        self.assertRaisesRegex(parser.ParserError,
+                               "more than 9 combinations",
+                               p.get_dicts_parallel, processes=2)
+        p.parse_string("only a1\n")
+        self.assertEqual(len(list(p.get_dicts())), 6)
+
+    def testIter(self):
+        p = parser.parse_string("""
+            variants:
+                - a:
+                - b:
+            variants:
+                - x:
+                - y:
+            """)
+        names = ["x.a", "x.b", "y.a", "y.b"]
+        self.assertEqual([d["name"] for d in p], names)
+        self.assertEqual(list(p), list(p.get_dicts()))
+        for _ in range(2):
+            seen = []
+            for d in p:
+                seen.append(d["name"])
+            self.assertEqual(seen, names)
+        # an iteration left early doesn't affect the next ones
+        next(iter(p))
+        self.assertEqual([d["name"] for d in p], names)
+
+    def testDedupDicts(self):
+        config = """
+            x = 1
+            variants:
+                - a:
+                    y = 2
+                - b:
+                    y = 3
+                    del y
+                - c:
+            variants:
+                - d:
+                - e:
+                    y = 2
+            """
+        self.assertEqual(self._getNames(config),
+                         ["d.a", "d.b", "d.c", "e.a", "e.b", "e.c"])
+        self.assertEqual(self._getNames(config, dedup_dicts=True),
+                         ["d.a", "d.b"])
+        # the first dictionary is kept with its names
+        p = parser.Parser(dedup_dicts=True)
+        p.parse_string(config + "no d\n")
+        self.assertEqual([(d["name"], d["shortname"]) for d in p],
+                         [("e.a", "e.a")])
+        # the duplicates are only known once the dictionaries are generated
+        p = parser.Parser(dedup_dicts=True)
+        p.parse_string("""
+            variants:
+                - a:
+                    x = 1
+                - b:
+                    x = 1
+                - c:
+                    x = 2
+            """)
+        self.assertEqual(p.count(), len(list(p.get_dicts())))
+        self.assertEqual(p.count(), 2)
+        # the parallel chunks are deduplicated and limited as a whole
+        p = parser.Parser(dedup_dicts=True)
+        p.parse_string("""
+            variants:
+                - a:
+                - b:
+                - c:
+                - d:
+            x = 1
+            """)
+        self.assertEqual(p.get_dicts_parallel(processes=2),
+                         list(p.get_dicts()))
+        self.assertEqual(len(p.get_dicts_parallel(processes=2)), 1)
+        p = parser.Parser(max_combinations=3)
+        p.parse_string("""
+            variants:
+                - a:
+                - b:
+                - c:
+                - d:
+            a:
+                y = 1
+            """)
+        with self.assertRaises(parser.ParserError):
+            list(p.get_dicts())
+        with self.assertRaises(parser.ParserError):
+            p.get_dicts_parallel(processes=2)
+
+    def testMatrix(self):
+        p = parser.parse_string("""
+            x = 1
+            variants:
+                - a:
+                    y = 2
+                - b: a
+                    z = 3
+                - c: a b
+            b:
+                w = 4
+            """)
+        rows = p.matrix()
+        self.assertEqual(rows[0],
+                         ["dep", "name", "shortname", "w", "x", "y", "z"])
+        self.assertEqual(rows[1:], [["", "a", "a", "", "1", "2", ""],
+                                    ["a", "b", "b", "4", "1", "", "3"],
+                                    ["a b", "c", "c", "", "1", "", ""]])
+        out = io.StringIO()
+        csv.writer(out, lineterminator="\n").writerows(rows)
+        self.assertEqual(out.getvalue().splitlines()[2:],
+                         ["a,b,b,4,1,,3", "a b,c,c,,1,,"])
+
+    def testWithDefaults(self):
+        config = """
+            variants:
+                - a:
+                    vm = vm_a
+                - b:
+                    root = /srv
+                    mem ?= 2048
+            vm_path = ${root}/${vm}
+            """
+        p = parser.Parser().with_defaults({"root": "/var", "vm": "vm0",
+                                            "mem": "1024"})
+        p.parse_string(config)
+        self.assertEqual([(d["name"], d["vm_path"], d["mem"]) for d in p],
+                         [("a", "/var/vm_a", "1024"),
+                          ("b", "/srv/vm0", "2048")])
+        # the defaults may be given once parsed as well
+        p = parser.parse_string(config).with_defaults({"vm": "vm1"})
+        self.assertEqual([d.get("vm") for d in p], ["vm_a", "vm1"])
+        self.assertRaisesRegex(ValueError, "reserved key name",
+                               parser.Parser().with_defaults, {"name": "x"})
+
+    def testPrint(self):
+        p = parser.parse_string("""
+            print "all"
+            variants:
+                - a:
+                    x = 1
+                    print "a with x=${x}"
+                - b:
+                    print in b
+                    variants:
+                        - c:
+                            print 'c of ${name}'
+                        - d:
+            """)
+        self.assertEqual([(d["name"], d[constants.messages_key]) for d in p],
+                         [("a", ["all", "a with x=1"]),
+                          ("b.c", ["all", "in b", "c of b.c"]),
+                          ("b.d", ["all", "in b"])])
+        self.assertIn("print c of ${name}", p.node.to_config_string())
+        self.assertNotIn(constants.messages_key,
+                         next(parser.parse_string("x = 1\n").get_dicts()))
+        # the messages aren't suffixed as the keys of a variant are
+        p = parser.parse_string("""
+            variants:
+                - vm:
+                    print "hi"
+                    x = 1
+                    suffix _vm
+            """)
+        for skipdups in (True, False):
+            d = next(p.get_dicts(skipdups=skipdups))
+            self.assertEqual(["hi"], d[constants.messages_key])
+            self.assertNotIn(constants.messages_key + "_vm", d)
+        self.assertEqual("1", d["x_vm"])
+        d = next(parser.parse_string("print = 1\nprint += x\n").get_dicts())
+        self.assertEqual("1x", d["print"])
+        self.assertNotIn(constants.messages_key, d)
+
+    def testIncrementalParser(self):
+        text = """
+            x = 1
+            variants:
+                - a:
+                    y = 1
+                - b:
+                    y = 2
+            only a
+            z = ${x}_${y}
+            variants:
+                - c:
+                - d:
+            """
+        incremental = parser.IncrementalParser(text, checkpoint_lines=1)
+
+        def edit(old, new):
+            start = incremental.text.index(old)
+            restart = incremental.edit(start, start + len(old), new)
+            full = parser.parse_string(incremental.text)
+            self.assertEqual(list(incremental.parser.get_dicts()),
+                             list(full.get_dicts()))
+            try:
+                tokens = parser.lex(incremental.text, spans=True)
+            except parser.LexerError:
+                self.assertRaises(parser.LexerError, getattr, incremental,
+                                  "tokens")
+            else:
+                self.assertEqual(incremental.tokens, tokens)
+            return restart
+
+        # the lines before the edited statement are not parsed again
+        self.assertEqual(edit("y = 2", "y = 3"), 3)
+        self.assertEqual(edit("only a", "no a"), 8)
+        self.assertEqual([d["z"] for d in incremental.parser.get_dicts()],
+                         ["1_3", "1_3"])
+        self.assertEqual(edit("                - d:\n",
+                              "                - d:\n"
+                              "                    w = 1\n"), 10)
+        self.assertEqual(edit("            z =", "                z ="), 8)
+        self.assertEqual(edit("                    y = 1",
+                              "                        y = 1"), 3)
+        self.assertRaises(parser.ParserError, edit, "no a", "no a:")
+        # the next edit parses again from before the error
+        self.assertEqual(edit("no a:", "no b"), 8)
+        self.assertEqual([d["name"] for d in incremental.parser.get_dicts()],
+                         ["c.a", "d.a"])
+        # the parser accepts a dedent between two levels, lex() doesn't
+        incremental = parser.IncrementalParser(
+            "variants:\n    - a:\n            x = 1\n        y = 2\n")
+        self.assertEqual([(d["x"], d["y"]) for d in
+                          incremental.parser.get_dicts()], [("1", "2")])
+        self.assertRaises(parser.LexerError, getattr, incremental, "tokens")
+        edit("        y = 2", "        y = 3")
+        edit("            x = 1", "          x = 1")
+        edit("        y = 3", "          y = 3")
+        self.assertEqual([(d["x"], d["y"]) for d in
+                          incremental.parser.get_dicts()], [("1", "3")])
+        self.assertTrue(incremental.tokens)
+
+    def testPickle(self):
+        p = parser.Parser()
+        p.parse_string("""
+            x = 1
+            variants:
+                - a:
+                    foo = a
+                    suffix _a
+                - b:
+                    x += b
+            variants:
+                - c:
+                - d:
+            no /b/..d
+            """)
+        expected = list(p.get_dicts())
+        self.assertEqual(list(pickle.loads(pickle.dumps(p)).get_dicts()),
+                         expected)
+        # pickled while expanding
+        dicts = p.get_dicts()
+        next(dicts)
+        copied = pickle.loads(pickle.dumps(p))
+        dicts.close()
+        self.assertEqual(list(copied.get_dicts()), expected)
+
+    def testOutputOrder(self):
+        p = parser.Parser()
+        p.parse_string("""
+            variants first:
+                - a:
+                - b:
+            variants second:
+                - x:
+                - y:
+                - z:
+            """)
+        self.assertEqual([(d["first"], d["second"]) for d in p.get_dicts()],
+                         [("a", "x"), ("b", "x"), ("a", "y"),
+                          ("b", "y"), ("a", "z"), ("b", "z")])
+
+    def testKeyOrder(self):
+        p = parser.Parser()
+        p.parse_string("""
+            b = 1
+            a = ${b}
+            variants:
+                - v:
+                    c = 3
+                    b += 2
+                    suffix _v
+                    d = 4
+            e = 5
+            a ?= 6
+            """)
+        for skipdups, keys in ((True, ["b", "a", "c", "d", "e"]),
+                               (False, ["b_v", "a", "c_v", "d_v", "e"])):
+            d = next(p.get_dicts(skipdups=skipdups))
+            self.assertEqual([key for key in d if key not in
+                              parser.reserved_keys], keys)
+
+    def testNamePath(self):
+        p = parser.Parser()
+        p.parse_string("""
+            variants:
+                - a:
+                    variants:
+                        - x:
+                        - y:
+                - b:
+            variants:
+                - smp1:
+                - smp2:
+            """)
+        names = [d["name"] for d in p.get_dicts()]
+        self.assertIn("smp2.a.y", names)
+        self.assertEqual(len(names), 6)
+        self.assertEqual([str(label) for label in
+                          parser.name_labels("smp2.(tests=a).y")],
+                         ["smp2", "(tests=a)", "y"])
+        self.assertEqual([str(label) for label in
+                          parser.name_labels("b::(tests=a)::a:b", "::")],
+                         ["b", "(tests=a)", "a:b"])
+
+    def testNameSeparator(self):
+        space = """
+            variants tests:
+                - wait:
+                - boot: wait
+            variants:
+                - x:
+                - y:
+            """
+        for sep in "-/":
+            dicts = list(parser.parse_string(space + "only y..boot, x\n",
+                                             name_separator=sep).get_dicts())
+            self.assertEqual([(d["name"], d["shortname"], d["dep"])
+                              for d in dicts],
+                             [("x%s(tests=wait)" % sep, "x%swait" % sep, []),
+                              ("x%s(tests=boot)" % sep, "x%sboot" % sep,
+                               ["x%swait" % sep]),
+                              ("y%s(tests=boot)" % sep, "y%sboot" % sep,
+                               ["y%swait" % sep])])
+            self.assertEqual([d["shortname"] for d in parser.filter_dicts(
+                dicts, only=["x..boot, y"], separator=sep)],
+                ["x%sboot" % sep, "y%sboot" % sep])
+            joined = parser.parse_string(space + "join x y\n",
+                                         name_separator=sep)
+            self.assertEqual(next(joined.get_dicts())["name"],
+                             sep.join(["x", "(tests=wait)",
+                                       "y", "(tests=wait)"]))
+
+    def testFilterDicts(self):
+        space = """
+            variants tests:
+                - wait:
+                    variants:
+                        - long:
+                        - short:
+                - test2:
+            variants:
+                - qcow2:
+                - raw:
+            """
+        p = parser.Parser()
+        p.parse_string(space)
+        dicts = list(p.get_dicts())
+        for only, no in ((["qcow2"], []), ([], ["(tests=wait).long"]),
+                         (["wait..raw, test2"], ["test2..qcow2"]),
+                         (["/q.*/", "short, test2"], [])):
+            statements = "".join("only %s\n" % f for f in only)
+            statements += "".join("no %s\n" % f for f in no)
+            self.assertEqual(list(parser.filter_dicts(dicts, only, no)),
+                             list(parser.parse_string(space + statements)
+                                  .get_dicts()))
+
+    def testExcludeBy(self):
+        p = parser.Parser()
+        p.parse_string("""
+            variants:
+                - x86_64:
+                    arch = x86_64
+                - ppc64:
+                    arch = ppc64
+                - ppc64le:
+                    arch = ${name}
+            variants:
+                - a:
+                - b:
+            """)
+        p.exclude_by("arch", "ppc64")
+        self.assertEqual([d["name"] for d in p.get_dicts()],
+                         ["a.x86_64", "a.ppc64le", "b.x86_64", "b.ppc64le"])
+        p.exclude_by_regexp("arch", r"b\.ppc.*")
+        self.assertEqual([d["name"] for d in p.get_dicts()],
+                         ["a.x86_64", "a.ppc64le", "b.x86_64"])
+        self.assertEqual(p.count(), 3)
+        p.exclude_by("missing", "1")
+        self.assertEqual(p.count(), 3)
+
+    def testIterDictsProgress(self):
+        space = """
+            variants:
+                - a:
+                - b:
+                - c:
+            variants:
+                - d:
+                - e:
+                - f:
+                - g:
+                - h:
+            """
+        calls = []
+        names = [d["name"] for d in parser.parse_string(space).iter_dicts(
+            lambda done, total: calls.append((done, total)), every=4)]
+        self.assertEqual(names, self._getNames(space))
+        self.assertEqual(calls, [(4, 15), (8, 15), (12, 15), (15, 15)])
+        del calls[:]
+        list(parser.parse_string(space + "no a\n").iter_dicts(
+            lambda done, total: calls.append((done, total)), every=5))
+        self.assertEqual(calls, [(5, 10), (10, 10)])
+        self.assertEqual(len(list(parser.parse_string(space).iter_dicts())),
+                         15)
+        self.assertRaises(ValueError, list,
+                          parser.parse_string(space).iter_dicts(print, 0))
+
+    def testSubset(self):
+        space = "".join("""
+            variants:
+                - a%d:
+                - b%d:
+                - c%d:
+                - d%d:
+            """ % ((level,) * 4) for level in range(3))
+        p = parser.parse_string(space)
+        names = [d["name"] for d in p.get_dicts()]
+        sample = [d["name"] for d in p.subset(8, seed=42)]
+        self.assertEqual(len(sample), 8)
+        self.assertEqual(sample, [d["name"] for d in p.subset(8, seed=42)])
+        self.assertEqual(sample, [d["name"] for d in
+                                  parser.parse_string(space).subset(8, 42)])
+        # one pick in every eighth of the space, in order
+        indexes = [names.index(name) for name in sample]
+        self.assertEqual([i * 8 // 64 for i in indexes], list(range(8)))
+        self.assertEqual(sample, ["a2.a1.c0", "a2.d1.c0", "b2.b1.c0",
+                                  "b2.c1.a0", "c2.a1.a0", "c2.d1.d0",
+                                  "d2.a1.c0", "d2.d1.c0"])
+        self.assertNotEqual(sample, [d["name"] for d in p.subset(8, seed=7)])
+        self.assertEqual([d["name"] for d in p.subset(100)], names)
+        self.assertEqual(p.subset(0), [])
+        filtered = parser.parse_string(space + "only a0\n")
+        self.assertEqual(len(filtered.subset(5, seed="ci")), 5)
+
+    def testSamplePaths(self):
+        space = "".join("""
+            variants:
+                - a%d:
+                - b%d:
+                - c%d:
+                - d%d:
+            """ % ((level,) * 4) for level in range(3))
+        p = parser.parse_string(space + """
+            variants guest:
+                - linux:
+                    variants:
+                        - fedora:
+                        - rhel:
+                        - debian:
+                    only fedora, rhel
+                - windows:
+            """)
+        sample = p.sample_paths()
+        names = [d["name"] for d in p.get_dicts()]
+        self.assertLess(len(sample), len(names))
+        self.assertEqual(len(sample), 4)
+        self.assertEqual([d["name"] for d in sample],
+                         [name for name in names
+                          if name in [d["name"] for d in sample]])
+        paths = ["%s%d" % (variant, level) for variant in "abcd"
+                 for level in range(3)]
+        for path in paths + ["linux", "linux.fedora", "linux.rhel",
+                             "windows"]:
+            self.assertTrue(list(parser.filter_dicts(sample, only=[path])),
+                            path)
+        self.assertFalse(list(parser.filter_dicts(sample,
+                                                  only=["linux.debian"])))
+        self.assertEqual([d["x"] for d in
+                          parser.parse_string("x = 1").sample_paths()], ["1"])
+
+    def testPruning(self):
+        space = "x = 1\n" + "".join("""
+            variants:
+                - a%d:
+                - b%d:
+                - c%d:
+                - d%d:
+            """ % ((level,) * 4) for level in range(3))
+        get_dicts_plain = parser.Parser.get_dicts_plain
+        entered = []
+
+        def counting(self, node=None, *args):
+            entered.append(str(node.name[0]) if node.name else None)
+            return get_dicts_plain(self, node, *args)
+
+        def expand(config):
+            del entered[:]
+            p = parser.parse_string(config)
+            with unittest.mock.patch.object(parser.Parser, "get_dicts_plain",
+                                            counting):
+                return [d["name"] for d in p.get_dicts()]
+        names = expand(space)
+        self.assertEqual(entered.count("a0"), 16)
+        self.assertEqual(entered.count("b0"), 16)
+        for statement, only, no in (("only a0", ["a0"], []),
+                                    ("no b0, c0, d0", [], ["b0, c0, d0"]),
+                                    ("only a0, b0", ["a0, b0"], [])):
+            filtered = expand(space + statement + "\n")
+            self.assertEqual(filtered, [d["name"] for d in parser.filter_dicts(
+                parser.parse_string(space).get_dicts(), only, no)])
+            # the other variants of the first block are never entered
+            self.assertEqual(entered.count("a0"), 16)
+            self.assertEqual(entered.count("c0"), 0)
+            self.assertEqual(entered.count("d0"), 0)
+        self.assertEqual(entered.count("b0"), 16)
+        self.assertEqual(len(filtered), 32)
+        self.assertEqual(len(names), 64)
+
+    def testUnicodeIdentifiers(self):
+        composed = "caf\u00e9"
+        decomposed = "cafe\u0301"
+        self.assertNotEqual(composed, decomposed)
+        config = """
+            variants:
+                - %s:
+                    na\u00efve = 1
+                - \u0442\u0435\u0441\u0442:
+            \u00fcber = ${na\u00efve}
+            only %s
+            """
+        self.assertEqual(self._getNames(config % (composed, composed)),
+                         [composed])
+        self.assertEqual(self._getValues(config % (composed, composed),
+                                         "\u00fcber"), [(composed, "1")])
+        # combining marks are part of the identifiers
+        self.assertEqual(self._getNames(config % (decomposed, decomposed)),
+                         [decomposed])
+        self.assertEqual(self._getNames(config % (composed, decomposed)), [])
+        self.assertEqual(self._getNames(config % (composed, decomposed),
+                                        normalize_identifiers=True),
+                         [composed])
+        p = parser.parse_string("%s = x\n" % decomposed,
+                                normalize_identifiers=True)
+        self.assertEqual(next(p.get_dicts())[composed], "x")
+        self.assertRaises(parser.LexerError, parser.parse_string,
+                          "variants:\n    - a\u00b7b:\n")
+
+    def testDependencyGraph(self):
+        p = parser.parse_string("""
+            variants guest:
+                - linux:
+                - windows:
+            variants disk:
+                - qcow2:
+                - raw: linux
+            variants:
+                - x:
+                - y:
+            join x y
+            only (guest=linux)..raw, windows
+            """)
+        graph = p.dependency_graph()
+        self.assertEqual(graph.nodes, ["guest", "disk", "x|y"])
+        self.assertEqual(graph.edges,
+                         [parser.Edge("guest", "disk", "only"),
+                          parser.Edge("disk", "guest", "dep"),
+                          parser.Edge("x|y", "x|y", "join")])
+        self.assertEqual(graph.to_dot(),
+                         "digraph variants {\n"
+                         "    \"guest\";\n"
+                         "    \"disk\";\n"
+                         "    \"x|y\";\n"
+                         "    \"guest\" -> \"disk\" [label=\"only\"];\n"
+                         "    \"disk\" -> \"guest\" [label=\"dep\"];\n"
+                         "    \"x|y\" -> \"x|y\" [label=\"join\"];\n"
+                         "}\n")
+        self.assertEqual(graph.to_edge_list(),
+                         "guest disk only\ndisk guest dep\nx|y x|y join\n")
+        graph = parser.parse_string("only a\n").dependency_graph()
+        self.assertEqual((graph.nodes, graph.edges), ([], []))
+
+    def testGlobFilter(self):
+        space = """
+            variants:
+                - app:
+                - app-debug:
+                - lib-debug:
+                - lib:
+            variants:
+                - x1:
+                - y2:
+            """
+        self.assertEqual(self._getNames(space + "no *-debug\n"),
+                         ["x1.app", "x1.lib", "y2.app", "y2.lib"])
+        # a plain term still matches the whole name only
+        self.assertEqual(self._getNames(space + "no app-debug\n"),
+                         ["x1.app", "x1.lib-debug", "x1.lib",
+                          "y2.app", "y2.lib-debug", "y2.lib"])
+        self.assertEqual(self._getNames(space + "only l*..x?\n"),
+                         ["x1.lib-debug", "x1.lib"])
+        self.assertEqual(self._getNames(space + "only *.lib\n"),
+                         ["x1.lib", "y2.lib"])
+        self.assertEqual(self._getNames(space + "only x?.app*\n"),
+                         ["x1.app", "x1.app-debug"])
+        # in a regular expression the wildcards keep their regexp meaning
+        self.assertEqual(self._getNames(space + "only /a*pp/\n"),
+                         ["x1.app", "y2.app"])
+        self.assertEqual(self._getValues(space + "*-debug:\n" + " " * 16 +
+                                         "debug = yes\n", "debug"),
+                         [("x1.app", None), ("x1.app-debug", "yes"),
+                          ("x1.lib-debug", "yes"), ("x1.lib", None),
+                          ("y2.app", None), ("y2.app-debug", "yes"),
+                          ("y2.lib-debug", "yes"), ("y2.lib", None)])
+        self.assertRaisesRegex(parser.ParserError, "Wildcard",
+                               parser.parse_string,
+                               "variants:\n    - a*:\n")
+
+    def testGlobSegments(self):
+        space = """
+            variants:
+                - a:
+                    variants:
+                        - c:
+                        - b:
+                            variants:
+                                - c:
+                                - d:
+                                    variants:
+                                        - c:
+                                        - e:
+                - x:
+                    variants:
+                        - a:
+                            variants:
+                                - b:
+                                    variants:
+                                        - c:
+                                        - f:
+            """
+        self.assertEqual(self._getNames(space),
+                         ["a.c", "a.b.c", "a.b.d.c", "a.b.d.e",
+                          "x.a.b.c", "x.a.b.f"])
+        # every * stands for exactly one variant of the run
+        self.assertEqual(self._getNames(space + "only a.*.c\n"),
+                         ["a.b.c", "x.a.b.c"])
+        self.assertEqual(self._getNames(space + "only a.c\n"), ["a.c"])
+        self.assertEqual(self._getNames(space + "only a.*.*.c\n"),
+                         ["a.b.d.c"])
+        self.assertEqual(self._getNames(space + "only x.*.b\n"),
+                         ["x.a.b.c", "x.a.b.f"])
+        self.assertEqual(self._getNames(space + "only *.*.*.*\n"),
+                         ["a.b.d.c", "a.b.d.e", "x.a.b.c", "x.a.b.f"])
+        # unlike "..", which allows any variants in between
+        self.assertEqual(self._getNames(space + "only a..c\n"),
+                         ["a.c", "a.b.c", "a.b.d.c", "x.a.b.c"])
+
+    def testFilteredCache(self):
+        p = parser.parse_string("""
+            variants:
+                - a:
+                - b:
+                - c:
+            variants:
+                - x:
+                - y:
+            """)
+        get_dicts_plain = parser.Parser.get_dicts_plain
+        entered = []
+
+        def counting(self, *args):
+            entered.append(args)
+            return get_dicts_plain(self, *args)
+
+        def filtered(*args, **kwargs):
+            del entered[:]
+            with unittest.mock.patch.object(parser.Parser, "get_dicts_plain",
+                                            counting):
+                return [d["name"] for d in p.get_dicts_filtered(*args,
+                                                                **kwargs)]
+        self.assertEqual(filtered(["a, b"], ["y.b"]), ["x.a", "x.b", "y.a"])
+        self.assertTrue(entered)
+        self.assertEqual(filtered(["c"]), ["x.c", "y.c"])
+        self.assertEqual(filtered(no=["x"]), ["y.a", "y.b", "y.c"])
+        self.assertEqual(entered, [])
+        # the stored dicts aren't shared with the caller
+        p.get_dicts_filtered()[0]["name"] = "changed"
+        self.assertEqual(filtered(["x"])[0], "x.a")
+        p.parse_string("only a, c\n")
+        self.assertEqual(filtered(["x"]), ["x.a", "x.c"])
+        self.assertTrue(entered)
+        p.exclude_by("name", "x.c")
+        self.assertEqual(filtered(["x"]), ["x.a"])
+        self.assertTrue(entered)
+        self.assertEqual(filtered(), [d["name"] for d in p.get_dicts()])
+        self.assertEqual(entered, [])
+
+    def testFromJson(self):
+        p = parser.parse_string("""
+            image = base.img
+            variants guest:
+                - linux:
+                    cpus = 2
+                - @windows:
+                    image = win.img
+            variants:
+                - x:
+                    tag = ${image}-x
+                - y:
+            """)
+        exported = p.to_json()
+        loaded = parser.Parser.from_json(exported)
+        self.assertEqual(list(loaded.get_dicts()), list(p.get_dicts()))
+        self.assertEqual(loaded.to_json(), exported)
+        # every dictionary gets a dep list of its own
+        dicts = list(loaded.get_dicts())
+        dicts[0]["dep"].append("z")
+        self.assertEqual([d["dep"] for d in loaded.get_dicts()],
+                         [[], [], [], []])
+        self.assertEqual([d["name"] for d in loaded.get_dicts_filtered(
+                          ["(guest=linux)"], ["y"])], ["x.(guest=linux)"])
+        loaded.only_filter("windows")
+        self.assertEqual([(d["shortname"], d["image"])
+                          for d in loaded.get_dicts()],
+                         [("x", "win.img"), ("y", "win.img")])
+        for text in ("[]", "{}", "[1]", "[{\"a\": \"1\"}]"):
+            self.assertRaises(ValueError, parser.Parser.from_json, text)
+
+    def testPinned(self):
+        p = parser.parse_string("""
+            variants tests:
+                - wait:
+                - boot: wait
+            variants disk:
+                - qcow2:
+                    format = qcow2
+                - raw:
+                    format = raw
+            variants:
+                - x:
+                - y:
+            """)
+        self.assertEqual(len(list(p.get_dicts())), 8)
+        dicts = list(p.get_dicts_pinned({"disk": "raw"}))
+        self.assertEqual([d["name"] for d in dicts],
+                         ["x.(disk=raw).(tests=wait)",
+                          "x.(disk=raw).(tests=boot)",
+                          "y.(disk=raw).(tests=wait)",
+                          "y.(disk=raw).(tests=boot)"])
+        self.assertEqual(set(d["format"] for d in dicts), set(["raw"]))
+        self.assertEqual([d["shortname"] for d in p.get_dicts_pinned(
+            {"disk": "qcow2", "tests": "boot"})],
+            ["x.qcow2.boot", "y.qcow2.boot"])
+        self.assertEqual(len(list(p.get_dicts_pinned({}))), 8)
+        self.assertRaises(ValueError, p.get_dicts_pinned, {"arch": "x86"})
+        self.assertRaises(ValueError, p.get_dicts_pinned, {"disk": "vmdk"})
+        self.assertRaises(ValueError, p.get_dicts_pinned, {None: "x"})
+
+    def testFlat(self):
+        d = parser.parse_flat("""
+            # settings
+            a = 1
+            b = ${a}2
+            a += x
+            c = long \\
+                value
+            c <= "very "
+            del a
+            """)
+        self.assertEqual(d, {"b": "12", "c": "very long value"})
+        self.assertEqual(list(d), ["b", "c"])
+        for statement, error in [("variants:\n    - x:\n", "Variants block"),
+                                 ("only x\n", "Only filter"),
+                                 ("no x\n", "No filter"),
+                                 ("x:\n    a = 2\n", "Conditional block"),
+                                 ("!x:\n    a = 2\n", "Conditional block"),
+                                 ("join x y\n", "Join")]:
+            with self.assertRaises(parser.ParserError) as cm:
+                parser.parse_flat("a = 1\n" + statement)
+            self.assertEqual(cm.exception.msg,
+                             "%s not allowed in a flat configuration" % error)
+            self.assertEqual(cm.exception.linenum, 2)
+        # the plain parser accepts the same statements
+        parser.parse_string("a = 1\nonly x\n")
+
+    def testMerge(self):
+        base = parser.parse_string("""
+            image = base.img
+            mem = 512
+            extra = -a
+            variants guest:
+                - linux:
+                    cpus = 2
+                - windows:
+                    cpus = 4
+            """)
+        overlay = parser.parse_string("""
+            mem = 1024
+            extra += " -b"
+            windows:
+                cpus = 8
+            variants job:
+                - smoke:
+                - full:
+                    mem = 4096
+            """)
+        merged = parser.merge(base, overlay)
+        self.assertEqual([(d["name"], d["image"], d["mem"], d["extra"],
+                           d["cpus"]) for d in merged.get_dicts()],
+                         [("(job=smoke).(guest=linux)", "base.img", "1024",
+                           "-a -b", "2"),
+                          ("(job=smoke).(guest=windows)", "base.img", "1024",
+                           "-a -b", "8"),
+                          ("(job=full).(guest=linux)", "base.img", "4096",
+                           "-a -b", "2"),
+                          ("(job=full).(guest=windows)", "base.img", "4096",
+                           "-a -b", "8")])
+        # the merged parsers are left alone
+        self.assertEqual([(d["name"], d["mem"], d["cpus"])
+                          for d in base.get_dicts()],
+                         [("(guest=linux)", "512", "2"),
+                          ("(guest=windows)", "512", "4")])
+        self.assertEqual(len(list(overlay.get_dicts())), 2)
+
+        # the includes of the overlay are relative to its own file
+        with tempfile.TemporaryDirectory() as tmpdir:
+            os.mkdir(os.path.join(tmpdir, "sub"))
+            for name, text in (("overlay.cfg", "include inc.cfg\n"),
+                               ("inc.cfg", "image = overlay.img\n")):
+                with open(os.path.join(tmpdir, "sub", name), "w") as f:
+                    f.write(text)
+            cwd = os.getcwd()
+            os.chdir(tmpdir)
+            try:
+                overlay = parser.Parser(os.path.join("sub", "overlay.cfg"),
+                                        lazy_includes=True)
+            finally:
+                os.chdir(cwd)
+            lazy_base = parser.Parser(lazy_includes=True)
+            lazy_base.parse_string("image = base.img\n")
+            self.assertEqual([d["image"] for d in
+                              parser.merge(lazy_base, overlay).get_dicts()],
+                             ["overlay.img"])
+        # the options of the overlay would be lost
+        for options in ({"lazy_includes": True}, {"name_separator": "/"},
+                        {"indent_policy": parser.IndentPolicy("spaces")}):
+            overlay = parser.Parser(**options)
+            overlay.parse_string("mem = 1024\n")
+            with self.assertRaises(parser.ParserError) as cm:
+                parser.merge(base, overlay)
+            self.assertIn(list(options)[0], str(cm.exception))
+
+    def testExplain(self):
+        p = parser.parse_string("""
+            mem = 1024
+            cmd = run -m ${mem}
+            variants:
+                - a:
+                    mem = 2048
+                - b:
+            variants:
+                - x:
+                    only a
+                - y:
+            smp2:
+                mem += 0
+            b:
+                never = 1
+            variants:
+                - smp1:
+                - smp2:
+            cpus = ${smp}
+            smp = 2
+            no b..smp2
+            """)
+        lines = p.explain("smp2.x.a")
+        self.assertEqual(lines, [
+            "<string>:12: conditional block 'smp2:' applies",
+            "<string>:10: filter 'only a' passes",
+            "<string>:14: conditional block 'b:' skipped, it doesn't match",
+            "<string>:21: filter 'no b..smp2' passes",
+            "<string>:2: mem = '1024'",
+            "<string>:3: cmd = run -m ${mem} -> cmd = 'run -m 1024'",
+            "<string>:6 (a): mem = '2048'",
+            "<string>:13: mem += 0 -> mem = '20480'",
+            "<string>:19: cpus = ${smp} -> cpus = '${smp}'",
+            "<string>:20: smp = '2'",
+            "substitution: cpus = '${smp}' -> '2'"])
+        lines = p.explain("smp1.y.b")
+        self.assertIn("<string>:15: never = '1'", lines)
+        self.assertIn("<string>:12: conditional block 'smp2:' skipped,"
+                      " it doesn't match", lines)
+        self.assertRaisesRegex(ValueError, "smp2.y.b", p.explain, "smp2.y.b")
+
+    def testProvenance(self):
+        p = parser.parse_string("""
+            timeout = 10
+            mem = 512
+            variants guest:
+                - linux:
+                    timeout = 20
+                    variants:
+                        - old:
+                            timeout = 30
+                        - new:
+                - windows:
+                    mem += 0
+            suffix _vm
+            """)
+        results = list(p.get_dicts_provenance())
+        self.assertEqual([d for d, _ in results], list(p.get_dicts()))
+        provenance = dict((d["name"], prov) for d, prov in results)
+        source = parser.Provenance
+        self.assertEqual(provenance["(guest=linux).old"],
+                         {"timeout": source("<string>", 9,
+                                               "(guest=linux).old"),
+                          "mem": source("<string>", 3, ""),
+                          "guest": source("<string>", 5,
+                                             "(guest=linux)")})
+        self.assertEqual(provenance["(guest=linux).new"]["timeout"],
+                         source("<string>", 6, "(guest=linux)"))
+        self.assertEqual(provenance["(guest=windows)"]["timeout"],
+                         source("<string>", 2, ""))
+        self.assertEqual(provenance["(guest=windows)"]["mem"],
+                         source("<string>", 12, "(guest=windows)"))
+        # the provenance follows the suffixes kept in the keys
+        for d, prov in p.get_dicts_provenance(skipdups=False):
+            self.assertEqual(sorted(prov),
+                             ["guest_vm", "mem_vm", "timeout_vm"])
+            self.assertTrue(set(prov) <= set(d))
+        # plain expansion doesn't carry the provenance
+        self.assertNotIn("_cartconf_provenance", next(p.get_dicts()))
+
+    def testKeys(self):
+        p = parser.parse_string("""
+            x = 1
+            variants:
+                - a:
+                    y = 2
+                - b:
+                    z ~= 3
+                    only c
+            variants:
+                - c:
+                    w += 4
+                - d:
+            a:
+                v <= 5
+                b:
+                    unreachable = 6
+            !d:
+                u = 7
+            del x
+            """)
+        self.assertEqual(p.keys(),
+                         ["_name_map_file", "_short_name_map_file", "dep",
+                          "name", "shortname", "u", "unreachable", "v", "w",
+                          "x", "y", "z"])
+        produced = set(k for d in p.get_dicts() for k in d)
+        self.assertTrue(produced.issubset(p.keys()))
+        self.assertNotIn("unreachable", produced)
+
+    def testLintUnreachable(self):
+        p = parser.parse_string("""
+            variants guest:
+                - linux:
+                    variants:
+                        - fedora:
+                        - rhel:
+                    only fedora
+                - windows:
+            variants:
+                - a.b:
+                - c:
+                    variants:
+                        - x:
+                        - y:
+            no windows..c
+            no c..y
+            """)
+        self.assertEqual(p.lint_unreachable(), ["linux.rhel", "c.y"])
+        p = parser.parse_string("""
+            variants:
+                - a:
+                - b:
+            only a
+            """)
+        self.assertEqual(p.lint_unreachable(), ["b"])
+        self.assertEqual(parser.parse_string("x = 1").lint_unreachable(), [])
+
+    def testVisitor(self):
+        class Collector(parser.Visitor):
+            def __init__(self):
+                super(Collector, self).__init__()
+                self.keys = set(parser.reserved_keys)
+                self.operators = []
+                self.filters = 0
+
+            def visit_assignment(self, key, operator, value):
+                self.keys.add(key)
+                self.operators.append(operator)
+
+            def visit_filter(self, lfilter):
+                self.filters += 1
+
+        p = parser.parse_string("""
+            x = 1
+            variants:
+                - a:
+                    y = 2
+                - b:
+                    z ~= 3
+                    only c
+            variants:
+                - c:
+                    w += 4
+                - d:
+            a:
+                v <= 5
+                b:
+                    unreachable = 6
+            !d:
+                u = 7
+            no a..d
+            """)
+        collector = parser.walk(p, Collector())
+        self.assertEqual(sorted(collector.keys), p.keys())
+        self.assertEqual(sorted(set(collector.operators)),
+                         ["+=", "<=", "=", "~="])
+        self.assertEqual(collector.filters, 2)
+        # a visitor also starts without walk()
+        collector = Collector()
+        collector.visit_node(p.node)
+        self.assertEqual(sorted(collector.keys), p.keys())
+        self.assertIsNone(collector.parser)
+
+    def testVariantNames(self):
+        p = parser.Parser()
+        p.parse_string("""
+            variants guest:
+                - linux:
+                    variants:
+                        - fedora:
+                        - @rhel:
+                - windows:
+            only linux
+            variants:
+                - smp1:
+                - smp2:
+            """)
+        self.assertEqual(p.variant_names(), [
+            {"name": "guest",
+             "variants": [{"name": "linux",
+                           "blocks": [{"name": None,
+                                       "variants": [
+                                           {"name": "fedora", "blocks": []},
+                                           {"name": "rhel", "blocks": []}]}]},
+                          {"name": "windows", "blocks": []}]},
+            {"name": None,
+             "variants": [{"name": "smp1", "blocks": []},
+                          {"name": "smp2", "blocks": []}]}])
+
+    def testExpandLists(self):
+        space = """
+            variants:
+                - a:
+                - b:
+            cpus = 1, 2, 4
+            label = "x, y"
+            msg = hello, world!
+            """
+        literal = [(d["name"], d["cpus"], d["label"], d["msg"])
+                   for d in parser.parse_string(space).get_dicts()]
+        self.assertEqual(literal, [("a", "1, 2, 4", "x, y", "hello, world!"),
+                                   ("b", "1, 2, 4", "x, y", "hello, world!")])
+        p = parser.parse_string(space, expand_lists=True)
+        self.assertEqual([(d["name"], d["cpus"], d["label"], d["msg"])
+                          for d in p.get_dicts()],
+                         [("(cpus=%s).%s" % (cpus, name), cpus, "x, y",
+                           "hello, world!")
+                          for cpus in ("1", "2", "4") for name in "ab"])
+
+    def testEscapedCommas(self):
+        space = """
+            disk = a\\,b, c
+            label = x\\, y
+            """
+        p = parser.parse_string(space, expand_lists=True)
+        self.assertEqual([(d["name"], d["disk"], d["label"])
+                          for d in p.get_dicts()],
+                         [("(disk=a,b)", "a,b", "x, y"),
+                          ("(disk=c)", "c", "x, y")])
+        p.parse_string("only disk=a\\,b\n")
+        self.assertEqual([d["name"] for d in p.get_dicts()], ["(disk=a,b)"])
+        self.assertEqual(self._getNames("""
+            variants:
+                - a\\,b:
+                - c:
+                - d:
+            no a\\,b, d
+            """), ["c"])
+        self.assertEqual([str(t) for t in parser.lex("only a\\,b, c")
+                          if type(t) is parser.LIdentifier],
+                         ["a,b", "c"])
+        # the values are kept as they are without list expansion
+        self.assertEqual(self._getValues(space, "label"),
+                         [("", "x\\, y")])
+        self.assertEqual(utils.split_escaped("a\\,b,,c"), ["a,b", "", "c"])
+        self.assertEqual(utils.Params(x="a\\,b, c").get_list("x", ","),
+                         ["a,b", "c"])
+
+    def testDiff(self):
+        space = """
+            variants:
+                - a:
+                - b:
+            variants:
+                - c:
+                - d:
+            %s
+            """
+        old = parser.parse_string(space % "")
+        new = parser.parse_string(space % "    - e:\n                    x = 1")
+        added, removed = parser.diff(old, new)
+        self.assertEqual(list(added), ["e.a", "e.b"])
+        self.assertEqual(added["e.a"]["x"], "1")
+        self.assertEqual(removed, {})
+        added, removed = parser.diff(list(new.get_dicts()), old)
+        self.assertEqual((added, list(removed)), ({}, ["e.a", "e.b"]))
+
+    def testStrictDuplicates(self):
+        valid = """
+            x = 1
+            x += 2
+            x <= 0
+            variants:
+                - a:
+                    x = 3
+                - b:
+                    y = 1
+                    del y
+                    y = 2
+            a:
+                x = 4
+            """
+        p = parser.parse_string(valid, strict_duplicates=True)
+        self.assertEqual([d["x"] for d in p.get_dicts()], ["4", "012"])
+        duplicate = """
+            variants:
+                - a:
+                    x = 1
+                    y = 2
+                    x = 3
+            """
+        self.assertEqual(next(parser.parse_string(duplicate).get_dicts())["x"],
+                         "3")
+        with self.assertRaises(parser.ParserError) as cm:
+            parser.parse_string(duplicate, strict_duplicates=True)
+        self.assertIn("already set on line 4", str(cm.exception))
+        self.assertEqual(cm.exception.linenum, 6)
+
+    def testStrictKeys(self):
+        config = """
+            variants:
+                - a:
+                    names = x
+                    _name = y
+                    name.* ?= z
+            """
+        d = next(parser.parse_string(config, strict_keys=True).get_dicts())
+        self.assertEqual((d["name"], d["names"], d["_name"]), ("a", "z", "y"))
+        for statement in ("name = x", "shortname += x", "dep <= x",
+                          "_name_map_file ~= x", "_cartconf_id = 1"):
+            text = config.rstrip(" ") + " " * 20 + statement + "\n"
+            d = next(parser.parse_string(text).get_dicts())
+            self.assertEqual(d["name"], "a")
+            with self.assertRaises(parser.ParserError) as cm:
+                parser.parse_string(text, strict_keys=True)
+            self.assertIn("reserved key", str(cm.exception))
+            self.assertEqual(cm.exception.linenum, 7)
+
+    def testNamedValueFilter(self):
+        space = """
+            variants tests:
+                - wait:
+                - test2:
+            variants:
+                - qcow2:
+                - raw:
+            """
+        self.assertEqual(self._getNames(space + "only tests=wait\n"),
+                         ["qcow2.(tests=wait)", "raw.(tests=wait)"])
+        self.assertEqual(self._getNames(space + "only tests = test2\n"),
+                         ["qcow2.(tests=test2)", "raw.(tests=test2)"])
+        self.assertEqual(
+            self._getNames(space + "only tests=wait..raw, tests=test2..qcow2\n"),
+            ["qcow2.(tests=test2)", "raw.(tests=wait)"])
+        self.assertEqual(self._getNames(space + "no tests=wait, raw\n"),
+                         ["qcow2.(tests=test2)"])
+
+    def testFilterGroups(self):
+        space = """
+            variants:
+                - c:
+                - d:
+            variants:
+                - a:
+                - b:
+                - x:
+            """
+        self.assertEqual(self._getNames(space + "only (a, b).c\n"),
+                         ["a.c", "b.c"])
+        self.assertEqual(self._getNames(space + "only a, b.c\n"),
+                         ["a.c", "a.d", "b.c"])
+        self.assertEqual(self._getNames(space + "only ((a, b), x).c\n"),
+                         ["a.c", "b.c", "x.c"])
+        self.assertEqual(self._getNames(space + "only x.(c d), a..d\n"),
+                         ["a.d", "x.c", "x.d"])
+        self.assertEqual(self._getNames(space + "no (a, b).c\n"),
+                         ["a.d", "b.d", "x.c", "x.d"])
+        self.assertEqual(self._getNames(space + "only (a)\n"),
+                         ["a.c", "a.d"])
+        self.assertRaises(parser.ParserError, parser.parse_string,
+                          space + "only (a, b.c\n")
+
+    def testDeepExpansion(self):
+        config = "base = ${l0}\nsize = 9\n"
+        for level in range(6):
+            config += "variants:\n"
+            for value in "ab":
+                config += ("    - %s%d:\n"
+                           "        l%d = %s\n"
+                           "        path += /${l%d}\n"
+                           "        size_max = %d\n" %
+                           (value, level, level, value, level, level))
+        expected = []
+        for choice in itertools.product("ab", repeat=6):
+            # the first block varies fastest
+            choice = choice[::-1]
+            d = dict(("l%d" % i, v) for i, v in enumerate(choice))
+            d["name"] = ".".join("%s%d" % (v, i)
+                                 for i, v in reversed(list(enumerate(choice))))
+            d["base"] = choice[0]
+            d["path"] = "".join("/%s" % v for v in choice)
+            d["size"] = "5"
+            expected.append(d)
+        dicts = [dict((k, d[k]) for k in expected[0])
+                 for d in parser.parse_string(config).get_dicts()]
+        self.assertEqual(dicts, expected)
+
+    def testInterning(self):
+        config = "".join("""
+            variants:
+                - os%d:
+                    image_name = img
+                - arch%d:
+                    image_name = img
+            """ % (i, i) for i in range(3))
+        p = parser.parse_string(config)
+        dicts = list(p.get_dicts())
+        self.assertEqual(len(dicts), 8)
+        keys = set(id(k) for d in dicts for k in d if k == "image_name")
+        self.assertEqual(len(keys), 1)
+        first = parser.Label("os0")
+        second = parser.Label(parser.LIdentifier("os") + "0")
+        self.assertIs(first.name, second.name)
+        named = parser.Label("guest", "os0")
+        self.assertIs(named.name, first.name)
+        self.assertEqual(str(named), "(guest=os0)")
+        self.assertEqual(named, parser.Label("guest", "os0"))
+
+    def testOnlyNo(self):
+        space = """
+            variants:
+                - a:
+                - b:
+            variants:
+                - c:
+                - d:
+            """
+        self.assertEqual(self._getNames(space),
+                         ["c.a", "c.b", "d.a", "d.b"])
+        self.assertEqual(self._getNames(space + "only a\n"),
+                         ["c.a", "d.a"])
+        self.assertEqual(self._getNames(space + "no b\n"),
+                         ["c.a", "d.a"])
+        self.assertEqual(self._getNames(space + "only d..b\n"), ["d.b"])
+        self.assertEqual(self._getNames(space + "no d..b\n"),
+                         ["c.a", "c.b", "d.a"])
+
+    def testOnlyAlternatives(self):
+        space = """
+            variants:
+                - a:
+                - b:
+                - e:
+            variants:
+                - c:
+                - d:
+            """
+        self.assertEqual(self._getNames(space + "only a, b\n"),
+                         ["c.a", "c.b", "d.a", "d.b"])
+        self.assertEqual(self._getNames(space + "only c..a, e\n"),
+                         ["c.a", "c.e", "d.e"])
+        self.assertEqual(self._getNames(space + "only a, e\nonly d\n"),
+                         ["d.a", "d.e"])
+
+    def testSegmentFilter(self):
+        space = """
+            variants:
+                - smp2:
+                - smp2x:
+                - xsmp2.large:
+            variants:
+                - qcow2:
+                - raw:
+            """
+        self.assertEqual(self._getNames(space + "only smp2\n"),
+                         ["qcow2.smp2", "raw.smp2"])
+        self.assertEqual(self._getNames(space + "only large\n"),
+                         ["qcow2.xsmp2.large", "raw.xsmp2.large"])
+        self.assertEqual(self._getNames(space + "only mp2\n"), [])
+        self.assertEqual(self._getNames(space + "only smp2x..raw\n"),
+                         ["raw.smp2x"])
+
+    def testRegExpFilter(self):
+        space = """
+            variants:
+                - smp1:
+                - smp2:
+            variants:
+                - qcow2-smp2:
+                - raw:
+            """
+        self.assertEqual(self._getNames(space + "only /.*-smp2/\n"),
+                         ["qcow2-smp2.smp1", "qcow2-smp2.smp2"])
+        self.assertEqual(self._getNames(space + "no /smp./..raw\n"),
+                         ["qcow2-smp2.smp1", "qcow2-smp2.smp2"])
+        with self.assertRaises(parser.ParserError) as cm:
+            self._getNames(space + "only /smp(/\n")
+        self.assertIn("smp(", str(cm.exception))
+        self.assertEqual(cm.exception.linenum, 8)
+
+    def testRegExpCompiledOnce(self):
+        p = parser.Parser()
+        p.parse_string("""
+            variants:
+                - smp1:
+                - smp2:
+                - smp4:
+            variants:
+                - qcow2:
+                - raw:
+            key_a = 1
+            key_b = 2
+            only /smp[12]/
+            key_.* ?= 3
+            del key_b
+            """)
+        labels = [label for _, _, op in p.node.content
+                  if isinstance(op, parser.OnlyFilter)
+                  for group in op.filter
+                  for block in group
+                  for label in block]
+        self.assertEqual(len(labels), 1)
+        regexp = labels[0].regexp
+        with unittest.mock.patch("re.compile") as compile_mock:
+            for _ in range(3):
+                self.assertEqual(len(list(p.get_dicts())), 4)
+        compile_mock.assert_not_called()
+        self.assertIs(labels[0].regexp, regexp)
+
+    def testAppendPrependInheritance(self):
+        p = parser.Parser()
+        p.parse_string("""
+            x = a
+            variants:
+                - app:
+                    x += b
+                - pre:
+                    x <= b
+                - unset:
+                    y += b
+                    z <= b
+            """)
+        dicts = list(p.get_dicts())
+        self.assertEqual([d["x"] for d in dicts], ["ab", "ba", "a"])
+        self.assertEqual((dicts[2]["y"], dicts[2]["z"]), ("b", "b"))
+
+    def testScopedAppend(self):
+        # parent set, child append
+        self.assertEqual(self._getValues("""
+            x = p
+            variants:
+                - a:
+                    x += c
+                - b:
+            """, "x"), [("a", "pc"), ("b", "p")])
+        # parent appends around nested blocks, the statements following a
+        # block apply after it
+        self.assertEqual(self._getValues("""
+            x += p
+            variants:
+                - a:
+                    x += c
+                    variants:
+                        - i:
+                            x += g
+                        - j:
+                    x += d
+                - b:
+            x += q
+            """, "x"), [("a.i", "pcgdq"), ("a.j", "pcdq"), ("b", "pq")])
+        # the block declared first is the outer scope, a sibling filtered
+        # out with no contributes nothing
+        self.assertEqual(self._getValues("""
+            x = p
+            variants:
+                - a:
+                    x += c
+                - b:
+                    x += e
+            variants:
+                - c:
+                    x += f
+                    no a
+                - d:
+                    x += h
+            """, "x"), [("c.b", "pef"), ("d.a", "pch"), ("d.b", "peh")])
+
+    def testAnonymousVariants(self):
+        space = """
+            variants:
+                - a:
+                    x = 1
+                -:
+                    x = 2
+                -
+            variants guest:
+                - linux:
+                -: a
+                    y = anon
+            """
+        p = parser.parse_string(space)
+        self.assertEqual(p.count(), 6)
+        self.assertEqual([(d["name"], d.get("x"), d.get("y"), d.get("guest"),
+                           d["dep"]) for d in p.get_dicts()],
+                         [("(guest=linux).a", "1", None, "linux", []),
+                          ("(guest=linux)", "2", None, "linux", []),
+                          ("(guest=linux)", None, None, "linux", []),
+                          ("a", "1", "anon", None, ["a"]),
+                          ("", "2", "anon", None, ["a"]),
+                          ("", None, "anon", None, ["a"])])
+        # the filters only see the named variants
+        self.assertEqual(self._getNames(space + "no a\n"),
+                         ["(guest=linux)", "(guest=linux)", "", ""])
+        self.assertEqual(self._getNames(space + "only linux\n"),
+                         ["(guest=linux).a", "(guest=linux)",
+                          "(guest=linux)"])
+        self.assertEqual(self._getNames(space + "no linux..a\n"),
+                         ["(guest=linux)", "(guest=linux)", "a", "", ""])
+
+    def testInlineConditions(self):
+        space = """
+            mem = 2048 if smp2 else 1024
+            cmd = "run if smp2 else stop"
+            variants:
+                - smp1:
+                - smp2:
+            variants:
+                - x:
+                - y:
+            disk = fast if smp1..x else slow if smp2 else none
+            """
+        p = parser.Parser(inline_conditions=True)
+        p.parse_string(space)
+        self.assertEqual([(d["name"], d["mem"], d["disk"], d["cmd"])
+                          for d in p.get_dicts()],
+                         [("x.smp1", "1024", "fast", "run if smp2 else stop"),
+                          ("x.smp2", "2048", "slow", "run if smp2 else stop"),
+                          ("y.smp1", "1024", "none", "run if smp2 else stop"),
+                          ("y.smp2", "2048", "slow", "run if smp2 else stop")])
+        # values are literal without the option
+        self.assertEqual(self._getValues(space, "mem")[0],
+                         ("x.smp1", "2048 if smp2 else 1024"))
+        p = parser.Parser(inline_conditions=True)
+        self.assertRaisesRegex(parser.ParserError, "Invalid condition",
+                               p.parse_string, "x = 1 if (a else 2\n")
+
+    def testVariantsTemplate(self):
+        space = """
+            variants @disk:
+                - qcow2:
+                    format = qcow2
+                - raw:
+                    format = raw
+            variants image: @disk
+            variants backing: @disk
+                - none:
+                    format = none
+            """
+        self.assertEqual(self._getValues(space, "format"),
+                         [("(backing=qcow2).(image=qcow2)", "qcow2"),
+                          ("(backing=qcow2).(image=raw)", "qcow2"),
+                          ("(backing=raw).(image=qcow2)", "raw"),
+                          ("(backing=raw).(image=raw)", "raw"),
+                          ("(backing=none).(image=qcow2)", "none"),
+                          ("(backing=none).(image=raw)", "none")])
+        # every reference parses the template again in its own context
+        nested = """
+            variants @size:
+                - small:
+                    mem = ${base}
+                - big:
+            variants:
+                - a:
+                    base = 1
+                    variants: @size
+                - b:
+                    base = 2
+                    variants: @size
+            """
+        self.assertEqual(self._getValues(nested, "mem"),
+                         [("a.small", "1"), ("a.big", None),
+                          ("b.small", "2"), ("b.big", None)])
+        # the statements following a template are lexed as usual
+        self.assertEqual(self._getValues("variants @t:\n    - a:\n"
+                                         "y = 1\nx = ${y}\n", "x"),
+                         [("", "1")])
+        for config, error in (
+                ("variants @t:\n    - a:\n        variants: @t\n",
+                 "Recursive reference"),
+                ("variants @t:\n    - a:\n        variants: @u\n",
+                 "Unknown variants template u"),
+                ("variants: @t\n", "Unknown variants template t"),
+                ("variants @t:\n    - a:\nvariants @t:\n    - b:\n",
+                 "already defined"),
+                ("variants @t:\nx = 1\n", "without any variant"),
+                ("variants @t [x]:\n    - a:\n", "variants @name:")):
+            self.assertRaisesRegex(parser.ParserError, error,
+                                   parser.parse_string, config)
+
+    def testRegExpDel(self):
+        self.assertEqual(self._getValues("""
+            cmd = qemu -m 1024 -smp 2
+            opt = -cpu host
+            variants:
+                - a:
+                    cmd -= \\s*-smp \\d+
+                    opt -= ${cpu}
+                    cpu = -cpu
+                - b:
+                    missing -= x
+            """, "cmd"), [("a", "qemu -m 1024"), ("b", "qemu -m 1024 -smp 2")])
+        p = parser.parse_string("""
+            opt = -cpu host
+            cpu = -cpu
+            opt -= ${cpu}\\s
+            a-b = x-y
+            a-b -= -
+            """)
+        d = next(p.get_dicts())
+        self.assertEqual((d["opt"], d["a-b"]), ("host", "xy"))
+        self.assertNotIn("missing", next(parser.parse_string(
+            "missing -= x\n").get_dicts()))
+        stream = parser.Lexer(parser.StrReader("a-b -= c")).tokenize()
+        self.assertEqual([type(t) for t in stream],
+                         [parser.LIdentifier, parser.LWhite,
+                          parser.LRegExpDel, parser.LWhite,
+                          parser.LIdentifier, parser.LEndL])
+        self.assertRaisesRegex(parser.ParserError, "Invalid regular",
+                               parser.parse_string, "x -= (\n")
+        # a key ending with "-" needs a space before the operator
+        d = next(parser.parse_string("key-=value\nk- = v\n").get_dicts())
+        self.assertEqual([(k, v) for k, v in d.items()
+                          if k not in parser.reserved_keys], [("k-", "v")])
+        self.assertEqual(self._getValues("key = a-value\nkey-=value\n",
+                                         "key"), [("", "a-")])
+
+    def testColonsInValues(self):
+        config = """
+            variants x:
+                - a:
+                - b:
+            url = http://host:8080/path
+            y =:c
+            (x=a): v = 1:2
+            (x=b):
+                v = 3
+            !(x=a): w = 4
+            a: z = u:v
+            """
+        self.assertEqual(self._getValues(config, "url"),
+                         [("(x=a)", "http://host:8080/path"),
+                          ("(x=b)", "http://host:8080/path")])
+        self.assertEqual(self._getValues(config, "y"),
+                         [("(x=a)", ":c"), ("(x=b)", ":c")])
+        self.assertEqual(self._getValues(config, "v"),
+                         [("(x=a)", "1:2"), ("(x=b)", "3")])
+        self.assertEqual(self._getValues(config, "w"),
+                         [("(x=a)", None), ("(x=b)", "4")])
+        self.assertEqual(self._getValues(config, "z"),
+                         [("(x=a)", "u:v"), ("(x=b)", None)])
+        self.assertRaisesRegex(parser.ParserError, "Expected \":\" after",
+                               parser.parse_string, "(x=a)\n")
+
+    def testJoinCount(self):
+        space = """
+            variants:
+                - x:
+                    foo = x
+                    suffix _x
+                - y:
+                    foo = y
+                    suffix _y
+                - z:
+                    foo = z
+                    suffix _z
+            """
+        self.assertEqual(len(self._getNames(space)), 3)
+        # each join term selects one variant, the selections are multiplied
+        # together into a single combination instead of separate ones
+        self.assertEqual(self._getNames(space + "join x y\n"), ["x.y"])
+        self.assertEqual(self._getNames(space + "join x y z\n"), ["x.y.z"])
+
+    def testNamedVariantKey(self):
+        p = parser.Parser()
+        p.parse_string("""
+            variants guest:
+                - linux:
+                - windows:
+                    guest = win10
+            """)
+        self.assertEqual([d["guest"] for d in p.get_dicts()],
+                         ["linux", "win10"])
 
-            variants virt_system [ default=  linux ]:
-              - linux:
-              - @windows:
+    def testComments(self):
+        p = parser.Parser()
+        p.parse_string("""
+            # full line comment
+            a = 1 # trailing comment
+            b = "quoted # value" # comment
+            c = escaped \\# value
+            d = url#fragment
+            """)
+        d = list(p.get_dicts())[0]
+        self.assertEqual((d["a"], d["b"], d["c"], d["d"]),
+                         ("1", "quoted # value", "escaped # value",
+                          "url#fragment"))
 
-            variants host_os:
-              - linux:
-                   image = linux
-              - @windows:
-                   image = windows
-            """,
-                              [
-                                  {'_name_map_file': {'<string>': '(host_os=windows).(virt_system=linux).(tests=wait).long'},
-                                   '_short_name_map_file': {'<string>': 'windows.linux.wait.long'},
-                                   'dep': [],
-                                   'host_os': 'windows',
-                                   'image': 'windows',
-                                   'name': '(host_os=windows).(virt_system=linux).(tests=wait).long',
-                                   'run': 'wait',
-                                   'shortname': 'wait.long',
-                                   'tests': 'wait',
-                                   'time': 'short_time',
-                                   'virt_system': 'linux'},
-                                  {'_name_map_file': {'<string>': '(host_os=windows).(virt_system=linux).(tests=wait).short'},
-                                   '_short_name_map_file': {'<string>': 'windows.linux.wait.short'},
-                                   'dep': ['(host_os=windows).(virt_system=linux).(tests=wait).long'],
-                                   'host_os': 'windows',
-                                   'image': 'windows',
-                                   'name': '(host_os=windows).(virt_system=linux).(tests=wait).short',
-                                   'run': 'wait',
-                                   'shortname': 'wait.short',
-                                   'tests': 'wait',
-                                   'time': 'long_time',
-                                   'virt_system': 'linux'},
-                                  {'_name_map_file': {'<string>': '(host_os=windows).(virt_system=linux).(tests=test2)'},
-                                   '_short_name_map_file': {'<string>': 'windows.linux.test2'},
-                                   'dep': [],
-                                   'host_os': 'windows',
-                                   'image': 'windows',
-                                   'name': '(host_os=windows).(virt_system=linux).(tests=test2)',
-                                   'run': 'test1',
-                                   'shortname': 'test2',
-                                   'tests': 'test2',
-                                   'virt_system': 'linux'},
-                              ],
-                              True)
+    def testEscapes(self):
+        p = parser.Parser()
+        p.parse_string(r"""
+            newline = a\nb
+            tab = "a\tb"
+            backslash = a\\nb
+            hash = a \# b # comment
+            unknown = a\qb
+            """)
+        d = next(p.get_dicts())
+        self.assertEqual(d["newline"], "a\nb")
+        self.assertEqual(d["tab"], "a\tb")
+        self.assertEqual(d["backslash"], "a\\nb")
+        self.assertEqual(d["hash"], "a # b")
+        self.assertEqual(d["unknown"], "a\\qb")
 
-        self.assertRaises(parser.ParserError,
-                          self._checkStringDump, """
-                variants tests [default=system2]:
-                  - system1:
-                """,
-                          [],
-                          True)
+    def testLineContinuation(self):
+        p = parser.Parser()
+        p.parse_string("""
+            variants:
+                - a:
+                    cmd = first \\
+                          second \\
+                    third
+                    path = c:\\\\
+            """)
+        d = list(p.get_dicts())[0]
+        self.assertEqual(d["cmd"], "first second third")
+        # an escaped backslash does not continue the line
+        self.assertEqual(d["path"], "c:\\")
+        tokens = parser.Lexer(parser.StrReader("x = a \\\n  b")).tokenize()
+        self.assertEqual(len([t for t in tokens
+                              if isinstance(t, parser.LEndL)]), 1)
 
-    def testDel(self):
-        self._checkStringDump("""
+    def testToJson(self):
+        p = parser.Parser()
+        p.parse_string("""
+            z = 1
+            variants:
+                - a:
+                    b = 2
+                - c:
+            """)
+        self.assertEqual(p.to_json(),
+                         '[{"_name_map_file": {"<string>": "a"}, '
+                         '"_short_name_map_file": {"<string>": "a"}, '
+                         '"b": "2", "dep": [], "name": "a", '
+                         '"shortname": "a", "z": "1"}, '
+                         '{"_name_map_file": {"<string>": "c"}, '
+                         '"_short_name_map_file": {"<string>": "c"}, '
+                         '"dep": [], "name": "c", '
+                         '"shortname": "c", "z": "1"}]')
+        self.assertEqual(p.to_json(), p.to_json())
+
+    def testWriteJsonl(self):
+        p = parser.Parser()
+        p.parse_string("".join("variants:\n" +
+                               "".join("    - v%d_%d:\n" % (i, j)
+                                       for j in range(10))
+                               for i in range(4)))
+        with tempfile.TemporaryDirectory() as tmpdir:
+            filename = os.path.join(tmpdir, "dicts.jsonl")
+            self.assertEqual(p.write_jsonl(filename), 10000)
+            with open(filename) as f:
+                lines = f.readlines()
+        self.assertEqual(len(lines), 10000)
+        self.assertEqual(json.loads(lines[0]), next(p.get_dicts()))
+
+    @unittest.skipUnless(yaml, "PyYAML is not installed")
+    def testToYaml(self):
+        p = parser.Parser()
+        p.parse_string("""
+            z = 1
+            variants:
+                - a:
+                    b = 2
+                - c:
+            """)
+        self.assertEqual(yaml.safe_load(p.to_yaml()), list(p.get_dicts()))
+        self.assertTrue(p.to_yaml().startswith("- _name_map_file:"))
+
+    def testToConfigString(self):
+        p = parser.Parser()
+        p.parse_string("""
+            x = 1
             variants tests:
-              - wait:
-                   run = "wait"
-                   variants:
-                     - long:
-                        time = short_time
-                     - short: long
-                        time = long_time
-              - test2:
-                   run = "test1"
-            """,
-                              [
-                                  {'_name_map_file': {'<string>': '(tests=wait).long'},
-                                   '_short_name_map_file': {'<string>': 'wait.long'},
-                                   'dep': [],
-                                   'name': '(tests=wait).long',
-                                   'run': 'wait',
-                                   'shortname': 'wait.long',
-                                   'tests': 'wait',
-                                   'time': 'short_time'},
-                                  {'_name_map_file': {'<string>': '(tests=wait).short'},
-                                   '_short_name_map_file': {'<string>': 'wait.short'},
-                                   'dep': ['(tests=wait).long'],
-                                   'name': '(tests=wait).short',
-                                   'run': 'wait',
-                                   'shortname': 'wait.short',
-                                   'tests': 'wait',
-                                   'time': 'long_time'},
-                                  {'_name_map_file': {'<string>': '(tests=test2)'},
-                                   '_short_name_map_file': {'<string>': 'test2'},
-                                   'dep': [],
-                                   'name': '(tests=test2)',
-                                   'run': 'test1',
-                                   'shortname': 'test2',
-                                   'tests': 'test2'},
-                              ],
-                              True)
+                - a:
+                    foo = a
+                    variants:
+                        - c:
+                            q = "  spaced # value "
+                        - @d: c
+                            q = a\\tb
+                - b:
+                    only c
+                    suffix _b
+            y ?= 2
+            del z
+            b:
+                z = 3
+            !a..d, b:
+                w += 4
+            variants:
+                - j1:
+                - j2:
+            join j1 j2
+            """)
+        text = p.node.to_config_string()
+        reparsed = parser.Parser()
+        reparsed.parse_string(text)
+        self.assertEqual(list(reparsed.get_dicts()), list(p.get_dicts()))
+        self.assertEqual(reparsed.node.to_config_string(), text)
+        self.assertIn("variants tests:\n    - a:\n        foo = a\n", text)
 
-        self._checkStringDump("""
+    def testConfigBuilder(self):
+        body = parser.ConfigBuilder().set("y", "2")
+        body.add_variants(None, ["c", "d"])
+        builder = parser.ConfigBuilder().set("x", "1")
+        builder.add_variants("tests", ["a", ("b", body)])
+        builder.set("z", "${x}")
+        p = parser.Parser()
+        p.parse_string("""
+            x = 1
             variants tests:
-              - wait:
-                   run = "wait"
-                   variants:
-                     - long:
-                        time = short_time
-                     - short: long
-                        time = long_time
-              - test2:
-                   run = "test1"
+                - a:
+                - b:
+                    y = 2
+                    variants:
+                        - c:
+                        - d:
+            z = ${x}
+            """)
+        self.assertEqual(list(builder.build().get_dicts()),
+                         list(p.get_dicts()))
 
-            del time
+    def testParseFunctions(self):
+        import cartconf
+        config = "x = 1\nvariants:\n    - a:\n    - b:\n        x = 2\n"
+        dicts = list(cartconf.parse_string(config).get_dicts())
+        self.assertEqual([(d["name"], d["x"]) for d in dicts],
+                         [("a", "1"), ("b", "2")])
+        with tempfile.TemporaryDirectory() as tmpdir:
+            filename = os.path.join(tmpdir, "test.cfg")
+            with open(filename, "w") as f:
+                f.write(config)
+            dicts = list(cartconf.parse_file(filename).get_dicts())
+        self.assertEqual([(d["name"], d["x"]) for d in dicts],
+                         [("a", "1"), ("b", "2")])
+
+    def testFilterPrecedence(self):
+        space = """
+            variants:
+                - a:
+                - b:
+            """
+        # an inner only can't bring back what an outer no dropped
+        self.assertEqual(self._getNames(space + """
+            no a
+            variants:
+                - x:
+                    only a
+                - y:
+            """), ["y.b"])
+        self.assertEqual(self._getNames(space + """
+            only a
+            variants:
+                - x:
+                    no b
+                - y:
+                    no a
+            """), ["x.a"])
+        self.assertEqual(self._getNames(space + """
+            variants:
+                - x:
+                    only a
+                    variants:
+                        - deep:
+                            no a
+                        - other:
+            """), ["x.other.a"])
+
+    def testMaxDepth(self):
+        def nested(depth):
+            return "".join("%svariants:\n%s    - v%d:\n" % (" " * 8 * i,
+                                                           " " * 8 * i, i)
+                           for i in range(depth))
+
+        with self.assertRaises(parser.ParserError) as cm:
+            parser.Parser().parse_string(nested(300))
+        self.assertIn("Maximum nesting depth 200", str(cm.exception))
+        self.assertEqual(cm.exception.linenum, 400)
+        p = parser.Parser()
+        p.parse_string("".join("variants:\n    - v%d:\n" % i
+                               for i in range(300)))
+        self.assertRaises(parser.ParserError, list, p.get_dicts())
+        p = parser.Parser(max_depth=20)
+        p.parse_string(nested(19))
+        self.assertEqual(len(list(p.get_dicts())), 1)
+        self.assertRaises(parser.ParserError, p.parse_string, nested(21))
+
+    def testEmptyVariants(self):
+        p = parser.Parser()
+        with self.assertRaises(parser.ParserError) as cm:
+            p.parse_string("""
+                x = 1
+                variants:
+                """)
+        self.assertEqual(cm.exception.linenum, 3)
+
+    def testDelMissingKey(self):
+        self._checkStringDump("""
+            foo = 1
+            del foo
+            del bar
             """,
                               [
-                                  {'_name_map_file': {'<string>': '(tests=wait).long'},
-                                   '_short_name_map_file': {'<string>': 'wait.long'},
-                                   'dep': [],
-                                   'name': '(tests=wait).long',
-                                   'run': 'wait',
-                                   'shortname': 'wait.long',
-                                   'tests': 'wait'},
-                                  {'_name_map_file': {'<string>': '(tests=wait).short'},
-                                   '_short_name_map_file': {'<string>': 'wait.short'},
-                                   'dep': ['(tests=wait).long'],
-                                   'name': '(tests=wait).short',
-                                   'run': 'wait',
-                                   'shortname': 'wait.short',
-                                   'tests': 'wait'},
-                                  {'_name_map_file': {'<string>': '(tests=test2)'},
-                                   '_short_name_map_file': {'<string>': 'test2'},
-                                   'dep': [],
-                                   'name': '(tests=test2)',
-                                   'run': 'test1',
-                                   'shortname': 'test2',
-                                   'tests': 'test2'},
+                                  {'dep': [],
+                                   'name': '',
+                                   'shortname': ''},
                               ],
                               True)
 
+    def testSuffixScope(self):
+        p = parser.Parser()
+        p.parse_string("""
+            x = 1
+            z = 3
+            variants:
+                - a:
+                    foo = a
+                    z = 4
+                    suffix _a
+                - b:
+                    foo = b
+            y = 2
+            """)
+        dicts = list(p.get_dicts(skipdups=False))
+        self.assertEqual(["a", "b"], [d["name"] for d in dicts])
+        keys = [sorted(k for k in d if not k.startswith("_")) for d in dicts]
+        self.assertEqual(["dep", "foo_a", "name", "shortname",
+                          "x", "y", "z_a"], keys[0])
+        self.assertEqual(["dep", "foo", "name", "shortname",
+                          "x", "y", "z"], keys[1])
+        self.assertEqual("4", dicts[0]["z_a"])
+        # keys set again to the value they had are still set in the block
+        dicts = list(parser.parse_string("""
+            x = 1
+            variants:
+                - a:
+                    x = 1
+                    z = 1
+                    suffix _s
+            """).get_dicts(skipdups=False))
+        self.assertEqual(("1", "1"), (dicts[0]["x_s"], dicts[0]["z_s"]))
+        self.assertNotIn("x", dicts[0])
+
+    def testPrefix(self):
+        p = parser.parse_string("""
+            x = 1
+            y = a
+            prefix ns_
+            variants:
+                - one:
+                    x = 2
+                    y += b
+                    prefix in_
+                    z = 3
+                - two:
+                    y <= c
+                    x ?= 5
+            w = 4
+            prefix = /usr
+            """)
+        dicts = list(p.get_dicts())
+        keys = [dict((k, v) for k, v in d.items() if k not in
+                     parser.reserved_keys) for d in dicts]
+        self.assertEqual({"x": "1", "y": "a", "ns_x": "2", "ns_y": "b",
+                          "ns_in_z": "3", "ns_w": "4", "ns_prefix": "/usr"},
+                         keys[0])
+        self.assertEqual({"x": "5", "y": "a", "ns_y": "c", "ns_w": "4",
+                          "ns_prefix": "/usr"}, keys[1])
+        self.assertEqual(self._getValues("""
+            variants:
+                - a:
+                    prefix a_
+                    x = 1
+                    x += 2
+                - b:
+                    x = 2
+            x += 3
+            """, "a_x"), [("a", "12"), ("b", None)])
+        self.assertEqual(self._getValues("""
+            variants:
+                - a:
+                    prefix a_
+                    x = 1
+                - b:
+                    x = 2
+            x += 3
+            """, "x"), [("a", "3"), ("b", "23")])
+        # the substitutions name the keys as they are
+        d = next(parser.parse_string("""
+            x = 1
+            prefix ns_
+            x = 2
+            y = ${x}
+            z = ${ns_x}
+            """).get_dicts())
+        self.assertEqual(("1", "2", "1", "2"),
+                         (d["x"], d["ns_x"], d["ns_y"], d["ns_z"]))
+        # only "prefix" before the operator assigns the key named prefix
+        self.assertIsInstance(parser.lex("prefix x=1\n")[0], parser.LPrefix)
+        self.assertIsInstance(parser.lex("prefix += 1\n")[0],
+                              parser.LIdentifier)
+        self.assertRaises(parser.ParserError, parser.parse_string,
+                          "prefix x=1\n")
+
     def testSuffixJoinDel(self):
         self._checkStringDump("""
             variants:
@@ -540,6 +3148,273 @@ class ParserTest(unittest.TestCase):
                           [],
                           True)
 
+    def testInclude(self):
+        with tempfile.TemporaryDirectory() as tmpdir:
+            os.mkdir(os.path.join(tmpdir, "sub"))
+            base = os.path.join(tmpdir, "base.cfg")
+            with open(base, "w") as f:
+                f.write("x = 1\ninclude sub/fragment.cfg\n")
+            with open(os.path.join(tmpdir, "sub", "fragment.cfg"), "w") as f:
+                f.write("variants:\n    - a:\n        y = 2\n")
+            dicts = list(parser.Parser(base).get_dicts())
+            self.assertEqual([(d["name"], d["x"], d["y"]) for d in dicts],
+                             [("a", "1", "2")])
+
+    def testReaders(self):
+        content = "x = 1\n\nvariants:\n    - a:\n    - b:\n        y = 2\n"
+        with tempfile.TemporaryDirectory() as tmpdir:
+            filename = os.path.join(tmpdir, "test.cfg")
+            with open(filename, "w") as f:
+                f.write(content)
+            file_reader = parser.FileReader(filename)
+            self.assertEqual(file_reader.filename, filename)
+            file_parser = parser.Parser(filename)
+        str_reader = parser.StrReader(content)
+        self.assertEqual(str_reader.filename, "<string>")
+        str_reader.get_next_line(-1)
+        str_reader.get_next_line(-1)
+        self.assertEqual(str_reader.linenum, 3)
+        str_parser = parser.Parser()
+        str_parser.parse_string(content)
+
+        def strip_map(d):
+            return dict((k, v) for k, v in d.items() if "map_file" not in k)
+        self.assertEqual([strip_map(d) for d in file_parser.get_dicts()],
+                         [strip_map(d) for d in str_parser.get_dicts()])
+
+    def testGzipFile(self):
+        content = "x = 1\nvariants:\n    - a:\n    - b:\n        y = 2\n"
+        with tempfile.TemporaryDirectory() as tmpdir:
+            plain = os.path.join(tmpdir, "test.cfg")
+            with open(plain, "w") as f:
+                f.write(content + "include fragment\n")
+            # the included files are detected by their content
+            with gzip.open(os.path.join(tmpdir, "fragment"), "wt") as f:
+                f.write("z = 3\n")
+            compressed = os.path.join(tmpdir, "test.cfg.gz")
+            with gzip.open(compressed, "wt") as f:
+                f.write(content + "include fragment\n")
+
+            def dicts(filename):
+                return [dict((k, v) for k, v in d.items()
+                             if "map_file" not in k)
+                        for d in parser.parse_file(filename).get_dicts()]
+            self.assertEqual(dicts(compressed), dicts(plain))
+            self.assertEqual(dicts(compressed)[1]["z"], "3")
+
+            with gzip.open(compressed, "wt") as f:
+                f.write(content + "  x = 2\n")
+            with self.assertRaises(parser.ParserError) as cm:
+                parser.parse_file(compressed)
+            self.assertEqual((cm.exception.filename, cm.exception.linenum),
+                             (compressed, 6))
+
+            with open(compressed, "w") as f:
+                f.write(content)
+            self.assertRaisesRegex(parser.LexerError, "Invalid gzip file",
+                                   parser.parse_file, compressed)
+
+    def testIncludeParams(self):
+        with tempfile.TemporaryDirectory() as tmpdir:
+            with open(os.path.join(tmpdir, "image.cfg"), "w") as f:
+                f.write("image = disk-${arch}.${format}\n"
+                        "arch_seen = ${arch}\n")
+            main = os.path.join(tmpdir, "main.cfg")
+            with open(main, "w") as f:
+                f.write("format = qcow2\n"
+                        "variants:\n"
+                        "    - x86:\n"
+                        "        include image.cfg with arch=x86_64\n"
+                        "    - arm:\n"
+                        "        arch = keep\n"
+                        "        include image.cfg with arch=aarch64"
+                        " format=raw\n"
+                        "after = ${arch}-${format}\n")
+            dicts = list(parser.Parser(main).get_dicts())
+            self.assertEqual([d["image"] for d in dicts],
+                             ["disk-x86_64.qcow2", "disk-aarch64.raw"])
+            self.assertEqual([d["arch_seen"] for d in dicts],
+                             ["x86_64", "aarch64"])
+            self.assertNotIn("arch", dicts[0])
+            self.assertEqual(dicts[0]["after"], "${arch}-qcow2")
+            self.assertEqual(dicts[1]["arch"], "keep")
+            self.assertEqual(dicts[1]["after"], "keep-qcow2")
+            with open(main, "w") as f:
+                f.write("include image.cfg with arch\n")
+            self.assertRaises(parser.ParserError, parser.Parser, main)
+
+    def testMalformedInput(self):
+        # inputs which used to fail with other errors than ParserError
+        for text in ["!#join\"\tx)\t)includex(\t\n",
+                     "!a\n    x = 1\n",
+                     "a[?=1\n",
+                     "del a(\n",
+                     "suffix \\\n",
+                     "  del \t \\",
+                     "variants:\n    - a:\n    - b:\na:\n    join a b\n"]:
+            self.assertRaises(parser.ParserError, parser.parse_string, text)
+        self.assertTrue(parser.LEndL() != "x")
+        with tempfile.TemporaryDirectory() as tmpdir:
+            path = os.path.join(tmpdir, "binary.cfg")
+            with open(path, "wb") as f:
+                f.write(b"x = 1\ny = \xff\xfe\n")
+            with self.assertRaises(parser.LexerError) as cm:
+                parser.Parser(path)
+            self.assertEqual(cm.exception.linenum, 2)
+
+    def testFuzz(self):
+        # parsing arbitrary text raises CartconfError or nothing else
+        alphabet = (list("ax0_-=+<~?!:.,()[]/@#\"'\\ \t\n$}{\x00\x0c\u2028") +
+                    ["variants", "only ", "no ", "del ", "include ", "join ",
+                     "suffix ", "${", "    ", "- ", "\r\n", "a:", "- b:"])
+        rnd = random.Random(0)
+        for _ in range(500):
+            text = "".join(rnd.choice(alphabet)
+                           for _ in range(rnd.randint(0, 30)))
+            try:
+                list(itertools.islice(parser.parse_string(text).get_dicts(),
+                                      20))
+            except parser.CartconfError:
+                pass
+
+    def testLazyIncludes(self):
+        with tempfile.TemporaryDirectory() as tmpdir:
+            def write(name, text):
+                with open(os.path.join(tmpdir, name), "w") as f:
+                    f.write(text)
+            write("big.cfg", "size = big\nonly x\n")
+            write("small.cfg", "size = small\nx:\n    tag = ${size}-x\n")
+            write("variants.cfg", "variants:\n    - v:\n")
+            write("cycle.cfg", "include cycle.cfg\n")
+            main = os.path.join(tmpdir, "main.cfg")
+            write("main.cfg", "variants:\n"
+                              "    - a:\n"
+                              "        include big.cfg\n"
+                              "    - b:\n"
+                              "        include small.cfg\n"
+                              "        after = ${size}\n"
+                              "variants:\n"
+                              "    - x:\n"
+                              "    - y:\n"
+                              "no a\n")
+            read = []
+
+            class RecordingReader(parser.FileReader):
+                def __init__(self, filename, *args):
+                    read.append(os.path.basename(filename))
+                    super(RecordingReader, self).__init__(filename, *args)
+            with unittest.mock.patch("cartconf.parser.FileReader",
+                                     RecordingReader):
+                p = parser.Parser(main, lazy_includes=True)
+                self.assertEqual(read, ["main.cfg"])
+                dicts = list(p.get_dicts())
+            self.assertEqual(read, ["main.cfg", "small.cfg"])
+            self.assertEqual([(d["name"], d.get("tag"), d["after"])
+                              for d in dicts],
+                             [("x.b", "small-x", "small"),
+                              ("y.b", None, "small")])
+            self.assertEqual(dicts, list(parser.Parser(main).get_dicts()))
+
+            write("main.cfg", "variants:\n"
+                              "    - a:\n"
+                              "        include big.cfg\n"
+                              "    - b:\n"
+                              "variants:\n"
+                              "    - x:\n"
+                              "    - y:\n")
+            self.assertEqual(
+                [d["name"] for d in
+                 parser.Parser(main, lazy_includes=True).get_dicts()],
+                [d["name"] for d in parser.Parser(main).get_dicts()])
+            for fragment, error in (("variants.cfg", "variants"),
+                                    ("cycle.cfg", "Cyclic")):
+                write("main.cfg", "include %s\n" % fragment)
+                p = parser.Parser(main, lazy_includes=True)
+                with self.assertRaises(parser.ParserError) as cm:
+                    list(p.get_dicts())
+                self.assertIn(error, str(cm.exception))
+
+    def testReaderCache(self):
+        with tempfile.TemporaryDirectory() as tmpdir:
+            def path(name):
+                return os.path.join(tmpdir, name)
+            for name, text in (("main.cfg", "include a.cfg\ninclude b.cfg\n"),
+                               ("a.cfg", "a = 1\n"), ("b.cfg", "b = 1\n")):
+                with open(path(name), "w") as f:
+                    f.write(text)
+            read = []
+
+            class RecordingReader(parser.FileReader):
+                def _read(self, filename):
+                    read.append(os.path.basename(filename))
+                    return super(RecordingReader, self)._read(filename)
+            cache = parser.ReaderCache()
+
+            def parse():
+                del read[:]
+                with unittest.mock.patch("cartconf.parser.FileReader",
+                                         RecordingReader):
+                    p = parser.Parser(path("main.cfg"), reader_cache=cache)
+                return [(d["a"], d["b"]) for d in p.get_dicts()]
+            self.assertEqual(parse(), [("1", "1")])
+            self.assertEqual(read, ["main.cfg", "a.cfg", "b.cfg"])
+            self.assertEqual(parse(), [("1", "1")])
+            self.assertEqual(read, [])
+            stat = os.stat(path("b.cfg"))
+            os.utime(path("b.cfg"), ns=(stat.st_atime_ns,
+                                        stat.st_mtime_ns + 10 ** 9))
+            self.assertEqual(parse(), [("1", "1")])
+            self.assertEqual(read, ["b.cfg"])
+            with open(path("a.cfg"), "w") as f:
+                f.write("a = 22\n")
+            self.assertEqual(parse(), [("22", "1")])
+            self.assertEqual(read, ["a.cfg"])
+            cache.clear()
+            self.assertEqual(parse(), [("22", "1")])
+            self.assertEqual(read, ["main.cfg", "a.cfg", "b.cfg"])
+
+    def testCyclicInclude(self):
+        with tempfile.TemporaryDirectory() as tmpdir:
+            first = os.path.join(tmpdir, "first.cfg")
+            with open(first, "w") as f:
+                f.write("include second.cfg\n")
+            with open(os.path.join(tmpdir, "second.cfg"), "w") as f:
+                f.write("include first.cfg\n")
+            with open(os.path.join(tmpdir, "self.cfg"), "w") as f:
+                f.write("include self.cfg\n")
+            self.assertRaises(parser.ParserError, parser.Parser, first)
+            self.assertRaises(parser.ParserError, parser.Parser,
+                              os.path.join(tmpdir, "self.cfg"))
+
+    def testValidate(self):
+        self.assertEqual(parser.validate("x = 1\nonly a\n"), [])
+        errors = parser.validate("""
+variants:
+    - a:
+\tx = 1
+    - b:
+        y = "abc
+only /a(/
+z = 3
+""", indent_policy=parser.IndentPolicy("spaces"))
+        self.assertEqual([(type(e), e.linenum, e.column) for e in errors],
+                         [(parser.LexerError, 4, 1),
+                          (parser.LexerError, 6, 13),
+                          (parser.ParserError, 7, None)])
+        self.assertIn("regular expression", str(errors[2]))
+        with tempfile.TemporaryDirectory() as tmpdir:
+            first = os.path.join(tmpdir, "first.cfg")
+            with open(first, "w") as f:
+                f.write("include second.cfg\n")
+            with open(os.path.join(tmpdir, "second.cfg"), "w") as f:
+                f.write("include first.cfg\n")
+            errors = parser.validate("include %s\nonly /(/\n" % first,
+                                     filename="main.cfg")
+            self.assertEqual(len(errors), 2)
+            self.assertIn("Cyclic include", str(errors[0]))
+            self.assertEqual(errors[1].filename, "main.cfg")
+            self.assertEqual(errors[1].linenum, 2)
+
     def testVariableAssignment(self):
         self._checkStringDump("""
             variants tests:
@@ -582,6 +3457,57 @@ class ParserTest(unittest.TestCase):
                               ],
                               True)
 
+    def testLateSubstitution(self):
+        p = parser.Parser()
+        p.parse_string("""
+            path = /base/${dir}/out
+            dir = ${sub}/data
+            sub = root
+            missing = ${undefined}
+            """)
+        d = list(p.get_dicts())[0]
+        self.assertEqual(d["dir"], "root/data")
+        self.assertEqual(d["path"], "/base/root/data/out")
+        self.assertEqual(d["missing"], "${undefined}")
+
+        p = parser.Parser(strict_substitution=True)
+        p.parse_string("missing = ${undefined}\n")
+        self.assertRaises(parser.ParserError, list, p.get_dicts())
+
+        p = parser.Parser()
+        p.parse_string("""
+            a = ${b}
+            b = ${c}
+            c = ${a}
+            """)
+        with self.assertRaises(parser.ParserError) as cm:
+            list(p.get_dicts())
+        self.assertIn("Cyclic", str(cm.exception))
+
+    def testEnvSubstitution(self):
+        config = """
+            build = ${env:CARTCONF_TEST_BUILD}
+            path = /out/${build}/${env:CARTCONF_TEST_UNSET:-default}
+            empty = ${env:CARTCONF_TEST_UNSET:-}
+            missing = ${env:CARTCONF_TEST_UNSET}
+            """
+        env = {"CARTCONF_TEST_BUILD": "42"}
+        with unittest.mock.patch.dict("os.environ", env):
+            os.environ.pop("CARTCONF_TEST_UNSET", None)
+            d = next(parser.parse_string(config).get_dicts())
+            self.assertEqual(d["build"], "42")
+            self.assertEqual(d["path"], "/out/42/default")
+            self.assertEqual(d["empty"], "")
+            self.assertEqual(d["missing"], "${env:CARTCONF_TEST_UNSET}")
+            p = parser.parse_string(config, strict_substitution=True)
+            with self.assertRaises(parser.ParserError) as cm:
+                list(p.get_dicts())
+            self.assertIn("CARTCONF_TEST_UNSET", str(cm.exception))
+            os.environ["CARTCONF_TEST_UNSET"] = "set"
+            d = next(parser.parse_string(config).get_dicts())
+            self.assertEqual(d["path"], "/out/42/set")
+            self.assertEqual(d["missing"], "set")
+
     def testVariableLazyAssignment(self):
         self._checkStringDump("""
             arg1 = ~balabala
@@ -795,6 +3721,72 @@ class ParserTest(unittest.TestCase):
                               ],
                               True)
 
+    def testConditionalAssignments(self):
+        space = """
+            variants:
+                - a:
+                - b:
+            variants:
+                - c:
+                - d:
+            x = 0
+            %s
+            """
+        self.assertEqual(self._getValues(space % "a:\n                x = 1",
+                                         "x"),
+                         [("c.a", "1"), ("c.b", "0"),
+                          ("d.a", "1"), ("d.b", "0")])
+        self.assertEqual(self._getValues(space % "!a:\n                x = 1",
+                                         "x"),
+                         [("c.a", "0"), ("c.b", "1"),
+                          ("d.a", "0"), ("d.b", "1")])
+        self.assertEqual(self._getValues(space % "d: x = 1", "x"),
+                         [("c.a", "0"), ("c.b", "0"),
+                          ("d.a", "1"), ("d.b", "1")])
+
+    def testNegativeDisjunction(self):
+        space = """
+            variants:
+                - a:
+                - b:
+                - c:
+            x = 0
+            !a, b:
+                x = 1
+            """
+        self.assertEqual(self._getValues(space, "x"),
+                         [("a", "0"), ("b", "0"), ("c", "1")])
+
+    def testConjunctionConditions(self):
+        space = """
+            variants:
+                - a:
+                - b:
+            variants:
+                - c:
+                - d:
+            variants:
+                - e:
+                - f:
+            x = 0
+            %s:
+                x = 1
+            """
+        names = self._getNames(space % "a")
+
+        def matching(condition):
+            values = dict(self._getValues(space % condition, "x"))
+            return [name for name in names if values[name] == "1"]
+
+        self.assertEqual(matching("a..d"), ["e.d.a", "f.d.a"])
+        self.assertEqual(matching("d..a"), matching("a..d"))
+        self.assertEqual(matching("a..d..f"), ["f.d.a"])
+        self.assertEqual(matching("f..a..d"), matching("a..d..f"))
+        # a single dot requires the names to be adjacent and in order
+        self.assertEqual(matching("d.a"), ["e.d.a", "f.d.a"])
+        self.assertEqual(matching("a.d"), [])
+        self.assertEqual(matching("f.a"), [])
+
     def testSyntaxErrors(self):
         self.assertRaises(parser.LexerError,
                           self._checkStringDump, """
@@ -1131,5 +4123,135 @@ class ParserTest(unittest.TestCase):
                               True)
 
 
+class ParseScriptTest(unittest.TestCase):
+
+    config = "variants:\n    - a:\n        x = 1\n    - b:\n        x = 2\n"
+
+    def _run(self, *args, **kwargs):
+        return subprocess.run([sys.executable, "-m", "cartconf.parse"] +
+                              list(args), cwd=basedir, capture_output=True,
+                              text=True, **kwargs)
+
+    def test_formats(self):
+        with tempfile.TemporaryDirectory() as tmpdir:
+            filename = os.path.join(tmpdir, "test.cfg")
+            with open(filename, "w") as f:
+                f.write(self.config)
+            result = self._run("--format", "json", "--no", "b", filename)
+        self.assertEqual(result.returncode, 0, result.stderr)
+        dicts = json.loads(result.stdout)
+        self.assertEqual([(d["name"], d["x"]) for d in dicts], [("a", "1")])
+        if yaml is not None:
+            result = self._run("--format", "yaml", "--only", "b", "-",
+                               input=self.config)
+            self.assertEqual(result.returncode, 0, result.stderr)
+            self.assertEqual([(d["name"], d["x"])
+                              for d in yaml.safe_load(result.stdout)],
+                             [("b", "2")])
+
+    def test_count_names(self):
+        config = self.config + "variants:\n    - c:\n    - d:\n"
+        with tempfile.TemporaryDirectory() as tmpdir:
+            filename = os.path.join(tmpdir, "test.cfg")
+            with open(filename, "w") as f:
+                f.write(config)
+            result = self._run("--count-only", filename)
+            self.assertEqual(result.returncode, 0, result.stderr)
+            self.assertEqual(result.stdout, "4\n")
+            result = self._run("--count-only", "--no", "a..d", filename)
+            self.assertEqual(result.stdout, "3\n")
+            result = self._run("--names-only", "--only", "c", filename)
+            self.assertEqual(result.returncode, 0, result.stderr)
+            self.assertEqual(result.stdout.splitlines(), ["c.a", "c.b"])
+        result = self._run("--names-only", "--no", "b", "-", input=config)
+        self.assertEqual(result.stdout.splitlines(), ["c.a", "d.a"])
+        result = self._run("--names-only", "--count-only", "-", input=config)
+        self.assertEqual(result.returncode, 2)
+        self.assertIn("mutually exclusive", result.stderr)
+
+    def test_errors(self):
+        result = self._run("-", input="variants:\n")
+        self.assertEqual(result.returncode, 1)
+        self.assertIn("(<string>:1)", result.stderr)
+        result = self._run(os.path.join(testdir, "missing.cfg"))
+        self.assertEqual(result.returncode, 1)
+        self.assertIn("missing.cfg", result.stderr)
+
+
+class StubgenTest(unittest.TestCase):
+
+    def test_generate(self):
+        import ast
+        import cartconf
+        from cartconf import stubgen
+        tree = ast.parse(stubgen.generate())
+        defined = [node.name for node in tree.body
+                   if isinstance(node, (ast.ClassDef, ast.FunctionDef))]
+        self.assertEqual(defined, stubgen.exported_names(cartconf))
+        for name in ("Parser", "parse_string", "lex", "CartconfError"):
+            self.assertIn(name, defined)
+        parser_stub = next(node for node in tree.body
+                           if getattr(node, "name", None) == "Parser")
+        methods = dict((node.name, node) for node in parser_stub.body)
+        self.assertIn("get_dicts", methods)
+        self.assertEqual([d.id for d in methods["from_json"].decorator_list],
+                         ["classmethod"])
+        init_args = methods["__init__"].args
+        self.assertEqual([arg.arg for arg in init_args.args][:3],
+                         ["self", "filename", "defaults"])
+        self.assertEqual(len(init_args.defaults), len(init_args.args) - 1)
+
+
+class BenchFixturesTest(unittest.TestCase):
+
+    def setUp(self):
+        sys.path.insert(0, os.path.join(basedir, "benches"))
+        self.addCleanup(sys.path.remove, os.path.join(basedir, "benches"))
+        import fixtures
+        self.fixtures = fixtures
+
+    def test_fixtures(self):
+        for name in ("small", "medium"):
+            size = self.fixtures.SIZES[name]
+            text = self.fixtures.config(*size)
+            self.assertTrue(parser.lex(text))
+            dicts = list(parser.parse_string(text).get_dicts())
+            self.assertEqual(len(dicts), self.fixtures.expected_count(*size),
+                             name)
+            self.assertEqual((dicts[0]["block0"], dicts[0]["mem"]),
+                             ("v0_1", "2048"))
+            self.assertTrue(dicts[0]["cmd"].startswith("smp run 1024"))
+            self.assertEqual(dicts[-1]["cmd"].split(),
+                             ["run", "1024"] +
+                             ["v%d_%d=1024" % (block, size[1] - 1)
+                              for block in range(size[0])])
+        dicts = list(parser.parse_string(self.fixtures.config(3, 3, 4))
+                     .get_dicts())
+        self.assertEqual(len(dicts), self.fixtures.expected_count(3, 3, 4))
+        self.assertEqual([key for key in dicts[0] if key.startswith("v0_1")],
+                         ["v0_1_key", "v0_1_key1", "v0_1_key2", "v0_1_key3"])
+        dicts = list(parser.parse_string(self.fixtures.config(3, 3, 1, 2))
+                     .get_dicts())
+        self.assertEqual(len(dicts),
+                         self.fixtures.expected_count(3, 3, 1, 2))
+        self.assertEqual([(d["block0"], d["block1"]) for d in dicts
+                          if d["block2"] == "v2_0"],
+                         [("v0_1", "v1_0"), ("v0_0", "v1_1"),
+                          ("v0_1", "v1_1")])
+        self.assertEqual((dicts[0]["v0_1_key"], dicts[0]["v2_0_key"]),
+                         ("v0_1 r", "v2_0"))
+
+    def test_bench(self):
+        result = subprocess.run([sys.executable,
+                                 os.path.join(basedir, "benches", "bench.py"),
+                                 "-n", "1", "small"],
+                                capture_output=True, text=True)
+        self.assertEqual(result.returncode, 0, result.stderr)
+        self.assertEqual([line.split()[:2]
+                          for line in result.stdout.splitlines()],
+                         [["small", "lex"], ["small", "parse"],
+                          ["small", "expand"]])
+
+
 if __name__ == '__main__':
     unittest.main()